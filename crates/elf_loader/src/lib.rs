@@ -0,0 +1,1333 @@
+//! ELF loader for RISC-V executables, shared between shoo, the kernel and
+//! userspace.
+//!
+//! The loader itself does not know anything about how memory is allocated or
+//! mapped: that is up to the [`AddressSpace`] it is loading into.
+//!
+//! Both static (`ET_EXEC`) and position independent (`ET_DYN`) executables are
+//! supported. Position independent ones are placed wherever the address space
+//! chooses and have their `R_RISCV_RELATIVE` and `R_RISCV_64` relocations
+//! applied; there is no dynamic linking against other objects.
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+pub mod stack;
+
+use core::convert::Infallible;
+use core::fmt;
+use core::mem;
+use core::ops::Range;
+use core::ptr;
+use core::slice;
+
+use goblin::elf64::dynamic::{
+    Dyn, DT_NULL, DT_REL, DT_RELA, DT_RELAENT, DT_RELASZ, DT_SYMENT, DT_SYMTAB,
+};
+use goblin::elf64::header::{
+    self, Header, EI_CLASS, EI_DATA, ELFCLASS64, ELFDATA2LSB, ELFMAG, EM_RISCV, ET_DYN, ET_EXEC,
+    SELFMAG,
+};
+use goblin::elf64::program_header::{
+    ProgramHeader, PF_R, PF_W, PF_X, PT_DYNAMIC, PT_LOAD, PT_PHDR, PT_TLS,
+};
+use goblin::elf64::reloc::{r_sym, r_type, Rela, R_RISCV_64, R_RISCV_NONE, R_RISCV_RELATIVE};
+use goblin::elf64::section_header::SHN_UNDEF;
+use goblin::elf64::sym::{st_bind, Sym, STB_WEAK};
+use riscv_paging::{
+    AccessError, Addr, PageSize, PageTable, PhysAccess, PteAttrs, VirtAddr, PAGE_SIZE,
+};
+
+/// Something that an ELF can be loaded into
+pub trait AddressSpace {
+    type Error: fmt::Debug;
+
+    /// Maps zeroed memory covering the page aligned range `virt` with the
+    /// attributes `attrs`. The loader never maps any page twice.
+    fn map_zeroed(&mut self, virt: Range<VirtAddr>, attrs: PteAttrs) -> Result<(), Self::Error>;
+
+    /// Copies `data` into memory previously mapped with
+    /// [`AddressSpace::map_zeroed`] at `va`, regardless of whether that memory
+    /// is writable.
+    fn write(&mut self, va: VirtAddr, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Copies memory previously written with [`AddressSpace::write`] at `va`
+    /// into `buf`
+    fn read(&mut self, va: VirtAddr, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Picks where to put a position independent image that was linked to
+    /// span `link_span`. The result must be page aligned.
+    fn choose_base(&mut self, link_span: Range<VirtAddr>) -> Result<VirtAddr, Self::Error> {
+        Ok(default_base(&link_span))
+    }
+}
+
+/// Where position independent images linked at zero go if the address space
+/// does not care. This is the same place the static user linker script starts
+/// at, leaving the bottom of memory unmapped to catch null pointers.
+pub const DEFAULT_PIE_BASE: VirtAddr = VirtAddr(0x10_0000);
+
+/// Base to load a position independent image linked to span `link_span` at,
+/// if nothing else is chosen: where it was linked, unless that is at zero.
+pub fn default_base(link_span: &Range<VirtAddr>) -> VirtAddr {
+    if link_span.start == VirtAddr(0) {
+        DEFAULT_PIE_BASE
+    } else {
+        link_span.start
+    }
+}
+
+/// Size of a program header, which is the only size we accept
+pub const PHDR_SIZE: usize = mem::size_of::<ProgramHeader>();
+
+/// Converts the ELF Phdr.p_flags to PteAttrs
+pub fn flags_to_riscv(p_flags: u32) -> PteAttrs {
+    let mut out = PteAttrs::empty();
+    if p_flags & PF_R != 0 {
+        out |= PteAttrs::R;
+    }
+    if p_flags & PF_W != 0 {
+        out |= PteAttrs::W;
+    }
+    if p_flags & PF_X != 0 {
+        out |= PteAttrs::X;
+    }
+    out
+}
+
+/// Reasons an ELF may be rejected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ElfLoadErr {
+    /// File is too short to hold an ELF header
+    TooShort,
+    /// File does not start with the ELF magic
+    BadMagic,
+    /// ELF is not 64 bit
+    WrongClass(u8),
+    /// ELF is not little endian
+    WrongEndianness(u8),
+    /// ELF is not for RISC-V
+    WrongMachine(u16),
+    /// ELF is not an executable or position independent executable
+    WrongType(u16),
+    /// Program header entries are not the size we expect
+    BadPhentsize(u16),
+    /// Program headers do not fit in the file
+    PhdrsOutOfBounds,
+    /// The file contents of a segment do not fit in the file
+    SegmentOutOfBounds { index: usize },
+    /// A segment has more contents in the file than in memory
+    FileszExceedsMemsz { index: usize },
+    /// A segment's addresses overflow
+    SegmentOverflow { index: usize },
+    /// A segment's virtual address and file offset differ modulo the page size
+    SegmentMisaligned { index: usize },
+    /// Two segments share some pages
+    OverlappingSegments { first: usize, second: usize },
+    /// A segment is both writable and executable
+    WriteExecute { index: usize },
+    /// There is nothing to load
+    NoLoadableSegments,
+    /// The dynamic section is malformed or points outside the file
+    BadDynamic,
+    /// The thread local storage segment is malformed, or there is more than
+    /// one
+    BadTls,
+    /// A relocation type we do not know how to apply
+    UnsupportedRelocation(u32),
+    /// A relocation would write outside the image
+    RelocationOutOfBounds { offset: u64 },
+    /// A relocation refers to a symbol that is not defined in the image
+    UndefinedSymbol(u32),
+    /// The file could not be read while it was being streamed in
+    Unreadable,
+}
+
+impl fmt::Display for ElfLoadErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ElfLoadErr::TooShort => write!(f, "file too short for an ELF header"),
+            ElfLoadErr::BadMagic => write!(f, "bad ELF magic"),
+            ElfLoadErr::WrongClass(c) => write!(f, "ELF class {} is not ELFCLASS64", c),
+            ElfLoadErr::WrongEndianness(d) => {
+                write!(f, "ELF data encoding {} is not little endian", d)
+            }
+            ElfLoadErr::WrongMachine(m) => write!(f, "ELF machine {} is not EM_RISCV", m),
+            ElfLoadErr::WrongType(t) => write!(f, "ELF type {} is not ET_EXEC or ET_DYN", t),
+            ElfLoadErr::BadPhentsize(s) => {
+                write!(f, "program header size {} is not {}", s, PHDR_SIZE)
+            }
+            ElfLoadErr::PhdrsOutOfBounds => write!(f, "program headers out of bounds"),
+            ElfLoadErr::SegmentOutOfBounds { index } => {
+                write!(f, "segment {} file contents out of bounds", index)
+            }
+            ElfLoadErr::FileszExceedsMemsz { index } => {
+                write!(f, "segment {} has p_filesz > p_memsz", index)
+            }
+            ElfLoadErr::SegmentOverflow { index } => {
+                write!(f, "segment {} addresses overflow", index)
+            }
+            ElfLoadErr::SegmentMisaligned { index } => write!(
+                f,
+                "segment {} p_vaddr and p_offset are not congruent mod page size",
+                index
+            ),
+            ElfLoadErr::OverlappingSegments { first, second } => {
+                write!(f, "segments {} and {} overlap", first, second)
+            }
+            ElfLoadErr::WriteExecute { index } => {
+                write!(f, "segment {} is both writable and executable", index)
+            }
+            ElfLoadErr::NoLoadableSegments => write!(f, "no loadable segments"),
+            ElfLoadErr::BadDynamic => write!(f, "bad dynamic section"),
+            ElfLoadErr::BadTls => write!(f, "bad thread local storage segment"),
+            ElfLoadErr::UnsupportedRelocation(t) => {
+                write!(f, "unsupported relocation type {}", t)
+            }
+            ElfLoadErr::RelocationOutOfBounds { offset } => {
+                write!(f, "relocation at {:#x} is outside the image", offset)
+            }
+            ElfLoadErr::UndefinedSymbol(sym) => {
+                write!(f, "relocation against undefined symbol {}", sym)
+            }
+            ElfLoadErr::Unreadable => write!(f, "could not read the file"),
+        }
+    }
+}
+
+/// Errors from loading an ELF into an address space
+#[derive(Debug, PartialEq, Eq)]
+pub enum LoadError<E> {
+    /// The ELF is bad
+    Elf(ElfLoadErr),
+    /// The address space failed to map or write memory
+    AddressSpace(E),
+}
+
+impl<E> From<ElfLoadErr> for LoadError<E> {
+    fn from(e: ElfLoadErr) -> Self {
+        LoadError::Elf(e)
+    }
+}
+
+impl<E: fmt::Debug> fmt::Display for LoadError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Elf(e) => write!(f, "{}", e),
+            LoadError::AddressSpace(e) => write!(f, "address space error: {:?}", e),
+        }
+    }
+}
+
+/// A loadable segment of an ELF
+#[derive(Clone)]
+pub struct Segment {
+    /// Index of the program header
+    pub index: usize,
+    /// Pages in virtual memory covered by the segment
+    pub pages: Range<VirtAddr>,
+    pub attrs: PteAttrs,
+    header: ProgramHeader,
+}
+
+impl fmt::Debug for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Segment")
+            .field("index", &self.index)
+            .field("pages", &self.pages)
+            .field("attrs", &self.attrs)
+            .finish()
+    }
+}
+
+impl Segment {
+    fn new(index: usize, header: ProgramHeader) -> Option<Segment> {
+        let begin = VirtAddr(header.p_vaddr as usize).round_down(PageSize::Page4k)?;
+        let end = VirtAddr((header.p_vaddr as usize).checked_add(header.p_memsz as usize)?)
+            .round_up(PageSize::Page4k)?;
+        Some(Segment {
+            index,
+            pages: begin..end,
+            attrs: flags_to_riscv(header.p_flags),
+            header,
+        })
+    }
+
+    /// The program header of the segment
+    pub fn header(&self) -> &ProgramHeader {
+        &self.header
+    }
+}
+
+/// Where an image ended up after loading
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoadedImage {
+    /// Amount added to every virtual address in the ELF. Always zero for
+    /// static executables.
+    pub bias: usize,
+    /// Entry point, including the bias
+    pub entry: VirtAddr,
+    /// Lowest to highest virtual address of the image, including the bias
+    pub virt_span: Range<VirtAddr>,
+    /// Where the program headers are mapped, including the bias, if they are
+    pub phdr: Option<VirtAddr>,
+    /// Number of program headers
+    pub phnum: usize,
+    /// Thread local storage template, if the image uses TLS
+    pub tls: Option<TlsTemplate>,
+}
+
+/// The initial contents of each thread's thread local storage block, from the
+/// `PT_TLS` segment. The block is `mem_size` bytes aligned to `align`,
+/// starting with the `file_size` bytes at `template` and then zeros. On
+/// RISC-V, the thread pointer points to the start of the block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsTemplate {
+    /// Virtual address of the initialized part of the template, including
+    /// the bias
+    pub template: VirtAddr,
+    pub file_size: usize,
+    pub mem_size: usize,
+    /// Alignment of the block; always a power of two
+    pub align: usize,
+}
+
+impl TlsTemplate {
+    /// Places a thread's TLS block so it ends at or below `below`. Returns
+    /// the start of the block, which is the thread pointer, and how many bytes
+    /// from there need to be mapped for it, which is whole pages.
+    pub fn block_below(&self, below: VirtAddr) -> Option<(VirtAddr, usize)> {
+        let align = self.align.max(PAGE_SIZE as usize);
+        let begin = below.get().checked_sub(self.mem_size)? & !(align - 1);
+        let len = VirtAddr(self.mem_size).round_up(PageSize::Page4k)?.get();
+        Some((VirtAddr(begin), len))
+    }
+
+    /// Fills in a TLS block at `block` in `pt`, where it is mapped along with
+    /// the template
+    ///
+    /// # Safety
+    /// `pt` must be a valid page table.
+    pub unsafe fn init_block<P: PhysAccess>(
+        &self,
+        pt: PageTable<P>,
+        block: VirtAddr,
+    ) -> Result<(), AccessError> {
+        let mut buf = [0u8; 256];
+        let chunk_len = buf.len();
+        for offs in (0..self.file_size).step_by(chunk_len) {
+            let chunk = &mut buf[..(self.file_size - offs).min(chunk_len)];
+            pt.read_virt(self.template.map(|va| va + offs), chunk)?;
+            pt.write_virt(VirtAddr(block.get() + offs), chunk)?;
+        }
+        // the pages may not have been zeroed when they were allocated
+        let zeros = [0u8; 256];
+        for offs in (self.file_size..self.mem_size).step_by(chunk_len) {
+            let len = (self.mem_size - offs).min(chunk_len);
+            pt.write_virt(VirtAddr(block.get() + offs), &zeros[..len])?;
+        }
+        Ok(())
+    }
+}
+
+/// File offsets of the tables from the dynamic section
+#[derive(Clone, Copy, Debug, Default)]
+struct DynInfo {
+    /// Offset and count of the `Rela` entries
+    rela: Option<(usize, usize)>,
+    /// Offset of the symbol table
+    symtab: Option<usize>,
+}
+
+impl DynInfo {
+    /// Number of `Rela` entries
+    fn rela_count(&self) -> usize {
+        self.rela.map_or(0, |(_, count)| count)
+    }
+}
+
+/// An ELF file being read from the start a piece at a time, for loading with
+/// [`Elf::load_stream`]
+pub trait FileStream {
+    /// Reads the next part of the file into `buf`, returning how much was
+    /// read. This is only zero at the end of the file, or if `buf` is empty.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ElfLoadErr>;
+}
+
+impl FileStream for &[u8] {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ElfLoadErr> {
+        let len = buf.len().min(self.len());
+        buf[..len].copy_from_slice(&self[..len]);
+        *self = &self[len..];
+        Ok(len)
+    }
+}
+
+/// An ELF executable that has been checked and is ready to load
+pub struct Elf<'a> {
+    /// The file, or only the start of it if it is going to be streamed
+    data: &'a [u8],
+    /// Length of the whole file
+    file_len: usize,
+    header: Header,
+    dynamic: DynInfo,
+}
+
+/// Reads a `T` at the file offset `offs` with `read`, which fills its buffer
+/// from that offset if it can. The file may not be aligned so it is copied
+/// out.
+fn read_at<T: Copy, E>(
+    read: &mut impl FnMut(usize, &mut [u8]) -> Result<bool, E>,
+    offs: usize,
+) -> Result<Option<T>, E> {
+    let mut val = mem::MaybeUninit::<T>::zeroed();
+    // safety: the buffer is exactly the value, which this is only used for
+    // goblin's plain old data structs for which any bit pattern is valid
+    let bytes =
+        unsafe { slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, mem::size_of::<T>()) };
+    if !read(offs, bytes)? {
+        return Ok(None);
+    }
+    Ok(Some(unsafe { val.assume_init() }))
+}
+
+/// Makes a reader for [`read_at`] out of a whole file in memory
+fn file_reader(data: &[u8]) -> impl FnMut(usize, &mut [u8]) -> Result<bool, Infallible> + '_ {
+    move |offs, buf| {
+        let bytes = offs
+            .checked_add(buf.len())
+            .and_then(|end| data.get(offs..end));
+        if let Some(bytes) = bytes {
+            buf.copy_from_slice(bytes);
+        }
+        Ok(bytes.is_some())
+    }
+}
+
+/// Gets the ELF error out of a result from reading a file in memory, which
+/// can't fail in any other way
+fn in_memory<T>(res: Result<T, LoadError<Infallible>>) -> Result<T, ElfLoadErr> {
+    res.map_err(|e| match e {
+        LoadError::Elf(e) => e,
+        LoadError::AddressSpace(never) => match never {},
+    })
+}
+
+impl<'a> Elf<'a> {
+    /// Checks that `data` is an ELF we can load
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfLoadErr> {
+        let mut elf = Elf::parse_head(data, data.len())?;
+        if elf.is_pie() {
+            let mut read = file_reader(data);
+            elf.dynamic = in_memory(elf.read_dynamic(&mut read))?;
+            in_memory(elf.check_relocations(&elf.dynamic, &mut read))?;
+        }
+        Ok(elf)
+    }
+
+    /// Checks the headers of an ELF file `file_len` bytes long that starts
+    /// with `head`, so it can be loaded with [`Elf::load_stream`] without
+    /// having all of it at once. `head` must hold the program headers. The
+    /// dynamic section and relocations can only be checked while loading.
+    pub fn parse_head(head: &'a [u8], file_len: usize) -> Result<Elf<'a>, ElfLoadErr> {
+        let bits = head
+            .get(..header::SIZEOF_EHDR)
+            .ok_or(ElfLoadErr::TooShort)?;
+        // safety: it is in bounds and any bit pattern is a valid Header. the
+        // file may not be aligned so we copy it out.
+        let hdr = unsafe { ptr::read_unaligned(bits.as_ptr() as *const Header) };
+
+        if &hdr.e_ident[..SELFMAG] != ELFMAG {
+            return Err(ElfLoadErr::BadMagic);
+        }
+        if hdr.e_ident[EI_CLASS] != ELFCLASS64 {
+            return Err(ElfLoadErr::WrongClass(hdr.e_ident[EI_CLASS]));
+        }
+        if hdr.e_ident[EI_DATA] != ELFDATA2LSB {
+            return Err(ElfLoadErr::WrongEndianness(hdr.e_ident[EI_DATA]));
+        }
+        if hdr.e_machine != EM_RISCV {
+            return Err(ElfLoadErr::WrongMachine(hdr.e_machine));
+        }
+        if hdr.e_type != ET_EXEC && hdr.e_type != ET_DYN {
+            return Err(ElfLoadErr::WrongType(hdr.e_type));
+        }
+        if hdr.e_phentsize as usize != PHDR_SIZE {
+            return Err(ElfLoadErr::BadPhentsize(hdr.e_phentsize));
+        }
+        let phdrs_end = (hdr.e_phnum as usize)
+            .checked_mul(PHDR_SIZE)
+            .and_then(|len| len.checked_add(hdr.e_phoff as usize))
+            .ok_or(ElfLoadErr::PhdrsOutOfBounds)?;
+        if phdrs_end > head.len() {
+            return Err(ElfLoadErr::PhdrsOutOfBounds);
+        }
+
+        let elf = Elf {
+            data: head,
+            file_len: file_len.max(head.len()),
+            header: hdr,
+            dynamic: DynInfo::default(),
+        };
+        elf.check_segments()?;
+        Ok(elf)
+    }
+
+    /// Whether the image is position independent and can be loaded anywhere
+    pub fn is_pie(&self) -> bool {
+        self.header.e_type == ET_DYN
+    }
+
+    /// The ELF header
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// The entry point of the executable, without any bias from loading
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr(self.header.e_entry as usize)
+    }
+
+    /// Iterates over the program headers. The file may not be aligned so these
+    /// are copied out.
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        let phoff = self.header.e_phoff as usize;
+        (0..self.header.e_phnum as usize).map(move |i| {
+            let offs = phoff + i * PHDR_SIZE;
+            let bytes = &self.data[offs..offs + PHDR_SIZE];
+            // safety: bounds were checked by parse, and any bit pattern is a
+            // valid ProgramHeader
+            unsafe { ptr::read_unaligned(bytes.as_ptr() as *const ProgramHeader) }
+        })
+    }
+
+    /// Iterates over the segments that need loading, in program header order
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + '_ {
+        self.program_headers()
+            .enumerate()
+            .filter(|(_, h)| h.p_type == PT_LOAD && h.p_memsz != 0)
+            // checked in parse
+            .map(|(index, h)| Segment::new(index, h).unwrap())
+    }
+
+    /// Lowest to highest virtual address of the image, without any bias from
+    /// loading. Not all of this is necessarily mapped.
+    pub fn virt_span(&self) -> Range<VirtAddr> {
+        self.segments()
+            .map(|seg| seg.pages)
+            .reduce(|s1, s2| s1.start.min(s2.start)..s1.end.max(s2.end))
+            .unwrap()
+    }
+
+    /// Checks that the loadable segments are sane and fit in the file
+    fn check_segments(&self) -> Result<(), ElfLoadErr> {
+        let loadable = || {
+            self.program_headers()
+                .enumerate()
+                .filter(|(_, h)| h.p_type == PT_LOAD && h.p_memsz != 0)
+        };
+
+        for (index, h) in loadable() {
+            if h.p_filesz > h.p_memsz {
+                return Err(ElfLoadErr::FileszExceedsMemsz { index });
+            }
+            let file_end = h
+                .p_offset
+                .checked_add(h.p_filesz)
+                .ok_or(ElfLoadErr::SegmentOutOfBounds { index })?;
+            if file_end > self.file_len as u64 {
+                return Err(ElfLoadErr::SegmentOutOfBounds { index });
+            }
+            if Segment::new(index, h).is_none() {
+                return Err(ElfLoadErr::SegmentOverflow { index });
+            }
+            let page_mask = PageSize::Page4k.offs_mask() as u64;
+            if h.p_vaddr & page_mask != h.p_offset & page_mask {
+                return Err(ElfLoadErr::SegmentMisaligned { index });
+            }
+            if h.p_flags & PF_W != 0 && h.p_flags & PF_X != 0 {
+                return Err(ElfLoadErr::WriteExecute { index });
+            }
+        }
+
+        // there are only ever a handful of segments so quadratic is fine
+        for seg1 in self.segments() {
+            for seg2 in self.segments().filter(|s| s.index > seg1.index) {
+                if seg1.pages.start < seg2.pages.end && seg2.pages.start < seg1.pages.end {
+                    return Err(ElfLoadErr::OverlappingSegments {
+                        first: seg1.index,
+                        second: seg2.index,
+                    });
+                }
+            }
+        }
+
+        if loadable().next().is_none() {
+            return Err(ElfLoadErr::NoLoadableSegments);
+        }
+        self.check_tls()
+    }
+
+    /// The `PT_TLS` program header, if there is one
+    fn tls_header(&self) -> Option<ProgramHeader> {
+        self.program_headers().find(|h| h.p_type == PT_TLS)
+    }
+
+    /// Checks that there is at most one TLS segment and its template is
+    /// loaded
+    fn check_tls(&self) -> Result<(), ElfLoadErr> {
+        if self
+            .program_headers()
+            .filter(|h| h.p_type == PT_TLS)
+            .count()
+            > 1
+        {
+            return Err(ElfLoadErr::BadTls);
+        }
+        let h = match self.tls_header() {
+            Some(h) => h,
+            None => return Ok(()),
+        };
+        if h.p_filesz > h.p_memsz || !(h.p_align == 0 || h.p_align.is_power_of_two()) {
+            return Err(ElfLoadErr::BadTls);
+        }
+        // the template is read out of memory so it has to be in a segment
+        if h.p_filesz != 0 && self.vaddr_to_offset(h.p_vaddr, h.p_filesz).is_none() {
+            return Err(ElfLoadErr::BadTls);
+        }
+        Ok(())
+    }
+
+    /// Thread local storage template, without any bias from loading
+    pub fn tls(&self) -> Option<TlsTemplate> {
+        self.tls_header().map(|h| TlsTemplate {
+            template: VirtAddr(h.p_vaddr as usize),
+            file_size: h.p_filesz as usize,
+            mem_size: h.p_memsz as usize,
+            align: h.p_align.max(1) as usize,
+        })
+    }
+
+    /// Virtual address of the program headers, without any bias from
+    /// loading, if they are in a loaded segment
+    pub fn phdr_vaddr(&self) -> Option<VirtAddr> {
+        if let Some(h) = self.program_headers().find(|h| h.p_type == PT_PHDR) {
+            return Some(VirtAddr(h.p_vaddr as usize));
+        }
+        let phoff = self.header.e_phoff;
+        let len = self.header.e_phnum as u64 * PHDR_SIZE as u64;
+        self.segments()
+            .map(|seg| *seg.header())
+            .find(|h| h.p_offset <= phoff && phoff + len <= h.p_offset + h.p_filesz)
+            .map(|h| VirtAddr((h.p_vaddr + (phoff - h.p_offset)) as usize))
+    }
+
+    /// Converts the virtual address range `va..va + len` to a file offset, if
+    /// it is entirely within the file contents of one segment
+    fn vaddr_to_offset(&self, va: u64, len: u64) -> Option<usize> {
+        let end = va.checked_add(len)?;
+        self.segments()
+            .map(|seg| *seg.header())
+            .find(|h| h.p_vaddr <= va && end <= h.p_vaddr + h.p_filesz)
+            .map(|h| (h.p_offset + (va - h.p_vaddr)) as usize)
+    }
+
+    /// Finds the relocation and symbol tables through the dynamic section,
+    /// reading the file with `read`
+    fn read_dynamic<E>(
+        &self,
+        read: &mut impl FnMut(usize, &mut [u8]) -> Result<bool, E>,
+    ) -> Result<DynInfo, LoadError<E>> {
+        let dynamic = match self.program_headers().find(|h| h.p_type == PT_DYNAMIC) {
+            Some(h) => h,
+            // nothing to relocate
+            None => return Ok(DynInfo::default()),
+        };
+        let end = dynamic
+            .p_offset
+            .checked_add(dynamic.p_filesz)
+            .ok_or(ElfLoadErr::BadDynamic)?;
+        if end > self.file_len as u64 {
+            return Err(ElfLoadErr::BadDynamic.into());
+        }
+
+        let (mut rela, mut relasz, mut relaent) = (None, None, None);
+        let (mut symtab, mut syment) = (None, None);
+        let count = dynamic.p_filesz as usize / mem::size_of::<Dyn>();
+        for i in 0..count {
+            let offs = dynamic.p_offset as usize + i * mem::size_of::<Dyn>();
+            let d: Dyn = read_at(read, offs)
+                .map_err(LoadError::AddressSpace)?
+                .ok_or(ElfLoadErr::BadDynamic)?;
+            match d.d_tag {
+                DT_NULL => break,
+                DT_RELA => rela = Some(d.d_val),
+                DT_RELASZ => relasz = Some(d.d_val),
+                DT_RELAENT => relaent = Some(d.d_val),
+                DT_SYMTAB => symtab = Some(d.d_val),
+                DT_SYMENT => syment = Some(d.d_val),
+                // RISC-V only uses Rela, so we don't bother with Rel
+                DT_REL => return Err(ElfLoadErr::BadDynamic.into()),
+                _ => (),
+            }
+        }
+
+        let mut info = DynInfo::default();
+        if let Some(rela) = rela {
+            let relasz = relasz.ok_or(ElfLoadErr::BadDynamic)?;
+            if relaent.unwrap_or(mem::size_of::<Rela>() as u64) != mem::size_of::<Rela>() as u64 {
+                return Err(ElfLoadErr::BadDynamic.into());
+            }
+            let offs = self
+                .vaddr_to_offset(rela, relasz)
+                .ok_or(ElfLoadErr::BadDynamic)?;
+            info.rela = Some((offs, relasz as usize / mem::size_of::<Rela>()));
+        }
+        if let Some(symtab) = symtab {
+            if syment.unwrap_or(mem::size_of::<Sym>() as u64) != mem::size_of::<Sym>() as u64 {
+                return Err(ElfLoadErr::BadDynamic.into());
+            }
+            // we don't know how long the symbol table is without looking at
+            // the hash table, so each symbol is bounds checked when it is used
+            info.symtab = Some(
+                self.vaddr_to_offset(symtab, 0)
+                    .ok_or(ElfLoadErr::BadDynamic)?,
+            );
+        }
+        Ok(info)
+    }
+
+    /// Reads the dynamic relocation at `index` in the table `dynamic` points
+    /// to. Bounds were checked by [`Elf::read_dynamic`].
+    fn relocation<E>(
+        &self,
+        dynamic: &DynInfo,
+        read: &mut impl FnMut(usize, &mut [u8]) -> Result<bool, E>,
+        index: usize,
+    ) -> Result<Rela, LoadError<E>> {
+        let (offs, _) = dynamic.rela.expect("no relocations to read");
+        read_at(read, offs + index * mem::size_of::<Rela>())
+            .map_err(LoadError::AddressSpace)?
+            .ok_or_else(|| ElfLoadErr::BadDynamic.into())
+    }
+
+    /// Computes the value to store for the relocation `rela` if the image is
+    /// loaded with `bias`, or `None` if nothing needs storing. Also checks
+    /// that it stays inside the image.
+    fn relocation_value<E>(
+        &self,
+        dynamic: &DynInfo,
+        read: &mut impl FnMut(usize, &mut [u8]) -> Result<bool, E>,
+        rela: &Rela,
+        bias: u64,
+    ) -> Result<Option<u64>, LoadError<E>> {
+        let addend = rela.r_addend as u64;
+        let value = match r_type(rela.r_info) {
+            R_RISCV_NONE => None,
+            R_RISCV_RELATIVE => Some(bias.wrapping_add(addend)),
+            R_RISCV_64 => {
+                let idx = r_sym(rela.r_info);
+                let symtab = dynamic.symtab.ok_or(ElfLoadErr::BadDynamic)?;
+                let sym: Sym = read_at(read, symtab + idx as usize * mem::size_of::<Sym>())
+                    .map_err(LoadError::AddressSpace)?
+                    .ok_or(ElfLoadErr::BadDynamic)?;
+                if sym.st_shndx == SHN_UNDEF as u16 {
+                    // undefined weak symbols resolve to zero
+                    if st_bind(sym.st_info) != STB_WEAK {
+                        return Err(ElfLoadErr::UndefinedSymbol(idx).into());
+                    }
+                    Some(addend)
+                } else {
+                    Some(bias.wrapping_add(sym.st_value).wrapping_add(addend))
+                }
+            }
+            t => return Err(ElfLoadErr::UnsupportedRelocation(t).into()),
+        };
+
+        let out_of_bounds = ElfLoadErr::RelocationOutOfBounds {
+            offset: rela.r_offset,
+        };
+        let end = rela
+            .r_offset
+            .checked_add(mem::size_of::<u64>() as u64)
+            .ok_or(out_of_bounds)?;
+        let in_image = self.segments().any(|seg| {
+            let h = seg.header();
+            h.p_vaddr <= rela.r_offset && end <= h.p_vaddr + h.p_memsz
+        });
+        if !in_image {
+            return Err(out_of_bounds.into());
+        }
+
+        Ok(value)
+    }
+
+    /// Checks that every relocation can be applied, so loading does not fail
+    /// halfway through
+    fn check_relocations<E>(
+        &self,
+        dynamic: &DynInfo,
+        read: &mut impl FnMut(usize, &mut [u8]) -> Result<bool, E>,
+    ) -> Result<(), LoadError<E>> {
+        for i in 0..dynamic.rela_count() {
+            let rela = self.relocation(dynamic, read, i)?;
+            self.relocation_value(dynamic, read, &rela, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the relocations in the table `dynamic` points to for an image
+    /// loaded into `space` with `bias`, reading the file with `read`
+    fn relocate<A: AddressSpace>(
+        &self,
+        space: &mut A,
+        dynamic: &DynInfo,
+        bias: usize,
+        mut read: impl FnMut(&mut A, usize, &mut [u8]) -> Result<bool, A::Error>,
+    ) -> Result<(), LoadError<A::Error>> {
+        for i in 0..dynamic.rela_count() {
+            let mut read_file = |offs: usize, buf: &mut [u8]| read(space, offs, buf);
+            let rela = self.relocation(dynamic, &mut read_file, i)?;
+            let value = self.relocation_value(dynamic, &mut read_file, &rela, bias as u64)?;
+            if let Some(value) = value {
+                space
+                    .write(
+                        VirtAddr((rela.r_offset as usize).wrapping_add(bias)),
+                        &value.to_le_bytes(),
+                    )
+                    .map_err(LoadError::AddressSpace)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Picks the bias for loading into `space`, and maps each segment there
+    /// with `extra_attrs` in addition to the segment's own permissions
+    fn map_segments<A: AddressSpace>(
+        &self,
+        space: &mut A,
+        extra_attrs: PteAttrs,
+    ) -> Result<usize, LoadError<A::Error>> {
+        let span = self.virt_span();
+        let bias = if self.is_pie() {
+            let base = space
+                .choose_base(span.clone())
+                .map_err(LoadError::AddressSpace)?;
+            assert!(
+                base.is_page_aligned(PageSize::Page4k),
+                "choose_base gave unaligned base {:?}",
+                base
+            );
+            base.get().wrapping_sub(span.start.get())
+        } else {
+            0
+        };
+        let biased = |va: usize| VirtAddr(va.wrapping_add(bias));
+
+        for seg in self.segments() {
+            let pages = biased(seg.pages.start.get())..biased(seg.pages.end.get());
+            if pages.end < pages.start {
+                return Err(ElfLoadErr::SegmentOverflow { index: seg.index }.into());
+            }
+            log::debug!(
+                "load segment {} {:?} attrs {:?}",
+                seg.index,
+                pages,
+                seg.attrs | extra_attrs
+            );
+            space
+                .map_zeroed(pages, seg.attrs | extra_attrs)
+                .map_err(LoadError::AddressSpace)?;
+        }
+        Ok(bias)
+    }
+
+    /// Copies the part of the file at `offs` in `chunk` into whichever
+    /// segments it belongs to, for an image loaded into `space` with `bias`
+    fn write_contents<A: AddressSpace>(
+        &self,
+        space: &mut A,
+        bias: usize,
+        offs: usize,
+        chunk: &[u8],
+    ) -> Result<(), LoadError<A::Error>> {
+        let chunk_end = offs + chunk.len();
+        for seg in self.segments() {
+            let h = seg.header();
+            let start = offs.max(h.p_offset as usize);
+            let end = chunk_end.min((h.p_offset + h.p_filesz) as usize);
+            if start < end {
+                let va = h.p_vaddr as usize + (start - h.p_offset as usize);
+                space
+                    .write(
+                        VirtAddr(va.wrapping_add(bias)),
+                        &chunk[start - offs..end - offs],
+                    )
+                    .map_err(LoadError::AddressSpace)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the part of the file at `offs` back out of the segments of an
+    /// image loaded into `space` with `bias`, if it is in one of them
+    fn read_loaded<A: AddressSpace>(
+        &self,
+        space: &mut A,
+        bias: usize,
+        offs: usize,
+        buf: &mut [u8],
+    ) -> Result<bool, A::Error> {
+        let end = match offs.checked_add(buf.len()) {
+            Some(end) => end as u64,
+            None => return Ok(false),
+        };
+        let h = match self
+            .segments()
+            .map(|seg| *seg.header())
+            .find(|h| h.p_offset <= offs as u64 && end <= h.p_offset + h.p_filesz)
+        {
+            Some(h) => h,
+            None => return Ok(false),
+        };
+        let va = (h.p_vaddr + (offs as u64 - h.p_offset)) as usize;
+        space.read(VirtAddr(va.wrapping_add(bias)), buf)?;
+        Ok(true)
+    }
+
+    /// Where the image is once it has been loaded with `bias`
+    fn loaded_image(&self, bias: usize) -> LoadedImage {
+        let span = self.virt_span();
+        let biased = |va: usize| VirtAddr(va.wrapping_add(bias));
+        LoadedImage {
+            bias,
+            entry: biased(self.entry().get()),
+            virt_span: biased(span.start.get())..biased(span.end.get()),
+            phdr: self.phdr_vaddr().map(|va| biased(va.get())),
+            phnum: self.header.e_phnum as usize,
+            tls: self.tls().map(|tls| TlsTemplate {
+                template: biased(tls.template.get()),
+                ..tls
+            }),
+        }
+    }
+
+    /// Maps each segment into `space` with `extra_attrs` in addition to the
+    /// segment's own permissions, copies in the contents, and applies
+    /// relocations if the image is position independent.
+    ///
+    /// # Panics
+    /// If only the start of the file was parsed, with [`Elf::parse_head`].
+    pub fn load<A: AddressSpace>(
+        &self,
+        space: &mut A,
+        extra_attrs: PteAttrs,
+    ) -> Result<LoadedImage, LoadError<A::Error>> {
+        assert_eq!(
+            self.data.len(),
+            self.file_len,
+            "only the start of the file was parsed, so it has to be streamed"
+        );
+        let bias = self.map_segments(space, extra_attrs)?;
+        self.write_contents(space, bias, 0, self.data)?;
+        let mut read = file_reader(self.data);
+        self.relocate(space, &self.dynamic, bias, |_, offs, buf| {
+            Ok(read(offs, buf).unwrap_or_else(|never| match never {}))
+        })?;
+        Ok(self.loaded_image(bias))
+    }
+
+    /// Like [`Elf::load`], but for an ELF parsed with [`Elf::parse_head`],
+    /// whose contents after the start that was parsed come from `stream`.
+    /// Only as much of the file as the segments need is read, and the rest of
+    /// it never has to be in memory. The tables the dynamic section points to
+    /// are read back out of `space` once the segments are loaded, so bad
+    /// ones are only found then.
+    pub fn load_stream<A: AddressSpace>(
+        &self,
+        stream: &mut impl FileStream,
+        space: &mut A,
+        extra_attrs: PteAttrs,
+    ) -> Result<LoadedImage, LoadError<A::Error>> {
+        let bias = self.map_segments(space, extra_attrs)?;
+        self.write_contents(space, bias, 0, self.data)?;
+
+        let needed = self
+            .segments()
+            .map(|seg| (seg.header().p_offset + seg.header().p_filesz) as usize)
+            .max()
+            .unwrap_or(0);
+        let mut offs = self.data.len();
+        let mut buf = [0u8; 512];
+        while offs < needed {
+            let want = (needed - offs).min(buf.len());
+            let len = stream.read(&mut buf[..want])?;
+            if len == 0 {
+                // the file was shorter than it claimed to be
+                let seg = self
+                    .segments()
+                    .find(|seg| seg.header().p_offset + seg.header().p_filesz > offs as u64)
+                    .unwrap();
+                return Err(ElfLoadErr::SegmentOutOfBounds { index: seg.index }.into());
+            }
+            self.write_contents(space, bias, offs, &buf[..len])?;
+            offs += len;
+        }
+
+        if self.is_pie() {
+            let dynamic =
+                self.read_dynamic(&mut |offs, buf| self.read_loaded(space, bias, offs, buf))?;
+            self.relocate(space, &dynamic, bias, |space, offs, buf| {
+                self.read_loaded(space, bias, offs, buf)
+            })?;
+        }
+        Ok(self.loaded_image(bias))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::convert::TryInto;
+    use std::vec::Vec;
+
+    const STATIC: &[u8] = include_bytes!("../testfiles/static.elf");
+    const PIE: &[u8] = include_bytes!("../testfiles/pie.elf");
+    const SHARED: &[u8] = include_bytes!("../testfiles/shared.elf");
+    const TLS: &[u8] = include_bytes!("../testfiles/tls.elf");
+
+    /// An address space made of separately allocated pages
+    #[derive(Default)]
+    struct TestSpace {
+        pages: BTreeMap<usize, (Vec<u8>, PteAttrs)>,
+        base: Option<VirtAddr>,
+    }
+
+    impl TestSpace {
+        fn read(&self, va: usize, len: usize) -> Vec<u8> {
+            (va..va + len)
+                .map(|a| self.pages[&(a & !0xfff)].0[a & 0xfff])
+                .collect()
+        }
+
+        fn read_u64(&self, va: usize) -> u64 {
+            u64::from_le_bytes(self.read(va, 8).try_into().unwrap())
+        }
+    }
+
+    impl AddressSpace for TestSpace {
+        type Error = &'static str;
+
+        fn map_zeroed(
+            &mut self,
+            virt: Range<VirtAddr>,
+            attrs: PteAttrs,
+        ) -> Result<(), &'static str> {
+            for page in (virt.start.0..virt.end.0).step_by(4096) {
+                if self
+                    .pages
+                    .insert(page, (std::vec![0; 4096], attrs))
+                    .is_some()
+                {
+                    return Err("already mapped");
+                }
+            }
+            Ok(())
+        }
+
+        fn write(&mut self, va: VirtAddr, data: &[u8]) -> Result<(), &'static str> {
+            for (i, &b) in data.iter().enumerate() {
+                let a = va.0 + i;
+                let page = self.pages.get_mut(&(a & !0xfff)).ok_or("not mapped")?;
+                page.0[a & 0xfff] = b;
+            }
+            Ok(())
+        }
+
+        fn read(&mut self, va: VirtAddr, buf: &mut [u8]) -> Result<(), &'static str> {
+            for (i, b) in buf.iter_mut().enumerate() {
+                let a = va.0 + i;
+                let page = self.pages.get(&(a & !0xfff)).ok_or("not mapped")?;
+                *b = page.0[a & 0xfff];
+            }
+            Ok(())
+        }
+
+        fn choose_base(&mut self, link_span: Range<VirtAddr>) -> Result<VirtAddr, &'static str> {
+            Ok(self.base.unwrap_or_else(|| default_base(&link_span)))
+        }
+    }
+
+    /// Makes a copy of `STATIC` with the program header `idx` modified by `f`
+    fn patch_phdr(idx: usize, f: impl FnOnce(&mut ProgramHeader)) -> Vec<u8> {
+        let mut data = STATIC.to_vec();
+        let elf = Elf::parse(STATIC).unwrap();
+        let mut phdr = elf.program_headers().nth(idx).unwrap();
+        f(&mut phdr);
+        let offs = elf.header().e_phoff as usize + idx * mem::size_of::<ProgramHeader>();
+        unsafe {
+            ptr::write_unaligned(data[offs..].as_mut_ptr() as *mut ProgramHeader, phdr);
+        }
+        data
+    }
+
+    #[test]
+    fn test_load_static() {
+        // make sure misaligned files work
+        let mut buf = std::vec![0u8; STATIC.len() + 1];
+        buf[1..].copy_from_slice(STATIC);
+        let elf = Elf::parse(&buf[1..]).unwrap();
+        assert_eq!(elf.entry(), VirtAddr(0x10_0000));
+        assert_eq!(elf.virt_span(), VirtAddr(0x10_0000)..VirtAddr(0x20_3000));
+
+        let mut space = TestSpace::default();
+        let loaded = elf.load(&mut space, PteAttrs::User).unwrap();
+        assert_eq!(loaded.bias, 0);
+        assert_eq!(loaded.entry, VirtAddr(0x10_0000));
+        // the headers are not in the first segment
+        assert_eq!(loaded.phdr, None);
+
+        let mapped: Vec<_> = space
+            .pages
+            .iter()
+            .map(|(&va, &(_, attrs))| (va, attrs))
+            .collect();
+        let rx = PteAttrs::R | PteAttrs::X | PteAttrs::User;
+        let r = PteAttrs::R | PteAttrs::User;
+        let rw = PteAttrs::R | PteAttrs::W | PteAttrs::User;
+        assert_eq!(
+            mapped,
+            [
+                (0x10_0000, rx),
+                (0x10_1000, r),
+                (0x20_0000, rw),
+                (0x20_1000, rw),
+                (0x20_2000, rw),
+            ]
+        );
+        assert_eq!(space.read(0x10_1000, 24), b"hello from a static elf\0");
+        assert_eq!(
+            space.read(0x20_0000, 8),
+            0x1122334455667788u64.to_le_bytes()
+        );
+        // bss
+        assert!(space.read(0x20_0008, 0x2ff8).iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_load_pie() {
+        let elf = Elf::parse(PIE).unwrap();
+        assert!(elf.is_pie());
+        assert_eq!(elf.virt_span(), VirtAddr(0)..VirtAddr(0x4000));
+
+        for &base in &[DEFAULT_PIE_BASE, VirtAddr(0x7654_3000)] {
+            let mut space = TestSpace {
+                base: Some(base),
+                ..Default::default()
+            };
+            let loaded = elf.load(&mut space, PteAttrs::User).unwrap();
+            let base = base.get();
+            assert_eq!(loaded.bias, base);
+            assert_eq!(loaded.entry, VirtAddr(base + 0x1000));
+            assert_eq!(loaded.virt_span, VirtAddr(base)..VirtAddr(base + 0x4000));
+            assert_eq!(space.pages.keys().next(), Some(&base));
+            assert_eq!(loaded.phdr, Some(VirtAddr(base + 0x40)));
+            assert_eq!(loaded.phnum, elf.header().e_phnum as usize);
+
+            // table: .dword _start, .dword message + 6
+            let message = space.read_u64(base + 0x3008) as usize;
+            assert_eq!(space.read_u64(base + 0x3000), (base + 0x1000) as u64);
+            assert_eq!(space.read(message - 6, 17), b"hello from a pie\0");
+        }
+
+        // first relocation, in .rela.dyn at 0x248, becomes R_RISCV_JUMP_SLOT
+        let mut data = PIE.to_vec();
+        data[0x248 + 8] = 5;
+        assert_eq!(
+            Elf::parse(&data).err(),
+            Some(ElfLoadErr::UnsupportedRelocation(5))
+        );
+    }
+
+    #[test]
+    fn test_load_shared() {
+        let elf = Elf::parse(SHARED).unwrap();
+        let mut space = TestSpace::default();
+        let loaded = elf.load(&mut space, PteAttrs::User).unwrap();
+        let base = DEFAULT_PIE_BASE.get();
+        assert_eq!(loaded.bias, base);
+
+        // exported: .dword 0x1234
+        // pointers: .dword exported + 8, .dword missing, .dword pointers
+        assert_eq!(space.read_u64(base + 0x3000), 0x1234);
+        assert_eq!(space.read_u64(base + 0x3008), (base + 0x3008) as u64);
+        assert_eq!(space.read_u64(base + 0x3010), 0);
+        assert_eq!(space.read_u64(base + 0x3018), (base + 0x3008) as u64);
+    }
+
+    /// Gives out a file a few bytes at a time, failing at `fail_at` if it
+    /// gets there
+    struct Trickle<'a> {
+        data: &'a [u8],
+        fail_at: usize,
+    }
+
+    impl FileStream for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, ElfLoadErr> {
+            if self.fail_at < 7 {
+                return Err(ElfLoadErr::Unreadable);
+            }
+            let len = buf.len().min(self.data.len()).min(7);
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            self.fail_at -= len;
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_load_stream() {
+        // the program headers end at 0x200 in all of these
+        for &file in &[STATIC, PIE, SHARED, TLS] {
+            let mut space = TestSpace::default();
+            let loaded = Elf::parse(file)
+                .unwrap()
+                .load(&mut space, PteAttrs::User)
+                .unwrap();
+
+            let elf = Elf::parse_head(&file[..0x200], file.len()).unwrap();
+            let mut stream = Trickle {
+                data: &file[0x200..],
+                fail_at: usize::MAX,
+            };
+            let mut streamed = TestSpace::default();
+            let got = elf
+                .load_stream(&mut stream, &mut streamed, PteAttrs::User)
+                .unwrap();
+            assert_eq!(got, loaded);
+            assert!(streamed.pages == space.pages);
+        }
+
+        let elf = Elf::parse_head(&PIE[..0x200], PIE.len()).unwrap();
+        let last = elf.segments().last().unwrap().index;
+        let mut short = &PIE[0x200..0x3008];
+        assert_eq!(
+            elf.load_stream(&mut short, &mut TestSpace::default(), PteAttrs::User)
+                .err(),
+            Some(LoadError::Elf(ElfLoadErr::SegmentOutOfBounds {
+                index: last
+            }))
+        );
+        let mut broken = Trickle {
+            data: &PIE[0x200..],
+            fail_at: 0x1000,
+        };
+        assert_eq!(
+            elf.load_stream(&mut broken, &mut TestSpace::default(), PteAttrs::User)
+                .err(),
+            Some(LoadError::Elf(ElfLoadErr::Unreadable))
+        );
+        assert_eq!(
+            Elf::parse_head(&PIE[..0x100], PIE.len()).err(),
+            Some(ElfLoadErr::PhdrsOutOfBounds)
+        );
+    }
+
+    #[test]
+    fn test_load_tls() {
+        assert_eq!(Elf::parse(STATIC).unwrap().tls(), None);
+
+        let elf = Elf::parse(TLS).unwrap();
+        let mut space = TestSpace::default();
+        let loaded = elf.load(&mut space, PteAttrs::User).unwrap();
+        let base = DEFAULT_PIE_BASE.get();
+        let tls = loaded.tls.unwrap();
+        assert_eq!(
+            tls,
+            TlsTemplate {
+                template: VirtAddr(base + 0x2000),
+                file_size: 8,
+                mem_size: 0x80,
+                align: 0x40,
+            }
+        );
+        // counter: .dword 42
+        assert_eq!(space.read_u64(tls.template.get()), 42);
+
+        let tls_idx = elf
+            .program_headers()
+            .position(|h| h.p_type == PT_TLS)
+            .unwrap();
+        let mut data = TLS.to_vec();
+        let offs = elf.header().e_phoff as usize + tls_idx * PHDR_SIZE;
+        let mut phdr = elf.program_headers().nth(tls_idx).unwrap();
+        phdr.p_align = 3;
+        unsafe { ptr::write_unaligned(data[offs..].as_mut_ptr() as *mut ProgramHeader, phdr) };
+        assert_eq!(Elf::parse(&data).err(), Some(ElfLoadErr::BadTls));
+    }
+
+    #[test]
+    fn test_bad_headers() {
+        let mut data = STATIC.to_vec();
+        data[EI_CLASS] = 1;
+        assert_eq!(Elf::parse(&data).err(), Some(ElfLoadErr::WrongClass(1)));
+
+        let mut data = STATIC.to_vec();
+        data[18] = 0x3e;
+        assert_eq!(
+            Elf::parse(&data).err(),
+            Some(ElfLoadErr::WrongMachine(0x3e))
+        );
+
+        assert_eq!(Elf::parse(&STATIC[..63]).err(), Some(ElfLoadErr::TooShort));
+        assert_eq!(
+            Elf::parse(&STATIC[..100]).err(),
+            Some(ElfLoadErr::PhdrsOutOfBounds)
+        );
+        assert_eq!(Elf::parse(&[0; 64]).err(), Some(ElfLoadErr::BadMagic));
+    }
+
+    #[test]
+    fn test_bad_segments() {
+        let data = patch_phdr(0, |h| h.p_flags |= PF_W);
+        assert_eq!(
+            Elf::parse(&data).err(),
+            Some(ElfLoadErr::WriteExecute { index: 0 })
+        );
+
+        let data = patch_phdr(1, |h| h.p_filesz = 0x10_0000);
+        assert_eq!(
+            Elf::parse(&data).err(),
+            Some(ElfLoadErr::FileszExceedsMemsz { index: 1 })
+        );
+
+        let data = patch_phdr(1, |h| {
+            h.p_filesz = 0x10_0000;
+            h.p_memsz = 0x10_0000
+        });
+        assert_eq!(
+            Elf::parse(&data).err(),
+            Some(ElfLoadErr::SegmentOutOfBounds { index: 1 })
+        );
+
+        let data = patch_phdr(1, |h| h.p_vaddr = 0x10_0100);
+        assert_eq!(
+            Elf::parse(&data).err(),
+            Some(ElfLoadErr::SegmentMisaligned { index: 1 })
+        );
+
+        let data = patch_phdr(2, |h| h.p_vaddr = 0x10_1000);
+        assert_eq!(
+            Elf::parse(&data).err(),
+            Some(ElfLoadErr::OverlappingSegments {
+                first: 1,
+                second: 2
+            })
+        );
+
+        let data = patch_phdr(2, |h| h.p_vaddr = 0xffff_ffff_ffff_f000);
+        assert_eq!(
+            Elf::parse(&data).err(),
+            Some(ElfLoadErr::SegmentOverflow { index: 2 })
+        );
+    }
+}
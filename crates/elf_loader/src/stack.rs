@@ -0,0 +1,413 @@
+//! Building the initial stack of a process in the System V ABI layout:
+//!
+//! ```text
+//! top ->  data pushed with StackBuilder::push: strings, AT_RANDOM bytes, ...
+//!         padding to 16 bytes
+//!         auxv: (type, value) pairs ending with AT_NULL
+//!         envp: pointers ending with null
+//!         argv: pointers ending with null
+//! sp ->   argc
+//! ```
+//!
+//! The stack is built in a buffer then copied to the process by the caller.
+//! [`ProcessStack`] fills it in the way every mu process expects.
+
+use core::mem;
+use core::slice;
+
+use mu_shared::auxv::*;
+use mu_shared::{BootInfo, TlsInfo};
+use riscv_paging::{AccessError, Addr, PageTable, PhysAccess, VirtAddr, PAGE_SIZE};
+
+use crate::{LoadedImage, PHDR_SIZE};
+
+/// Alignment of the stack pointer required by the RISC-V calling convention
+pub const STACK_ALIGN: usize = 16;
+
+/// Most arguments, and separately environment variables, a process can start
+/// with
+pub const MAX_ARGS: usize = 32;
+
+/// The stack buffer is too small for what was pushed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StackOverflow;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StackError {
+    /// The stack buffer is too small for what was pushed
+    Overflow,
+    /// More than [`MAX_ARGS`] arguments or environment variables
+    TooManyArgs,
+    /// Could not write the stack into the process
+    Access(AccessError),
+}
+
+impl From<StackOverflow> for StackError {
+    fn from(_: StackOverflow) -> Self {
+        StackError::Overflow
+    }
+}
+
+impl From<AccessError> for StackError {
+    fn from(e: AccessError) -> Self {
+        StackError::Access(e)
+    }
+}
+
+/// Builds an initial stack, from the top down
+pub struct StackBuilder<'a> {
+    buf: &'a mut [u8],
+    top: VirtAddr,
+    /// Offset in `buf` of the lowest byte used so far
+    pos: usize,
+}
+
+impl<'a> StackBuilder<'a> {
+    /// Starts building a stack in `buf`, which will be copied so that it ends
+    /// at `top`. `top` must be aligned to [`STACK_ALIGN`].
+    pub fn new(buf: &'a mut [u8], top: VirtAddr) -> StackBuilder<'a> {
+        assert!(
+            top.get() & (STACK_ALIGN - 1) == 0,
+            "stack top {:?} misaligned",
+            top
+        );
+        let pos = buf.len();
+        StackBuilder { buf, top, pos }
+    }
+
+    /// Virtual address of the byte at `pos` in the buffer
+    fn addr_of(&self, pos: usize) -> VirtAddr {
+        VirtAddr(self.top.get() - (self.buf.len() - pos))
+    }
+
+    /// Reserves `len` bytes aligned to `align` below what is already used,
+    /// returning their offset in the buffer
+    fn reserve(&mut self, len: usize, align: usize) -> Result<usize, StackOverflow> {
+        debug_assert!(align.is_power_of_two() && align <= STACK_ALIGN);
+        // top is aligned, so aligning the distance from the top aligns the
+        // address
+        let from_top = (self.buf.len() - self.pos)
+            .checked_add(len)
+            .and_then(|n| n.checked_add(align - 1))
+            .ok_or(StackOverflow)?
+            & !(align - 1);
+        self.pos = self.buf.len().checked_sub(from_top).ok_or(StackOverflow)?;
+        Ok(self.pos)
+    }
+
+    /// Pushes `data` aligned to `align`, returning its address
+    pub fn push(&mut self, data: &[u8], align: usize) -> Result<VirtAddr, StackOverflow> {
+        let pos = self.reserve(data.len(), align)?;
+        self.buf[pos..pos + data.len()].copy_from_slice(data);
+        Ok(self.addr_of(pos))
+    }
+
+    /// Pushes `s` with a nul terminator, returning its address
+    pub fn push_cstr(&mut self, s: &[u8]) -> Result<VirtAddr, StackOverflow> {
+        let pos = self.reserve(s.len() + 1, 1)?;
+        self.buf[pos..pos + s.len()].copy_from_slice(s);
+        self.buf[pos + s.len()] = 0;
+        Ok(self.addr_of(pos))
+    }
+
+    /// Finishes the stack with the argument, environment and auxiliary
+    /// vectors, which point to things previously pushed. The auxiliary vector
+    /// is terminated for you.
+    ///
+    /// Returns the stack pointer. The part of the buffer to copy to the
+    /// process is the last `top - sp` bytes.
+    pub fn finish(
+        &mut self,
+        argv: &[VirtAddr],
+        envp: &[VirtAddr],
+        auxv: &[(usize, usize)],
+    ) -> Result<VirtAddr, StackOverflow> {
+        let words = 1 + (argv.len() + 1) + (envp.len() + 1) + 2 * (auxv.len() + 1);
+        let mut pos = self.reserve(words * mem::size_of::<usize>(), STACK_ALIGN)?;
+        let sp = self.addr_of(pos);
+
+        let argv = argv.iter().map(|a| a.get());
+        let envp = envp.iter().map(|a| a.get());
+        let auxv = auxv.iter().flat_map(|&(k, v)| [k, v]);
+        let values = Some(argv.len())
+            .into_iter()
+            .chain(argv)
+            .chain(Some(0))
+            .chain(envp)
+            .chain(Some(0))
+            .chain(auxv)
+            // AT_NULL
+            .chain([0, 0]);
+        for v in values {
+            self.buf[pos..pos + mem::size_of::<usize>()].copy_from_slice(&v.to_le_bytes());
+            pos += mem::size_of::<usize>();
+        }
+        Ok(sp)
+    }
+}
+
+/// Views a plain old data struct as bytes
+unsafe fn as_bytes<T>(v: &T) -> &[u8] {
+    slice::from_raw_parts(v as *const T as *const u8, mem::size_of::<T>())
+}
+
+/// Builds the initial stack of a mu process. On top of its arguments and
+/// environment, it gets an auxiliary vector describing the loaded image, 16
+/// random bytes, where its TLS template is if it has one, and for init, a
+/// [`BootInfo`].
+pub struct ProcessStack<'a> {
+    stack: StackBuilder<'a>,
+    loaded: &'a LoadedImage,
+    random: VirtAddr,
+    boot_info: Option<VirtAddr>,
+    tls_info: Option<VirtAddr>,
+    argv: [VirtAddr; MAX_ARGS],
+    argc: usize,
+    envp: [VirtAddr; MAX_ARGS],
+    envc: usize,
+}
+
+impl<'a> ProcessStack<'a> {
+    /// Starts building the stack of `loaded` in `buf`, which will be copied
+    /// so that it ends at `top`, with `random` for `AT_RANDOM`
+    pub fn new(
+        buf: &'a mut [u8],
+        top: VirtAddr,
+        loaded: &'a LoadedImage,
+        random: [u8; 16],
+        boot_info: Option<&BootInfo>,
+    ) -> Result<ProcessStack<'a>, StackError> {
+        let mut stack = StackBuilder::new(buf, top);
+        let random = stack.push(&random, 16)?;
+        // safety: these are repr(C) plain old data
+        let boot_info = match boot_info {
+            Some(info) => Some(stack.push(unsafe { as_bytes(info) }, mem::align_of::<BootInfo>())?),
+            None => None,
+        };
+        let tls_info = match &loaded.tls {
+            Some(tls) => {
+                let info = TlsInfo {
+                    template: tls.template.get(),
+                    file_size: tls.file_size,
+                    mem_size: tls.mem_size,
+                    align: tls.align,
+                };
+                Some(stack.push(unsafe { as_bytes(&info) }, mem::align_of::<TlsInfo>())?)
+            }
+            None => None,
+        };
+        Ok(ProcessStack {
+            stack,
+            loaded,
+            random,
+            boot_info,
+            tls_info,
+            argv: [VirtAddr(0); MAX_ARGS],
+            argc: 0,
+            envp: [VirtAddr(0); MAX_ARGS],
+            envc: 0,
+        })
+    }
+
+    /// Adds an argument after the ones already there. The first one is the
+    /// program's name.
+    pub fn push_arg(&mut self, arg: &[u8]) -> Result<(), StackError> {
+        let va = self.push_listed(arg, self.argc)?;
+        self.argv[self.argc] = va;
+        self.argc += 1;
+        Ok(())
+    }
+
+    /// Adds a `key=value` environment variable
+    pub fn push_env(&mut self, var: &[u8]) -> Result<(), StackError> {
+        let va = self.push_listed(var, self.envc)?;
+        self.envp[self.envc] = va;
+        self.envc += 1;
+        Ok(())
+    }
+
+    /// Pushes a string for a list that has `count` things in it already
+    fn push_listed(&mut self, s: &[u8], count: usize) -> Result<VirtAddr, StackError> {
+        if count == MAX_ARGS {
+            return Err(StackError::TooManyArgs);
+        }
+        Ok(self.stack.push_cstr(s)?)
+    }
+
+    /// Finishes the stack and copies it into the process with page table
+    /// `pt`, where the memory under it must already be mapped. Returns the
+    /// stack pointer to start the process with.
+    ///
+    /// # Safety
+    /// `pt` must be a valid page table.
+    pub unsafe fn write_to<P: PhysAccess>(
+        mut self,
+        pt: PageTable<P>,
+    ) -> Result<VirtAddr, StackError> {
+        let loaded = self.loaded;
+        let mut auxv = [(0, 0); 8];
+        let mut auxc = 0;
+        let mut aux = |k, v| {
+            auxv[auxc] = (k, v);
+            auxc += 1;
+        };
+        if let Some(phdr) = loaded.phdr {
+            aux(AT_PHDR, phdr.get());
+            aux(AT_PHENT, PHDR_SIZE);
+            aux(AT_PHNUM, loaded.phnum);
+        }
+        aux(AT_PAGESZ, PAGE_SIZE as usize);
+        aux(AT_ENTRY, loaded.entry.get());
+        aux(AT_RANDOM, self.random.get());
+        if let Some(boot_info) = self.boot_info {
+            aux(AT_MU_BOOT_INFO, boot_info.get());
+        }
+        if let Some(tls_info) = self.tls_info {
+            aux(AT_MU_TLS, tls_info.get());
+        }
+
+        let sp = self.stack.finish(
+            &self.argv[..self.argc],
+            &self.envp[..self.envc],
+            &auxv[..auxc],
+        )?;
+        pt.write_virt(sp, &self.stack.buf[self.stack.pos..])?;
+        Ok(sp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::convert::TryInto;
+    use std::vec::Vec;
+
+    use riscv_paging::mock::MockPhysMem;
+    use riscv_paging::{PagingMode, PteAttrs};
+
+    use super::*;
+    use crate::TlsTemplate;
+
+    #[test]
+    fn test_initial_stack() {
+        let top = VirtAddr(0x4000_0000);
+        let mut buf = [0xaau8; 256];
+        let mut stack = StackBuilder::new(&mut buf, top);
+        let random = stack.push(&[7; 16], 16).unwrap();
+        let arg0 = stack.push_cstr(b"init").unwrap();
+        let arg1 = stack.push_cstr(b"-v").unwrap();
+        let env0 = stack.push_cstr(b"A=b").unwrap();
+        let sp = stack
+            .finish(
+                &[arg0, arg1],
+                &[env0],
+                &[(AT_PAGESZ, 4096), (AT_RANDOM, random.get())],
+            )
+            .unwrap();
+        assert_eq!(sp.get() % 16, 0);
+
+        let read = |va: usize, len: usize| {
+            let offs = buf.len() - (top.get() - va);
+            &buf[offs..offs + len]
+        };
+        let word =
+            |idx: usize| usize::from_le_bytes(read(sp.get() + idx * 8, 8).try_into().unwrap());
+        let words: Vec<_> = (0..12).map(word).collect();
+        assert_eq!(
+            words,
+            [
+                2,
+                arg0.get(),
+                arg1.get(),
+                0,
+                env0.get(),
+                0,
+                AT_PAGESZ,
+                4096,
+                AT_RANDOM,
+                random.get(),
+                0,
+                0
+            ]
+        );
+        assert_eq!(read(arg0.get(), 5), b"init\0");
+        assert_eq!(read(arg1.get(), 3), b"-v\0");
+        assert_eq!(read(env0.get(), 4), b"A=b\0");
+        assert_eq!(read(random.get(), 16), [7; 16]);
+
+        let mut small = [0u8; 32];
+        let mut stack = StackBuilder::new(&mut small, top);
+        assert_eq!(stack.finish(&[arg0], &[], &[]).err(), Some(StackOverflow));
+    }
+
+    #[test]
+    fn test_process_stack() {
+        MockPhysMem::init(16);
+        let pt = unsafe { PageTable::<MockPhysMem>::alloc(PagingMode::Sv39) }.unwrap();
+        let top = VirtAddr(0x4000_0000);
+        let attrs = PteAttrs::R | PteAttrs::W | PteAttrs::User;
+        unsafe { pt.virt_alloc(VirtAddr(top.get() - 0x3000), 0x3000, attrs) }.unwrap();
+        let tls = TlsTemplate {
+            template: VirtAddr(top.get() - 0x3000),
+            file_size: 3,
+            mem_size: 16,
+            align: 8,
+        };
+        let loaded = LoadedImage {
+            bias: 0,
+            entry: VirtAddr(0x1_0000),
+            virt_span: VirtAddr(0x1_0000)..VirtAddr(0x2_0000),
+            phdr: None,
+            phnum: 0,
+            tls: Some(tls.clone()),
+        };
+
+        let mut buf = [0u8; 256];
+        let mut stack = ProcessStack::new(&mut buf, top, &loaded, [7; 16], None).unwrap();
+        stack.push_arg(b"prog").unwrap();
+        stack.push_env(b"A=b").unwrap();
+        let sp = unsafe { stack.write_to(pt) }.unwrap();
+        assert_eq!(sp.get() % 16, 0);
+
+        let read = |va: usize, len: usize| {
+            let mut buf = vec![0; len];
+            unsafe { pt.read_virt(VirtAddr(va), &mut buf) }.unwrap();
+            buf
+        };
+        let word =
+            |idx: usize| usize::from_le_bytes(read(sp.get() + idx * 8, 8).try_into().unwrap());
+        assert_eq!([word(0), word(2), word(4)], [1, 0, 0]);
+        assert_eq!(read(word(1), 5), b"prog\0");
+        assert_eq!(read(word(3), 4), b"A=b\0");
+        let auxv: BTreeMap<_, _> = (0..)
+            .map(|i| (word(5 + 2 * i), word(6 + 2 * i)))
+            .take_while(|&(k, _)| k != AT_NULL)
+            .collect();
+        assert_eq!(
+            auxv.keys().copied().collect::<Vec<_>>(),
+            [AT_PAGESZ, AT_ENTRY, AT_RANDOM, AT_MU_TLS]
+        );
+        assert_eq!(auxv[&AT_ENTRY], 0x1_0000);
+        assert_eq!(read(auxv[&AT_RANDOM], 16), [7; 16]);
+        let tls_info = read(auxv[&AT_MU_TLS], 32);
+        assert_eq!(tls_info[..8], tls.template.get().to_le_bytes());
+
+        let mut buf = [0u8; 1024];
+        let mut stack = ProcessStack::new(&mut buf, top, &loaded, [0; 16], None).unwrap();
+        for _ in 0..MAX_ARGS {
+            stack.push_arg(b"").unwrap();
+        }
+        assert_eq!(stack.push_arg(b"").err(), Some(StackError::TooManyArgs));
+
+        // the block gets the template and zeros, whatever was there before
+        unsafe {
+            pt.write_virt(tls.template, b"abc").unwrap();
+            pt.write_virt(VirtAddr(top.get() - 0x2000), &[0xaa; 16])
+                .unwrap();
+        }
+        let (block, len) = tls.block_below(VirtAddr(top.get() - 0x1000)).unwrap();
+        assert_eq!((block.get(), len), (top.get() - 0x2000, 0x1000));
+        unsafe { tls.init_block(pt, block) }.unwrap();
+        assert_eq!(read(block.get(), 16), b"abc\0\0\0\0\0\0\0\0\0\0\0\0\0");
+    }
+}
@@ -0,0 +1,18 @@
+# A position independent executable with R_RISCV_RELATIVE relocations
+    .text
+    .globl _start
+_start:
+    lla a0, table
+    ld a1, 0(a0)
+1:  j 1b
+
+    .section .rodata
+message:
+    .asciz "hello from a pie"
+
+    .data
+    .p2align 3
+    .globl table
+table:
+    .dword _start
+    .dword message + 6
@@ -0,0 +1,17 @@
+# A shared object, which has R_RISCV_64 relocations against its own exported
+# symbols and an undefined weak symbol
+    .text
+    .globl _start
+_start:
+1:  j 1b
+
+    .data
+    .p2align 3
+    .globl exported
+exported:
+    .dword 0x1234
+    .weak missing
+pointers:
+    .dword exported + 8
+    .dword missing
+    .dword pointers
@@ -0,0 +1,24 @@
+# A tiny static executable with a gap between its text and data segments
+    .text
+    .globl _start
+_start:
+    la a0, message
+    la a1, counter
+    ld a2, 0(a1)
+    addi a2, a2, 1
+    sd a2, 0(a1)
+1:  j 1b
+
+    .section .rodata
+message:
+    .asciz "hello from a static elf"
+
+    .data
+    .p2align 3
+counter:
+    .dword 0x1122334455667788
+
+    .bss
+    .p2align 12
+scratch:
+    .zero 0x2000
@@ -0,0 +1,18 @@
+# A position independent executable with thread local storage
+    .text
+    .globl _start
+_start:
+    lui a0, %tprel_hi(counter)
+    add a0, a0, tp, %tprel_add(counter)
+    ld a1, %tprel_lo(counter)(a0)
+1:  j 1b
+
+    .section .tdata, "awT", @progbits
+    .p2align 3
+counter:
+    .dword 42
+
+    .section .tbss, "awT", @nobits
+    .p2align 6
+scratch:
+    .zero 64
@@ -0,0 +1,25 @@
+//! Opening and walking arbitrary archives must fail cleanly rather than panic
+#![no_main]
+use fallible_iterator::FallibleIterator;
+use libfuzzer_sys::fuzz_target;
+use microflop::{Microflop, MAGIC};
+
+fn walk(data: &[u8]) {
+    let _ = Microflop::new(data);
+    // checksums would keep nearly everything from getting further
+    if let Ok(mf) = Microflop::new_unverified(data) {
+        let _ = mf.files().count();
+        let _ = mf.entries().count();
+        let _ = mf.get("init");
+        let _ = mf.verify();
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    walk(data);
+    // most inputs have the wrong magic, so also try them as the rest of an
+    // archive
+    let mut with_magic = MAGIC.to_le_bytes().to_vec();
+    with_magic.extend_from_slice(data);
+    walk(&with_magic);
+});
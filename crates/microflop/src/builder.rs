@@ -0,0 +1,704 @@
+//! Writing microflop archives
+
+use std::convert::TryInto;
+use std::io::{self, Write};
+
+use fallible_iterator::FallibleIterator;
+
+use crate::v2::DATA_ALIGN;
+use crate::{check_path, ed25519, parent, v2, Compression, EntryKind, Error, Microflop, Mode};
+
+/// Deflate level to compress files with, from 0 to 10
+const DEFLATE_LEVEL: u8 = 9;
+
+#[derive(Debug)]
+struct BuilderEntry {
+    path: String,
+    kind: EntryKind,
+    mode: Mode,
+    /// contents as stored
+    contents: Vec<u8>,
+    compression: Compression,
+    /// length of the contents before compression, if they are compressed
+    size: u32,
+    /// alignment of the contents from the start of the archive, a power of
+    /// two of at least [`DATA_ALIGN`]
+    align: usize,
+}
+
+/// Builds a version 2 microflop archive out of files in memory.
+///
+/// Directories containing added files are added automatically.
+#[derive(Debug, Default)]
+pub struct MicroflopBuilder {
+    entries: Vec<BuilderEntry>,
+    /// key to sign the archive with, if any
+    signing_key: Option<ed25519::KeyPair>,
+}
+
+impl MicroflopBuilder {
+    pub fn new() -> MicroflopBuilder {
+        MicroflopBuilder::default()
+    }
+
+    /// Makes a builder holding the files and directories of an existing
+    /// archive, keeping compressed files as they are. The signature is
+    /// dropped since any change would invalidate it.
+    pub fn from_archive(archive: &Microflop<'_>) -> Result<MicroflopBuilder, Error> {
+        let mut builder = MicroflopBuilder::new();
+        let mut entries = archive.entries();
+        while let Some(entry) = entries.next()? {
+            if entry.kind == EntryKind::Signature {
+                continue;
+            }
+            let size = match entry.compression {
+                Compression::None => 0,
+                _ => entry.size.try_into().map_err(|_| Error::BadEntry)?,
+            };
+            builder.add_entry(BuilderEntry {
+                path: entry.path.to_owned(),
+                kind: entry.kind,
+                mode: entry.mode,
+                contents: entry.contents.to_vec(),
+                compression: entry.compression,
+                size,
+                align: entry.align.max(DATA_ALIGN),
+            })?;
+        }
+        Ok(builder)
+    }
+
+    /// Signs the archive with `key` when writing it
+    pub fn sign(&mut self, key: ed25519::KeyPair) -> &mut MicroflopBuilder {
+        self.signing_key = Some(key);
+        self
+    }
+
+    fn find(&self, path: &str) -> Option<&BuilderEntry> {
+        self.entries.iter().find(|e| e.path == path)
+    }
+
+    /// Adds the directories containing `path` that are not there yet. Fails
+    /// if one of them is a file.
+    fn add_parents(&mut self, path: &str) -> Result<(), Error> {
+        let dir = parent(path);
+        if dir.is_empty() {
+            return Ok(());
+        }
+        match self.find(dir).map(|e| e.kind) {
+            Some(EntryKind::Dir) => Ok(()),
+            Some(_) => Err(Error::BadEntry),
+            None => {
+                self.add_parents(dir)?;
+                self.entries.push(BuilderEntry {
+                    path: dir.to_owned(),
+                    kind: EntryKind::Dir,
+                    mode: Mode::empty(),
+                    contents: Vec::new(),
+                    compression: Compression::None,
+                    size: 0,
+                    align: DATA_ALIGN,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    fn add_entry(&mut self, entry: BuilderEntry) -> Result<&mut MicroflopBuilder, Error> {
+        check_path(&entry.path)?;
+        match self.find(&entry.path) {
+            // adding a directory that is already there is harmless
+            Some(e) if e.kind == EntryKind::Dir && entry.kind == EntryKind::Dir => return Ok(self),
+            Some(_) => return Err(Error::BadEntry),
+            None => (),
+        }
+        self.add_parents(&entry.path)?;
+        self.entries.push(entry);
+        Ok(self)
+    }
+
+    /// Adds a file to the archive. Fails if the path is invalid or already
+    /// in the archive.
+    pub fn add(&mut self, path: &str, contents: Vec<u8>) -> Result<&mut MicroflopBuilder, Error> {
+        self.add_with_mode(path, contents, Mode::empty())
+    }
+
+    /// Adds a file with the given mode to the archive. Fails if the path is
+    /// invalid or already in the archive.
+    pub fn add_with_mode(
+        &mut self,
+        path: &str,
+        contents: Vec<u8>,
+        mode: Mode,
+    ) -> Result<&mut MicroflopBuilder, Error> {
+        self.add_with_options(path, contents, mode, Compression::None)
+    }
+
+    /// Adds a file with the given mode to the archive, compressing it if
+    /// `compression` asks and that makes it smaller. Fails if the path is
+    /// invalid or already in the archive, or the file is 4GiB or larger.
+    pub fn add_with_options(
+        &mut self,
+        path: &str,
+        contents: Vec<u8>,
+        mode: Mode,
+        compression: Compression,
+    ) -> Result<&mut MicroflopBuilder, Error> {
+        let entry = file_entry(path, contents, mode, compression)?;
+        self.add_entry(entry)
+    }
+
+    /// Replaces the contents and mode of the file at `path`, keeping its
+    /// place in the archive. Fails if there is no file there.
+    pub fn replace(
+        &mut self,
+        path: &str,
+        contents: Vec<u8>,
+        mode: Mode,
+        compression: Compression,
+    ) -> Result<&mut MicroflopBuilder, Error> {
+        let idx = self
+            .entries
+            .iter()
+            .position(|e| e.path == path)
+            .ok_or(Error::NotFound)?;
+        if self.entries[idx].kind != EntryKind::File {
+            return Err(Error::BadEntry);
+        }
+        let align = self.entries[idx].align;
+        self.entries[idx] = BuilderEntry {
+            align,
+            ..file_entry(path, contents, mode, compression)?
+        };
+        Ok(self)
+    }
+
+    /// Aligns the contents of the file at `path` to `align` bytes from the
+    /// start of the archive, for files that get used in place. `align` must
+    /// be a power of two; files are always aligned to at least 8 bytes.
+    pub fn set_align(&mut self, path: &str, align: usize) -> Result<&mut MicroflopBuilder, Error> {
+        if !align.is_power_of_two() {
+            return Err(Error::BadEntry);
+        }
+        let entry = self
+            .entries
+            .iter_mut()
+            .find(|e| e.path == path)
+            .ok_or(Error::NotFound)?;
+        if entry.kind != EntryKind::File {
+            return Err(Error::BadEntry);
+        }
+        entry.align = align.max(DATA_ALIGN);
+        Ok(self)
+    }
+
+    /// Removes the entry at `path`, along with everything in it if it is a
+    /// directory. Fails if there is no such entry.
+    pub fn remove(&mut self, path: &str) -> Result<&mut MicroflopBuilder, Error> {
+        if self.find(path).is_none() {
+            return Err(Error::NotFound);
+        }
+        self.entries.retain(|e| {
+            let inside = e.path.starts_with(path) && e.path[path.len()..].starts_with('/');
+            e.path != path && !inside
+        });
+        Ok(self)
+    }
+
+    /// Adds an empty directory to the archive
+    pub fn add_dir(&mut self, path: &str) -> Result<&mut MicroflopBuilder, Error> {
+        self.add_entry(BuilderEntry {
+            path: path.to_owned(),
+            kind: EntryKind::Dir,
+            mode: Mode::empty(),
+            contents: Vec::new(),
+            compression: Compression::None,
+            size: 0,
+            align: DATA_ALIGN,
+        })
+    }
+
+    /// Writes the archive to `w`, with checksums
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.to_vec()?)
+    }
+
+    /// Writes the archive to a new buffer, with checksums, and signed if
+    /// there is a signing key
+    pub fn to_vec(&self) -> io::Result<Vec<u8>> {
+        let too_big = || io::Error::new(io::ErrorKind::InvalidInput, "archive exceeds 4GiB");
+        let to_u32 = |n: usize| -> io::Result<u32> { n.try_into().map_err(|_| too_big()) };
+
+        let count = self.entries.len() + self.signing_key.is_some() as usize;
+        let strtab_offset = v2::HEADER_SIZE + v2::CHECKSUM_SIZE + v2::ENTRY_SIZE * count;
+        let strtab_len: usize = self.entries.iter().map(|e| e.path.len()).sum();
+        let strtab_end = strtab_offset + strtab_len;
+        let data_start = align_up(strtab_end, DATA_ALIGN);
+
+        // the table checksum covers everything up to the data, so build it
+        // in memory first
+        let mut header = Vec::with_capacity(v2::HEADER_SIZE);
+        v2::Header {
+            magic: v2::MAGIC,
+            version: v2::VERSION,
+            flags: v2::Flags::Checksums,
+            count: to_u32(count)?,
+            strtab_offset: to_u32(strtab_offset)?,
+            strtab_len: to_u32(strtab_len)?,
+        }
+        .serialize(&mut header)?;
+
+        let mut table = Vec::with_capacity(strtab_end - v2::HEADER_SIZE);
+        let mut name_offset = 0;
+        let mut out_pos = data_start;
+        let mut begins = Vec::with_capacity(self.entries.len());
+        for entry in &self.entries {
+            let begin = align_up(out_pos, entry.align);
+            let file_end = begin + entry.contents.len();
+            begins.push(begin);
+            v2::HeaderEntry {
+                name_offset: to_u32(name_offset)?,
+                name_len: to_u32(entry.path.len())?,
+                begin: to_u32(begin)?,
+                end: to_u32(file_end)?,
+                kind: entry.kind,
+                mode: entry.mode,
+                checksum: crc32fast::hash(&entry.contents),
+                compression: entry.compression,
+                align_log2: match entry.align {
+                    DATA_ALIGN => 0,
+                    align => align.trailing_zeros() as u8,
+                },
+                size: entry.size,
+            }
+            .serialize(&mut table)?;
+            name_offset += entry.path.len();
+            out_pos = align_up(file_end, DATA_ALIGN);
+        }
+        if self.signing_key.is_some() {
+            // the signature goes right after the data, which is all aligned
+            v2::HeaderEntry {
+                name_offset: to_u32(name_offset)?,
+                name_len: 0,
+                begin: to_u32(out_pos)?,
+                end: to_u32(out_pos + v2::SIGNATURE_SIZE)?,
+                kind: EntryKind::Signature,
+                mode: Mode::empty(),
+                checksum: 0,
+                compression: Compression::None,
+                align_log2: 0,
+                size: 0,
+            }
+            .serialize(&mut table)?;
+        }
+        for entry in &self.entries {
+            table.extend_from_slice(entry.path.as_bytes());
+        }
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header);
+        hasher.update(&table);
+
+        let mut out = header;
+        v2::TableChecksum {
+            crc: hasher.finalize(),
+        }
+        .serialize(&mut out)?;
+        out.extend_from_slice(&table);
+        // everything between the entries is zero padding
+        for (entry, &begin) in self.entries.iter().zip(&begins) {
+            out.resize(begin, 0);
+            out.extend_from_slice(&entry.contents);
+        }
+        out.resize(out_pos, 0);
+        if let Some(key) = &self.signing_key {
+            let signature = key.sk.sign(&out, None);
+            out.extend_from_slice(&signature[..]);
+        }
+        Ok(out)
+    }
+}
+
+/// Makes the entry for a file, compressed if `compression` asks and that
+/// makes it smaller
+fn file_entry(
+    path: &str,
+    contents: Vec<u8>,
+    mode: Mode,
+    compression: Compression,
+) -> Result<BuilderEntry, Error> {
+    let size = contents.len().try_into().map_err(|_| Error::BadEntry)?;
+    if compression == Compression::Deflate {
+        let compressed = miniz_oxide::deflate::compress_to_vec(&contents, DEFLATE_LEVEL);
+        if compressed.len() < contents.len() {
+            return Ok(BuilderEntry {
+                path: path.to_owned(),
+                kind: EntryKind::File,
+                mode,
+                contents: compressed,
+                compression,
+                size,
+                align: DATA_ALIGN,
+            });
+        }
+    }
+    Ok(BuilderEntry {
+        path: path.to_owned(),
+        kind: EntryKind::File,
+        mode,
+        contents,
+        compression: Compression::None,
+        size: 0,
+        align: DATA_ALIGN,
+    })
+}
+
+/// Rounds `pos` up to a multiple of `align`, which is a power of two
+fn align_up(pos: usize, align: usize) -> usize {
+    (pos + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{Decompressor, Version, MAGIC, WINDOW_SIZE};
+
+    proptest! {
+        #[test]
+        fn roundtrip(files in vec(("[a-z]{1,4}(/[a-z]{1,4}){0,2}", vec(any::<u8>(), 0..64)), 0..8)) {
+            let mut builder = MicroflopBuilder::new();
+            let mut expected = Vec::new();
+            for (path, contents) in files {
+                // paths clashing with earlier ones are rejected
+                if builder.add(&path, contents.clone()).is_ok() {
+                    expected.push((path, contents));
+                }
+            }
+            let bytes = builder.to_vec().unwrap();
+
+            let mf = Microflop::new(&bytes).unwrap();
+            prop_assert_eq!(mf.version(), Version::V2);
+            let mut parsed = Vec::new();
+            let mut iter = mf.files();
+            while let Some(entry) = iter.next().unwrap() {
+                parsed.push((entry.path.to_owned(), entry.contents.to_vec()));
+            }
+            prop_assert_eq!(&parsed, &expected);
+            for (path, contents) in &expected {
+                prop_assert_eq!(mf.get(path).unwrap(), Some(&contents[..]));
+                let dir = parent(path);
+                if !dir.is_empty() {
+                    let entry = mf.entry(dir).unwrap().unwrap();
+                    prop_assert_eq!(entry.kind, EntryKind::Dir);
+                }
+            }
+        }
+
+        #[test]
+        fn garbage_does_not_panic(tail in vec(any::<u8>(), 0..256)) {
+            for magic in &[MAGIC, v2::MAGIC] {
+                let mut bytes = magic.to_le_bytes().to_vec();
+                bytes.extend_from_slice(&tail);
+                let _ = Microflop::new(&bytes);
+                if let Ok(mf) = Microflop::new_unverified(&bytes) {
+                    let _ = mf.files().count();
+                    let _ = mf.entries().count();
+                    let _ = mf.verify();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_dirs() {
+        let mut builder = MicroflopBuilder::new();
+        builder
+            .add_with_mode("bin/init", b"elf".to_vec(), Mode::Exec)
+            .unwrap()
+            .add_dir("etc")
+            .unwrap()
+            .add("a-rather-long-file-name.txt", b"hi".to_vec())
+            .unwrap();
+        assert!(builder.add("bin/init/nope", vec![]).is_err());
+        assert!(builder.add("bin", vec![]).is_err());
+        assert!(builder.add("../x", vec![]).is_err());
+        let bytes = builder.to_vec().unwrap();
+
+        let mf = Microflop::new(&bytes).unwrap();
+        let init = mf.entry("bin/init").unwrap().unwrap();
+        assert_eq!(init.mode, Mode::Exec);
+        assert_eq!(init.contents, b"elf");
+        assert_eq!(mf.get("bin").unwrap(), None);
+
+        let mut root = Vec::new();
+        let mut children = mf.children("");
+        while let Some(entry) = children.next().unwrap() {
+            root.push(entry.path);
+        }
+        assert_eq!(root, ["bin", "etc", "a-rather-long-file-name.txt"]);
+    }
+
+    #[test]
+    fn test_compression() {
+        let text = b"meow ".repeat(200);
+        let mut builder = MicroflopBuilder::new();
+        builder
+            .add_with_options("cat", text.clone(), Mode::empty(), Compression::Deflate)
+            .unwrap()
+            .add_with_options("tiny", b"x".to_vec(), Mode::empty(), Compression::Deflate)
+            .unwrap();
+        let bytes = builder.to_vec().unwrap();
+        let mf = Microflop::new(&bytes).unwrap();
+
+        let cat = mf.entry("cat").unwrap().unwrap();
+        assert_eq!(cat.compression, Compression::Deflate);
+        assert!(cat.contents.len() < text.len());
+        assert_eq!(cat.uncompressed(), None);
+        let mut out = vec![0u8; cat.size + 10];
+        let mut decompressor = Decompressor::new();
+        let got = cat.decompress_into(&mut decompressor, &mut out).unwrap();
+        assert_eq!(got, &text[..]);
+        assert!(matches!(
+            cat.decompress_into(&mut decompressor, &mut out[..10]),
+            Err(Error::BufferTooSmall)
+        ));
+
+        // compressing would make it bigger
+        let tiny = mf.entry("tiny").unwrap().unwrap();
+        assert_eq!(tiny.compression, Compression::None);
+        assert_eq!(tiny.uncompressed(), Some(&b"x"[..]));
+        assert!(tiny
+            .inflater(&mut decompressor, &mut [0; WINDOW_SIZE])
+            .is_none());
+    }
+
+    #[test]
+    fn test_streaming() {
+        // several windows of noise with repeats reaching most of a window
+        // back, so back references cross the point where the window wraps
+        let mut state = 1u32;
+        let mut text = Vec::new();
+        while text.len() < 5 * WINDOW_SIZE {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let word = (state >> 16) as usize;
+            if word & 3 == 0 && text.len() > WINDOW_SIZE {
+                let start = text.len() - WINDOW_SIZE + 1 + word % 1000;
+                text.extend_from_within(start..start + 100);
+            } else {
+                text.push(word as u8);
+            }
+        }
+        let mut builder = MicroflopBuilder::new();
+        builder
+            .add_with_options("noise", text.clone(), Mode::empty(), Compression::Deflate)
+            .unwrap();
+        let bytes = builder.to_vec().unwrap();
+        let mf = Microflop::new(&bytes).unwrap();
+        let noise = mf.entry("noise").unwrap().unwrap();
+        assert_eq!(noise.compression, Compression::Deflate);
+
+        let mut decompressor = Decompressor::new();
+        let mut window = [0u8; WINDOW_SIZE];
+        // an odd size so reads don't line up with the window
+        let mut chunk = [0u8; 1000];
+        let mut got = Vec::new();
+        let mut inflater = noise.inflater(&mut decompressor, &mut window).unwrap();
+        loop {
+            let len = inflater.read(&mut chunk).unwrap();
+            if len == 0 {
+                break;
+            }
+            got.extend_from_slice(&chunk[..len]);
+        }
+        assert!(got == text);
+
+        // the entry claiming to be longer or shorter than it is
+        for size in [text.len() - 1, text.len() + 1].iter() {
+            let mut inflater = decompressor.stream(noise.contents, *size, &mut window);
+            let res = loop {
+                match inflater.read(&mut chunk) {
+                    Ok(0) => break Ok(()),
+                    Ok(_) => (),
+                    Err(e) => break Err(e),
+                }
+            };
+            assert!(matches!(res, Err(Error::BadCompressedData)));
+        }
+        let mut inflater = decompressor.stream(&noise.contents[..100], text.len(), &mut window);
+        assert!(matches!(
+            inflater.read(&mut chunk).and_then(|_| inflater.read(&mut chunk)),
+            Err(Error::BadCompressedData)
+        ));
+    }
+
+    #[test]
+    fn test_checksums() {
+        let mut builder = MicroflopBuilder::new();
+        builder
+            .add("kern", b"kernel".to_vec())
+            .unwrap()
+            .add("init", b"init".to_vec())
+            .unwrap();
+        let bytes = builder.to_vec().unwrap();
+        assert!(Microflop::new(&bytes).unwrap().has_checksums());
+
+        // damage the contents of init
+        let mut damaged = bytes.clone();
+        let init = bytes.len() - 8;
+        damaged[init] ^= 1;
+        assert!(matches!(
+            Microflop::new(&damaged),
+            Err(Error::BadEntryChecksum(1))
+        ));
+        let mf = Microflop::new_unverified(&damaged).unwrap();
+        assert_eq!(mf.get("init").unwrap(), Some(&b"hnit"[..]));
+
+        // damage the string table
+        let mut damaged = bytes.clone();
+        let strtab = v2::HEADER_SIZE + v2::CHECKSUM_SIZE + 2 * v2::ENTRY_SIZE;
+        damaged[strtab] = b'x';
+        assert!(matches!(
+            Microflop::new(&damaged),
+            Err(Error::BadTableChecksum)
+        ));
+    }
+
+    #[test]
+    fn test_signature() {
+        let key = ed25519::KeyPair::from_seed(ed25519::Seed::new([7; 32]));
+        let other = ed25519::KeyPair::from_seed(ed25519::Seed::new([8; 32]));
+        let mut builder = MicroflopBuilder::new();
+        builder
+            .add("kern", b"kernel".to_vec())
+            .unwrap()
+            .add_with_options(
+                "init",
+                b"init ".repeat(50),
+                Mode::Exec,
+                Compression::Deflate,
+            )
+            .unwrap();
+        let unsigned = builder.to_vec().unwrap();
+        assert!(matches!(
+            Microflop::new(&unsigned).unwrap().verify_signature(&key.pk),
+            Err(Error::Unsigned)
+        ));
+
+        let bytes = builder.sign(key.clone()).to_vec().unwrap();
+        let mf = Microflop::new(&bytes).unwrap();
+        mf.verify_signature(&key.pk).unwrap();
+        assert!(matches!(
+            mf.verify_signature(&other.pk),
+            Err(Error::BadSignature)
+        ));
+        // the signature is not a file
+        assert_eq!(mf.files().count().unwrap(), 2);
+
+        // tamper with kern without breaking its checksum, as an attacker
+        // without the key would do
+        let mut tampered = MicroflopBuilder::from_archive(&mf).unwrap();
+        tampered.entries[0].contents = b"evil".to_vec();
+        let mut forged = tampered.sign(other).to_vec().unwrap();
+        assert!(matches!(
+            Microflop::new(&forged).unwrap().verify_signature(&key.pk),
+            Err(Error::BadSignature)
+        ));
+        forged[0] ^= 1;
+        assert!(Microflop::new(&forged).is_err());
+
+        // re-signing an archive keeps its entries as they are
+        let resigned = MicroflopBuilder::from_archive(&mf)
+            .unwrap()
+            .sign(key.clone())
+            .to_vec()
+            .unwrap();
+        assert_eq!(resigned, bytes);
+    }
+
+    #[test]
+    fn test_edit() {
+        let mut builder = MicroflopBuilder::new();
+        builder
+            .add("kern", b"kernel".to_vec())
+            .unwrap()
+            .add("bin/init", b"init".to_vec())
+            .unwrap()
+            .add("bin/sh", b"sh".to_vec())
+            .unwrap()
+            .add("binary", b"not in bin".to_vec())
+            .unwrap();
+        let bytes = builder.to_vec().unwrap();
+
+        let mut edited = MicroflopBuilder::from_archive(&Microflop::new(&bytes).unwrap()).unwrap();
+        edited
+            .replace(
+                "kern",
+                b"new kernel".to_vec(),
+                Mode::Exec,
+                Compression::None,
+            )
+            .unwrap()
+            .remove("bin")
+            .unwrap()
+            .add("etc/motd", b"meow".to_vec())
+            .unwrap();
+        assert!(matches!(edited.remove("bin/sh"), Err(Error::NotFound)));
+        assert!(matches!(
+            edited.replace("etc", vec![], Mode::empty(), Compression::None),
+            Err(Error::BadEntry)
+        ));
+        let bytes = edited.to_vec().unwrap();
+
+        let mf = Microflop::new(&bytes).unwrap();
+        let mut paths = Vec::new();
+        let mut entries = mf.entries();
+        while let Some(entry) = entries.next().unwrap() {
+            paths.push(entry.path);
+        }
+        assert_eq!(paths, ["kern", "binary", "etc", "etc/motd"]);
+        let kern = mf.entry("kern").unwrap().unwrap();
+        assert_eq!(kern.contents, b"new kernel");
+        assert_eq!(kern.mode, Mode::Exec);
+    }
+
+    #[test]
+    fn test_align() {
+        let mut builder = MicroflopBuilder::new();
+        builder
+            .add("a", b"abc".to_vec())
+            .unwrap()
+            .add("page", b"aligned".to_vec())
+            .unwrap()
+            .set_align("page", 4096)
+            .unwrap();
+        assert!(builder.set_align("a", 12).is_err());
+        let bytes = builder.to_vec().unwrap();
+
+        let mf = Microflop::new(&bytes).unwrap();
+        let page = mf.entry("page").unwrap().unwrap();
+        assert_eq!(page.contents, b"aligned");
+        let offset = page.contents.as_ptr() as usize - bytes.as_ptr() as usize;
+        assert_eq!(offset, 4096);
+
+        // replacing a file keeps its alignment
+        builder
+            .replace("page", b"new".to_vec(), Mode::empty(), Compression::None)
+            .unwrap();
+        assert_eq!(builder.to_vec().unwrap().len(), 4096 + 8);
+
+        // and so does rewriting the archive, even once things move around
+        let mut reopened = MicroflopBuilder::from_archive(&mf).unwrap();
+        assert_eq!(reopened.to_vec().unwrap(), bytes);
+        reopened.add("more/files", vec![1; 5000]).unwrap();
+        let bytes = reopened.to_vec().unwrap();
+        let mf = Microflop::new(&bytes).unwrap();
+        let page = mf.entry("page").unwrap().unwrap();
+        assert_eq!(page.align, 4096);
+        let offset = page.contents.as_ptr() as usize - bytes.as_ptr() as usize;
+        assert_eq!(offset % 4096, 0);
+        assert_eq!(mf.entry("a").unwrap().unwrap().align, 8);
+    }
+}
@@ -0,0 +1,139 @@
+//! Decompressing entries without a heap, so shoo can do it
+
+use core::ops::Range;
+
+use miniz_oxide::inflate::core::{decompress, inflate_flags, DecompressorOxide};
+use miniz_oxide::inflate::TINFLStatus;
+
+use crate::{Error, Result};
+
+/// How far back deflate back references can reach, and so how much of its
+/// recent output an [`Inflater`] has to keep
+pub const WINDOW_SIZE: usize = 32 * 1024;
+
+/// State for decompressing entries. This is about 11KiB, so mind small
+/// stacks.
+pub struct Decompressor {
+    state: DecompressorOxide,
+}
+
+impl Decompressor {
+    pub fn new() -> Decompressor {
+        Decompressor {
+            state: DecompressorOxide::new(),
+        }
+    }
+
+    /// Inflates the raw deflate stream `input` into `out`, which must be
+    /// exactly as long as the decompressed data.
+    pub fn inflate(&mut self, input: &[u8], out: &mut [u8]) -> Result<()> {
+        self.state.init();
+        // the output is the whole file, so back references can look at it
+        // directly rather than going through a window
+        let (status, _, written) = decompress(
+            &mut self.state,
+            input,
+            out,
+            0,
+            inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF,
+        );
+        match status {
+            TINFLStatus::Done if written == out.len() => Ok(()),
+            _ => Err(Error::BadCompressedData),
+        }
+    }
+
+    /// Starts inflating the raw deflate stream `input`, which decompresses to
+    /// `size` bytes, a piece at a time. Only the last [`WINDOW_SIZE`] bytes of
+    /// output are kept, in `window`, so nothing needs room for all of it.
+    pub fn stream<'a>(
+        &'a mut self,
+        input: &'a [u8],
+        size: usize,
+        window: &'a mut [u8; WINDOW_SIZE],
+    ) -> Inflater<'a> {
+        self.state.init();
+        Inflater {
+            state: &mut self.state,
+            input,
+            window,
+            out_pos: 0,
+            unread: 0..0,
+            remaining: size,
+            finished: false,
+        }
+    }
+}
+
+impl Default for Decompressor {
+    fn default() -> Decompressor {
+        Decompressor::new()
+    }
+}
+
+/// A deflate stream being inflated a piece at a time, from
+/// [`Decompressor::stream`]
+pub struct Inflater<'a> {
+    state: &'a mut DecompressorOxide,
+    /// Compressed data that has not been consumed yet
+    input: &'a [u8],
+    /// The most recent output, wrapping around
+    window: &'a mut [u8; WINDOW_SIZE],
+    /// Where in the window the next output goes
+    out_pos: usize,
+    /// Output in the window that has not been read yet
+    unread: Range<usize>,
+    /// How much more output there should be
+    remaining: usize,
+    /// Whether the end of the deflate stream has been reached
+    finished: bool,
+}
+
+impl Inflater<'_> {
+    /// Reads the next part of the output into `out`, returning how much was
+    /// read. This is only zero at the end of the output, or if `out` is
+    /// empty.
+    pub fn read(&mut self, out: &mut [u8]) -> Result<usize> {
+        while self.unread.is_empty() {
+            if self.finished {
+                return match self.remaining {
+                    0 => Ok(0),
+                    _ => Err(Error::BadCompressedData),
+                };
+            }
+            self.fill()?;
+        }
+        let len = out.len().min(self.unread.len());
+        let start = self.unread.start;
+        out[..len].copy_from_slice(&self.window[start..start + len]);
+        self.unread.start += len;
+        Ok(len)
+    }
+
+    /// Inflates as much as fits between the write position and the end of
+    /// the window. Everything in the window has been read by now, so all of
+    /// it can be overwritten.
+    fn fill(&mut self) -> Result<()> {
+        // all of the input is here, so running out of it is an error
+        let (status, consumed, written) = decompress(
+            self.state,
+            self.input,
+            &mut self.window[..],
+            self.out_pos,
+            0,
+        );
+        self.input = &self.input[consumed..];
+        self.remaining = self
+            .remaining
+            .checked_sub(written)
+            .ok_or(Error::BadCompressedData)?;
+        self.unread = self.out_pos..self.out_pos + written;
+        self.out_pos = (self.out_pos + written) % WINDOW_SIZE;
+        match status {
+            TINFLStatus::Done => self.finished = true,
+            TINFLStatus::HasMoreOutput => (),
+            _ => return Err(Error::BadCompressedData),
+        }
+        Ok(())
+    }
+}
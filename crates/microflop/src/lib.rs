@@ -1,25 +1,71 @@
 //! The microscopic version of the `flop` filesystem. This is used for initrd.
 //!
-//! The format goes as follows:
+//! There are two versions of the format, told apart by their magic. Both have
+//! a table of header entries pointing into a blob of unstructured data, and
+//! [`Microflop`] reads either.
+//!
+//! Version 1 goes as follows:
 //! - [`Header`] (currently just magic)
 //! - Arbitrary number of [`HeaderEntry`] entries followed by a [`HeaderEntry`]
 //!   with [`HeaderEntryType`] of [`HeaderEntryType::End`]
 //! - A blob of unstructured data
+//!
+//! Version 2, described in [`v2`], adds a version number, paths of any
+//! length with `/` separated directories, and [`Mode`] bits. It is what the
+//! builder writes.
 #![cfg_attr(not(feature = "std"), no_std)]
+#![allow(non_upper_case_globals)]
 use core::convert::TryInto;
 use core::mem;
 
 use fallible_iterator::FallibleIterator;
 use static_assertions::const_assert;
 
+#[cfg(feature = "std")]
+mod builder;
+mod compress;
+pub mod v2;
+
+#[cfg(feature = "std")]
+pub use builder::MicroflopBuilder;
+pub use compress::{Decompressor, Inflater, WINDOW_SIZE};
+pub use ed25519_compact as ed25519;
+pub use v2::{Compression, EntryKind};
+
 pub const MAGIC: u64 = u64::from_le_bytes(*b"*mewing*");
 
 type Result<T> = core::result::Result<T, Error>;
 /// Errors that can happen while deserializing a microflop archive
 #[derive(Debug)]
 pub enum Error {
+    /// The archive is too short to have a header or has the wrong magic
     BadMagic,
+    /// The v2 header is truncated or its tables are out of bounds
+    BadHeader,
+    /// The archive is of a version this library does not know about
+    UnsupportedVersion(u16),
+    /// A header entry is truncated, malformed or points outside the data
     BadEntry,
+    /// The checksum of the header, header entries and string table does not
+    /// match
+    BadTableChecksum,
+    /// The checksum of the contents of the entry at this index does not
+    /// match
+    BadEntryChecksum(usize),
+    /// Compressed contents are corrupt or decompress to the wrong length
+    BadCompressedData,
+    /// The buffer to decompress into is too small
+    BufferTooSmall,
+    /// The file is compressed, so it has to be decompressed rather than
+    /// borrowed
+    Compressed,
+    /// The archive has no signature
+    Unsigned,
+    /// The signature does not match the archive or the key
+    BadSignature,
+    /// There is no entry at the path being changed in a
+    /// [`MicroflopBuilder`]
+    NotFound,
 }
 
 impl core::fmt::Display for Error {
@@ -71,62 +117,225 @@ pub struct HeaderEntry {
 
 const_assert!(mem::size_of::<HeaderEntry>() % 8 == 0);
 
+/// Size of a serialized [`HeaderEntry`]
+const ENTRY_SIZE: usize =
+    mem::size_of::<FileName>() + mem::size_of::<HeaderEntryType>() + 2 * mem::size_of::<Offset>();
+
+bitflags::bitflags!(
+    /// Permissions of an entry. Entries of version 1 archives have none.
+    pub struct Mode: u8 {
+        /// File is an executable
+        const Exec = 1 << 0;
+        /// File should not be modified
+        const ReadOnly = 1 << 1;
+    }
+);
+
+/// Version of the format of an archive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+/// An entry in an archive
+#[derive(Clone, Copy)]
+pub struct Entry<'a> {
+    /// Path of the entry, with `/` separating directories
+    pub path: &'a str,
+    pub kind: EntryKind,
+    pub mode: Mode,
+    /// Contents of the entry as stored. Empty for directories.
+    pub contents: &'a [u8],
+    /// Expected CRC32 of the contents as stored, if the archive has
+    /// checksums
+    pub checksum: Option<u32>,
+    /// How the contents are stored
+    pub compression: Compression,
+    /// Length of the contents once decompressed
+    pub size: usize,
+    /// Alignment of the contents from the start of the archive, which
+    /// rewriting the archive keeps
+    pub align: usize,
+}
+
+impl<'a> Entry<'a> {
+    /// Checks the contents against the checksum. Entries without a checksum
+    /// always pass.
+    pub fn checksum_ok(&self) -> bool {
+        match self.checksum {
+            Some(crc) => crc32fast::hash(self.contents) == crc,
+            None => true,
+        }
+    }
+
+    /// Gets the contents without decompressing them, if they are not
+    /// compressed
+    pub fn uncompressed(&self) -> Option<&'a [u8]> {
+        match self.compression {
+            Compression::None => Some(self.contents),
+            Compression::Deflate => None,
+        }
+    }
+
+    /// Decompresses the contents into the start of `out`, which must be at
+    /// least [`Entry::size`] bytes, returning the part that was written.
+    pub fn decompress_into<'b>(
+        &self,
+        decompressor: &mut Decompressor,
+        out: &'b mut [u8],
+    ) -> Result<&'b mut [u8]> {
+        let out = out.get_mut(..self.size).ok_or(Error::BufferTooSmall)?;
+        match self.compression {
+            Compression::None => out.copy_from_slice(self.contents),
+            Compression::Deflate => decompressor.inflate(self.contents, out)?,
+        }
+        Ok(out)
+    }
+
+    /// Starts decompressing the contents a piece at a time, keeping only the
+    /// last [`WINDOW_SIZE`] bytes in `window`, or gets `None` if they are not
+    /// compressed
+    pub fn inflater<'b>(
+        &self,
+        decompressor: &'b mut Decompressor,
+        window: &'b mut [u8; WINDOW_SIZE],
+    ) -> Option<Inflater<'b>>
+    where
+        'a: 'b,
+    {
+        match self.compression {
+            Compression::None => None,
+            Compression::Deflate => Some(decompressor.stream(self.contents, self.size, window)),
+        }
+    }
+}
+
+impl core::fmt::Debug for Entry<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Entry")
+            .field("path", &self.path)
+            .field("kind", &self.kind)
+            .field("mode", &self.mode)
+            .field("len", &self.contents.len())
+            .field("compression", &self.compression)
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+/// Checks that `path` is relative and has no empty, `.` or `..` components
+pub(crate) fn check_path(path: &str) -> Result<()> {
+    if path
+        .split('/')
+        .all(|c| !c.is_empty() && c != "." && c != "..")
+    {
+        Ok(())
+    } else {
+        Err(Error::BadEntry)
+    }
+}
+
+/// Gets the directory containing `path`. The root is `""`.
+pub(crate) fn parent(path: &str) -> &str {
+    path.rfind('/').map_or("", |i| &path[..i])
+}
+
+/// Gets the string out of a zero terminated name
+fn name_str(name: &[u8]) -> Result<&str> {
+    let endidx = name
+        .iter()
+        .position(|c| *c == b'\0')
+        .ok_or(Error::BadEntry)?;
+
+    core::str::from_utf8(&name[..endidx]).map_err(|_| Error::BadEntry)
+}
+
 /// A client to access a microflop filesystem
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct Microflop<'a> {
     /// contains the entire region of the file
     region: &'a [u8],
+    version: Version,
+    /// the header entries, not including the end entry of v1
+    table: &'a [u8],
+    /// string table of v2 archives
+    strtab: &'a [u8],
+    /// checksum of the header, header table and string table, if the archive
+    /// has checksums
+    table_checksum: Option<u32>,
+    /// offset of the first byte after the header table
+    data_start: usize,
+}
+
+/// Gets the contents between `begin` and `end`, checking that they are
+/// within `region` and after the header table.
+fn file_contents(region: &[u8], data_start: usize, begin: u32, end: u32) -> Result<&[u8]> {
+    let begin = begin as usize;
+    let end = end as usize;
+    if begin < data_start || begin > end {
+        return Err(Error::BadEntry);
+    }
+    region.get(begin..end).ok_or(Error::BadEntry)
+}
+
+/// An iterator over the entries in an archive, including directories
+pub struct IterEntries<'a> {
+    archive: Microflop<'a>,
+    /// offset of the next entry in the header table
+    pos: usize,
+}
+
+impl<'a> FallibleIterator for IterEntries<'a> {
+    type Item = Entry<'a>;
+    type Error = Error;
+
+    fn next(&mut self) -> Result<Option<Self::Item>> {
+        if self.pos >= self.archive.table.len() {
+            return Ok(None);
+        }
+        let entry = self.archive.entry_at(self.pos)?;
+        self.pos += self.archive.entry_size();
+        Ok(Some(entry))
+    }
 }
 
 /// An iterator over the files in an archive
 pub struct IterFiles<'a> {
-    region: &'a [u8],
-    start: &'a [u8],
+    entries: IterEntries<'a>,
 }
 
 impl<'a> FallibleIterator for IterFiles<'a> {
-    type Item = (FileName, &'a [u8]);
+    type Item = Entry<'a>;
     type Error = Error;
 
     fn next(&mut self) -> Result<Option<Self::Item>> {
-        let (entry, rest) = HeaderEntry::deserialize(self.start)?;
-        // println!("{:?}", entry);
-        Ok(match entry.tag {
-            HeaderEntryType::End => None,
-            HeaderEntryType::Entry => {
-                self.start = rest;
-                Some((
-                    entry.fname,
-                    &self.region[entry.begin.0 as usize..entry.end.0 as usize],
-                ))
+        while let Some(entry) = self.entries.next()? {
+            if entry.kind == EntryKind::File {
+                return Ok(Some(entry));
             }
-        })
+        }
+        Ok(None)
     }
 }
 
-/// An iterator over the entries in an archive (debugging use)
-pub struct IterEntries<'a> {
-    region: &'a [u8],
-    start: &'a [u8],
+/// An iterator over the entries directly inside a directory
+pub struct IterChildren<'a, 'b> {
+    entries: IterEntries<'a>,
+    dir: &'b str,
 }
 
-impl<'a> FallibleIterator for IterEntries<'a> {
-    type Item = (HeaderEntry, &'a [u8]);
+impl<'a, 'b> FallibleIterator for IterChildren<'a, 'b> {
+    type Item = Entry<'a>;
     type Error = Error;
 
     fn next(&mut self) -> Result<Option<Self::Item>> {
-        let (entry, rest) = HeaderEntry::deserialize(self.start)?;
-        // println!("{:?}", entry);
-        Ok(match entry.tag {
-            HeaderEntryType::End => None,
-            HeaderEntryType::Entry => {
-                self.start = rest;
-                Some((
-                    entry,
-                    &self.region[entry.begin.0 as usize..entry.end.0 as usize],
-                ))
+        while let Some(entry) = self.entries.next()? {
+            if entry.kind != EntryKind::Signature && parent(entry.path) == self.dir {
+                return Ok(Some(entry));
             }
-        })
+        }
+        Ok(None)
     }
 }
 
@@ -135,14 +344,7 @@ impl FileName {
 
     /// Gets the filename as a string. Fails if it is invalid.
     pub fn as_str(&self) -> Result<&str> {
-        let endidx = self
-            .0
-            .iter()
-            .position(|c| *c == b'\0')
-            .ok_or(Error::BadEntry)?;
-
-        let s = &self.0[..endidx];
-        core::str::from_utf8(s).map_err(|_| Error::BadEntry)
+        name_str(&self.0)
     }
 
     /// Makes a new FileName. Fails if you give it a string too long.
@@ -195,29 +397,320 @@ impl HeaderEntry {
 }
 
 impl<'a> Microflop<'a> {
-    pub fn new(region: &'a [u8]) -> Result<Microflop> {
-        let header = region[0..mem::size_of::<Header>()]
+    /// Opens an archive of either version, checking its header, that every
+    /// header entry is valid and points to data within `region`, and the
+    /// checksums if it has them.
+    pub fn new(region: &'a [u8]) -> Result<Microflop<'a>> {
+        let mf = Self::open(region)?;
+        mf.verify()?;
+        Ok(mf)
+    }
+
+    /// Opens an archive like [`Microflop::new`] but without checking the
+    /// checksums, which is faster.
+    pub fn new_unverified(region: &'a [u8]) -> Result<Microflop<'a>> {
+        let mf = Self::open(region)?;
+        let mut entries = mf.entries();
+        while entries.next()?.is_some() {}
+        Ok(mf)
+    }
+
+    /// Reads the header of an archive without looking at the entries
+    fn open(region: &'a [u8]) -> Result<Microflop<'a>> {
+        let header = region
+            .get(..mem::size_of::<u64>())
+            .ok_or(Error::BadMagic)?
             .try_into()
             .map_err(|_| Error::BadMagic)?;
-        let magic = u64::from_le_bytes(header);
-        if magic != MAGIC {
-            return Err(Error::BadMagic);
+        match u64::from_le_bytes(header) {
+            MAGIC => Self::open_v1(region),
+            v2::MAGIC => Self::open_v2(region),
+            _ => Err(Error::BadMagic),
         }
+    }
 
-        Ok(Microflop { region })
+    fn open_v1(region: &'a [u8]) -> Result<Microflop<'a>> {
+        // find the end of the header table so the entries can be checked
+        // against it
+        let table_start = mem::size_of::<Header>();
+        let mut rest = &region[table_start..];
+        loop {
+            let (entry, next) = HeaderEntry::deserialize(rest)?;
+            if let HeaderEntryType::End = entry.tag {
+                break;
+            }
+            rest = next;
+        }
+        let table_end = region.len() - rest.len();
+        Ok(Microflop {
+            region,
+            version: Version::V1,
+            table: &region[table_start..table_end],
+            strtab: &[],
+            table_checksum: None,
+            data_start: table_end + ENTRY_SIZE,
+        })
+    }
+
+    fn open_v2(region: &'a [u8]) -> Result<Microflop<'a>> {
+        let header = v2::Header::deserialize(region)?;
+        if header.version != v2::VERSION {
+            return Err(Error::UnsupportedVersion(header.version));
+        }
+
+        let (table_start, table_checksum) = if header.flags.contains(v2::Flags::Checksums) {
+            let checksum = v2::TableChecksum::deserialize(&region[v2::HEADER_SIZE..])?;
+            (v2::HEADER_SIZE + v2::CHECKSUM_SIZE, Some(checksum.crc))
+        } else {
+            (v2::HEADER_SIZE, None)
+        };
+        let table_end = (header.count as usize)
+            .checked_mul(v2::ENTRY_SIZE)
+            .and_then(|n| n.checked_add(table_start))
+            .ok_or(Error::BadHeader)?;
+        let table = region.get(table_start..table_end).ok_or(Error::BadHeader)?;
+
+        let strtab_start = header.strtab_offset as usize;
+        let strtab_end = strtab_start
+            .checked_add(header.strtab_len as usize)
+            .ok_or(Error::BadHeader)?;
+        if strtab_start < table_end {
+            return Err(Error::BadHeader);
+        }
+        let strtab = region
+            .get(strtab_start..strtab_end)
+            .ok_or(Error::BadHeader)?;
+
+        Ok(Microflop {
+            region,
+            version: Version::V2,
+            table,
+            strtab,
+            table_checksum,
+            data_start: table_end,
+        })
     }
 
+    /// Whether the archive has checksums to verify
+    pub fn has_checksums(&self) -> bool {
+        self.table_checksum.is_some()
+    }
+
+    /// Checks the checksum of the header, header entries and string table.
+    /// Archives without checksums always pass.
+    pub fn verify_table(&self) -> Result<()> {
+        let expected = match self.table_checksum {
+            Some(crc) => crc,
+            None => return Ok(()),
+        };
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.region[..v2::HEADER_SIZE]);
+        hasher.update(self.table);
+        hasher.update(self.strtab);
+        if hasher.finalize() == expected {
+            Ok(())
+        } else {
+            Err(Error::BadTableChecksum)
+        }
+    }
+
+    /// Checks that every entry is valid and that the checksums match. The
+    /// header table is checked first so that damage to it is reported as
+    /// such rather than as a bad entry.
+    pub fn verify(&self) -> Result<()> {
+        self.verify_table()?;
+        let mut entries = self.entries().enumerate();
+        while let Some((idx, entry)) = entries.next()? {
+            if !entry.checksum_ok() {
+                return Err(Error::BadEntryChecksum(idx));
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets the whole archive
+    pub fn region(&self) -> &'a [u8] {
+        self.region
+    }
+
+    /// Gets the offset where the tables end and the contents of the entries
+    /// can start
+    pub fn data_start(&self) -> usize {
+        self.data_start
+    }
+
+    /// Gets the version of the format of the archive
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    fn entry_size(&self) -> usize {
+        match self.version {
+            Version::V1 => ENTRY_SIZE,
+            Version::V2 => v2::ENTRY_SIZE,
+        }
+    }
+
+    /// Decodes the entry at offset `pos` in the header table
+    fn entry_at(&self, pos: usize) -> Result<Entry<'a>> {
+        let raw = &self.table[pos..];
+        match self.version {
+            Version::V1 => {
+                let (entry, _) = HeaderEntry::deserialize(raw)?;
+                let contents =
+                    file_contents(self.region, self.data_start, entry.begin.0, entry.end.0)?;
+                Ok(Entry {
+                    path: name_str(&raw[..mem::size_of::<FileName>()])?,
+                    kind: EntryKind::File,
+                    mode: Mode::empty(),
+                    contents,
+                    checksum: None,
+                    compression: Compression::None,
+                    size: contents.len(),
+                    align: 1,
+                })
+            }
+            Version::V2 => {
+                let entry = v2::HeaderEntry::deserialize(raw)?;
+                let name_start = entry.name_offset as usize;
+                let name_end = name_start
+                    .checked_add(entry.name_len as usize)
+                    .ok_or(Error::BadEntry)?;
+                let name = self
+                    .strtab
+                    .get(name_start..name_end)
+                    .ok_or(Error::BadEntry)?;
+                let path = core::str::from_utf8(name).map_err(|_| Error::BadEntry)?;
+                if entry.kind == EntryKind::Signature {
+                    return self.signature_entry(pos, &entry, path);
+                }
+                check_path(path)?;
+
+                let contents = match entry.kind {
+                    EntryKind::File => {
+                        file_contents(self.region, self.data_start, entry.begin, entry.end)?
+                    }
+                    EntryKind::Dir if entry.begin == entry.end => &[],
+                    _ => return Err(Error::BadEntry),
+                };
+                let size = match entry.compression {
+                    Compression::None if entry.size == 0 => contents.len(),
+                    Compression::Deflate if entry.kind == EntryKind::File => entry.size as usize,
+                    _ => return Err(Error::BadEntry),
+                };
+                let checksum = match self.table_checksum {
+                    Some(_) => Some(entry.checksum),
+                    None if entry.checksum == 0 => None,
+                    None => return Err(Error::BadEntry),
+                };
+                let align = match entry.align_log2 {
+                    0 => v2::DATA_ALIGN,
+                    n if entry.kind == EntryKind::File && entry.begin % (1 << n) == 0 => 1 << n,
+                    _ => return Err(Error::BadEntry),
+                };
+                Ok(Entry {
+                    path,
+                    kind: entry.kind,
+                    mode: entry.mode,
+                    contents,
+                    checksum,
+                    compression: entry.compression,
+                    size,
+                    align,
+                })
+            }
+        }
+    }
+
+    /// Decodes the signature entry `entry` at offset `pos` in the header
+    /// table, checking that it is where it has to be
+    fn signature_entry(
+        &self,
+        pos: usize,
+        entry: &v2::HeaderEntry,
+        path: &'a str,
+    ) -> Result<Entry<'a>> {
+        let contents = file_contents(self.region, self.data_start, entry.begin, entry.end)?;
+        let valid = pos + v2::ENTRY_SIZE == self.table.len()
+            && path.is_empty()
+            && entry.mode.is_empty()
+            && entry.checksum == 0
+            && entry.compression == Compression::None
+            && entry.align_log2 == 0
+            && entry.size == 0
+            && contents.len() == v2::SIGNATURE_SIZE
+            && entry.end as usize == self.region.len();
+        if !valid {
+            return Err(Error::BadEntry);
+        }
+        Ok(Entry {
+            path,
+            kind: EntryKind::Signature,
+            mode: Mode::empty(),
+            contents,
+            checksum: None,
+            compression: Compression::None,
+            size: contents.len(),
+            align: v2::DATA_ALIGN,
+        })
+    }
+
+    /// Gets the signature entry, if the archive is signed
+    pub fn signature(&self) -> Result<Option<Entry<'a>>> {
+        if self.version != Version::V2 || self.table.is_empty() {
+            return Ok(None);
+        }
+        let last = self.entry_at(self.table.len() - v2::ENTRY_SIZE)?;
+        Ok(Some(last).filter(|e| e.kind == EntryKind::Signature))
+    }
+
+    /// Checks that the archive is signed by `key`. The signature covers
+    /// everything in the archive before it.
+    pub fn verify_signature(&self, key: &ed25519::PublicKey) -> Result<()> {
+        let signature = self.signature()?.ok_or(Error::Unsigned)?;
+        let signature =
+            ed25519::Signature::from_slice(signature.contents).map_err(|_| Error::BadSignature)?;
+        let message = &self.region[..self.region.len() - v2::SIGNATURE_SIZE];
+        key.verify(message, &signature)
+            .map_err(|_| Error::BadSignature)
+    }
+
+    /// Iterates over the files in the archive
     pub fn files(&self) -> IterFiles<'a> {
         IterFiles {
-            region: self.region,
-            start: &self.region[mem::size_of::<Header>()..],
+            entries: self.entries(),
         }
     }
 
+    /// Iterates over all the entries in the archive, including directories
     pub fn entries(&self) -> IterEntries<'a> {
         IterEntries {
-            region: self.region,
-            start: &self.region[mem::size_of::<Header>()..],
+            archive: *self,
+            pos: 0,
+        }
+    }
+
+    /// Iterates over the entries directly inside the directory `dir`. The
+    /// root directory is `""`.
+    pub fn children<'b>(&self, dir: &'b str) -> IterChildren<'a, 'b> {
+        IterChildren {
+            entries: self.entries(),
+            dir,
+        }
+    }
+
+    /// Gets the entry at `path`, if there is one.
+    pub fn entry(&self, path: &str) -> Result<Option<Entry<'a>>> {
+        self.entries().find(|e| Ok(e.path == path))
+    }
+
+    /// Gets the contents of the file at `path`, if there is one. Fails if
+    /// it is compressed; use [`Microflop::entry`] and
+    /// [`Entry::decompress_into`] for those.
+    pub fn get(&self, path: &str) -> Result<Option<&'a [u8]>> {
+        match self.files().find(|e| Ok(e.path == path))? {
+            Some(entry) => entry.uncompressed().ok_or(Error::Compressed).map(Some),
+            None => Ok(None),
         }
     }
 }
@@ -226,6 +719,9 @@ impl HeaderEntry {
     /// Deserializes a header entry, yielding a [`HeaderEntry`] and a slice
     /// of the remaining bytes.
     fn deserialize(slice: &[u8]) -> Result<(HeaderEntry, &[u8])> {
+        if slice.len() < ENTRY_SIZE {
+            return Err(Error::BadEntry);
+        }
         let (fname, rest) = slice.split_at(mem::size_of::<FileName>());
         let (tag, rest) = rest.split_at(mem::size_of::<HeaderEntryType>());
         let (begin, rest) = rest.split_at(mem::size_of::<Offset>());
@@ -246,3 +742,87 @@ impl HeaderEntry {
         ))
     }
 }
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn entry(out: &mut Vec<u8>, name: &str, tag: HeaderEntryType, begin: u32, end: u32) {
+        out.extend_from_slice(&FileName::new(name).unwrap().0);
+        out.push(tag as u8);
+        out.extend_from_slice(&begin.to_le_bytes());
+        out.extend_from_slice(&end.to_le_bytes());
+    }
+
+    /// Archive with `kern` = "abc" and `init` = "defgh"
+    fn archive() -> Vec<u8> {
+        let data_start = (mem::size_of::<Header>() + 3 * ENTRY_SIZE) as u32;
+        let mut out = MAGIC.to_le_bytes().to_vec();
+        entry(
+            &mut out,
+            "kern",
+            HeaderEntryType::Entry,
+            data_start,
+            data_start + 3,
+        );
+        entry(
+            &mut out,
+            "init",
+            HeaderEntryType::Entry,
+            data_start + 8,
+            data_start + 13,
+        );
+        entry(&mut out, "", HeaderEntryType::End, 0, 0);
+        out.extend_from_slice(b"abc\0\0\0\0\0defgh");
+        out
+    }
+
+    #[test]
+    fn test_get() {
+        let bytes = archive();
+        let mf = Microflop::new(&bytes).unwrap();
+        assert_eq!(mf.get("kern").unwrap(), Some(&b"abc"[..]));
+        assert_eq!(mf.get("init").unwrap(), Some(&b"defgh"[..]));
+        assert_eq!(mf.get("nope").unwrap(), None);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let bytes = archive();
+        for len in 0..bytes.len() {
+            assert!(Microflop::new(&bytes[..len]).is_err(), "len {}", len);
+        }
+    }
+
+    #[test]
+    fn test_bad_range() {
+        let header_end = mem::size_of::<Header>() as u32;
+        let mut bytes = MAGIC.to_le_bytes().to_vec();
+        // points into the header table
+        entry(
+            &mut bytes,
+            "kern",
+            HeaderEntryType::Entry,
+            header_end,
+            header_end + 4,
+        );
+        entry(&mut bytes, "", HeaderEntryType::End, 0, 0);
+        bytes.extend_from_slice(&[0; 8]);
+        assert!(matches!(Microflop::new(&bytes), Err(Error::BadEntry)));
+    }
+
+    #[test]
+    fn test_future_version() {
+        let mut bytes = v2::MAGIC.to_le_bytes().to_vec();
+        // version 3, no flags, no entries, empty string table
+        bytes.extend_from_slice(&3u16.to_le_bytes());
+        bytes.extend_from_slice(&[0; 14]);
+        assert!(matches!(
+            Microflop::new(&bytes),
+            Err(Error::UnsupportedVersion(3))
+        ));
+    }
+}
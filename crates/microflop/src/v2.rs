@@ -0,0 +1,222 @@
+//! On-disk structures of version 2 of the format.
+//!
+//! All integers are little endian. The layout goes as follows:
+//! - [`Header`]
+//! - If [`Flags::Checksums`] is set, a [`TableChecksum`]
+//! - [`Header::count`] of [`HeaderEntry`]
+//! - A string table of the entries' paths, which are not nul terminated
+//! - A blob of unstructured data, with each file aligned to 8 bytes, or more
+//!   if its [`HeaderEntry::align_log2`] says so. Files may be compressed, see
+//!   [`Compression`].
+//! - Optionally, an Ed25519 signature of everything before it, pointed to by
+//!   a [`EntryKind::Signature`] entry that is the last header entry
+use core::convert::TryInto;
+
+use crate::{Error, Mode, Result};
+
+pub const MAGIC: u64 = u64::from_le_bytes(*b"*meowed*");
+
+/// The version written by this library. Archives with a newer version are
+/// rejected.
+pub const VERSION: u16 = 2;
+
+/// Size of a serialized [`Header`]
+pub const HEADER_SIZE: usize = 24;
+
+/// Size of a serialized [`TableChecksum`]
+pub const CHECKSUM_SIZE: usize = 8;
+
+/// Size of a serialized [`HeaderEntry`]
+pub const ENTRY_SIZE: usize = 32;
+
+/// Alignment of file contents from the start of the archive, unless their
+/// entry asks for more
+pub const DATA_ALIGN: usize = 8;
+
+/// Largest [`HeaderEntry::align_log2`], since offsets are 32 bits
+pub const MAX_ALIGN_LOG2: u8 = 31;
+
+/// Size of the contents of a [`EntryKind::Signature`] entry
+pub const SIGNATURE_SIZE: usize = 64;
+
+bitflags::bitflags!(
+    /// Flags changing how the archive is read
+    pub struct Flags: u16 {
+        /// The header is followed by a [`TableChecksum`] and the entries
+        /// have checksums of their contents
+        const Checksums = 1 << 0;
+    }
+);
+
+/// Appears at the start of version 2 archives
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// Magic bytes, `*meowed*`
+    pub magic: u64,
+    /// Format version, [`VERSION`]
+    pub version: u16,
+    /// Flags changing how the archive is read. Unknown flags are rejected.
+    pub flags: Flags,
+    /// Number of [`HeaderEntry`] following the header
+    pub count: u32,
+    /// Offset of the string table in the archive
+    pub strtab_offset: u32,
+    /// Length of the string table
+    pub strtab_len: u32,
+}
+
+/// CRC32 of the [`Header`], the header entries and the string table, in that
+/// order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TableChecksum {
+    pub crc: u32,
+}
+
+impl TableChecksum {
+    /// Deserializes a checksum from the start of `slice`. The reserved bytes
+    /// must be zero.
+    pub fn deserialize(slice: &[u8]) -> Result<TableChecksum> {
+        let b = slice.get(..CHECKSUM_SIZE).ok_or(Error::BadHeader)?;
+        if u32_at(b, 4) != 0 {
+            return Err(Error::BadHeader);
+        }
+        Ok(TableChecksum { crc: u32_at(b, 0) })
+    }
+
+    /// Serializes the [`TableChecksum`] to an output stream
+    #[cfg(feature = "std")]
+    pub fn serialize<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
+        w.write_all(&self.crc.to_le_bytes())?;
+        // reserved
+        w.write_all(&[0u8; CHECKSUM_SIZE - 4])?;
+        Ok(())
+    }
+}
+
+typesafe_ints::int_enum_only!(
+    /// What an entry is
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum EntryKind(u8) {
+        File = 1,
+        Dir = 2,
+        /// Signature of the archive. It has an empty path, no checksum, and
+        /// must be the last entry, with its contents at the end of the
+        /// archive.
+        Signature = 3,
+    }
+);
+
+typesafe_ints::int_enum_only!(
+    /// How the contents of an entry are stored
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Compression(u8) {
+        None = 0,
+        /// Raw deflate stream, without a zlib header
+        Deflate = 1,
+    }
+);
+
+/// Entry in the header table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeaderEntry {
+    /// Offset of the path in the string table
+    pub name_offset: u32,
+    /// Length of the path in bytes
+    pub name_len: u32,
+    /// Offset of the start of the contents in the archive
+    pub begin: u32,
+    /// Offset of the end of the contents in the archive. Directories have
+    /// `begin == end`.
+    pub end: u32,
+    pub kind: EntryKind,
+    pub mode: Mode,
+    /// CRC32 of the contents as stored, so before decompressing, if the
+    /// archive has [`Flags::Checksums`], otherwise zero
+    pub checksum: u32,
+    pub compression: Compression,
+    /// Base two logarithm of the alignment the contents were placed with, if
+    /// it is more than [`DATA_ALIGN`], otherwise zero. This lets the archive
+    /// be rewritten without losing it.
+    pub align_log2: u8,
+    /// Length of the contents after decompressing them if they are
+    /// compressed, otherwise zero
+    pub size: u32,
+}
+
+fn u16_at(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes(b[off..off + 2].try_into().unwrap())
+}
+
+fn u32_at(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+impl Header {
+    /// Deserializes a header from the start of `slice`
+    pub fn deserialize(slice: &[u8]) -> Result<Header> {
+        let b = slice.get(..HEADER_SIZE).ok_or(Error::BadHeader)?;
+        let magic = u64::from_le_bytes(b[..8].try_into().unwrap());
+        if magic != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        Ok(Header {
+            magic,
+            version: u16_at(b, 8),
+            flags: Flags::from_bits(u16_at(b, 10)).ok_or(Error::BadHeader)?,
+            count: u32_at(b, 12),
+            strtab_offset: u32_at(b, 16),
+            strtab_len: u32_at(b, 20),
+        })
+    }
+
+    /// Serializes the [`Header`] to an output stream
+    #[cfg(feature = "std")]
+    pub fn serialize<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
+        w.write_all(&self.magic.to_le_bytes())?;
+        w.write_all(&self.version.to_le_bytes())?;
+        w.write_all(&self.flags.bits().to_le_bytes())?;
+        w.write_all(&self.count.to_le_bytes())?;
+        w.write_all(&self.strtab_offset.to_le_bytes())?;
+        w.write_all(&self.strtab_len.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+impl HeaderEntry {
+    /// Deserializes an entry from the start of `slice`. The reserved bytes
+    /// must be zero.
+    pub fn deserialize(slice: &[u8]) -> Result<HeaderEntry> {
+        let b = slice.get(..ENTRY_SIZE).ok_or(Error::BadEntry)?;
+        if b[23] > MAX_ALIGN_LOG2 || b[28..].iter().any(|&r| r != 0) {
+            return Err(Error::BadEntry);
+        }
+        Ok(HeaderEntry {
+            name_offset: u32_at(b, 0),
+            name_len: u32_at(b, 4),
+            begin: u32_at(b, 8),
+            end: u32_at(b, 12),
+            kind: b[16].try_into().map_err(|_| Error::BadEntry)?,
+            mode: Mode::from_bits(b[17]).ok_or(Error::BadEntry)?,
+            checksum: u32_at(b, 18),
+            compression: b[22].try_into().map_err(|_| Error::BadEntry)?,
+            align_log2: b[23],
+            size: u32_at(b, 24),
+        })
+    }
+
+    /// Serializes the [`HeaderEntry`] to an output stream
+    #[cfg(feature = "std")]
+    pub fn serialize<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
+        w.write_all(&self.name_offset.to_le_bytes())?;
+        w.write_all(&self.name_len.to_le_bytes())?;
+        w.write_all(&self.begin.to_le_bytes())?;
+        w.write_all(&self.end.to_le_bytes())?;
+        w.write_all(&[self.kind as u8, self.mode.bits()])?;
+        w.write_all(&self.checksum.to_le_bytes())?;
+        w.write_all(&[self.compression as u8, self.align_log2])?;
+        w.write_all(&self.size.to_le_bytes())?;
+        // reserved
+        w.write_all(&[0u8; ENTRY_SIZE - 28])?;
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+//! Arguments, environment and auxiliary vector given to the program on its
+//! initial stack
+
+use core::ptr;
+use core::slice;
+use core::str;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use mu_shared::auxv::{AT_MU_BOOT_INFO, AT_NULL};
+use mu_shared::BootInfo;
+
+/// The initial stack pointer, which points to argc
+static INITIAL_SP: AtomicPtr<usize> = AtomicPtr::new(ptr::null_mut());
+
+/// Remembers where the initial stack is. Called from `_start` before
+/// anything else runs.
+pub(crate) unsafe fn init(sp: *mut usize) {
+    INITIAL_SP.store(sp, Ordering::Relaxed);
+}
+
+/// Gets the null terminated list of pointers starting at `list`, and a
+/// pointer to just after its terminator
+unsafe fn null_terminated(list: *const usize) -> (&'static [usize], *const usize) {
+    let mut len = 0;
+    while *list.add(len) != 0 {
+        len += 1;
+    }
+    (slice::from_raw_parts(list, len), list.add(len + 1))
+}
+
+/// Pointers to the arguments, environment and auxiliary vector
+fn vectors() -> (&'static [usize], &'static [usize], *const usize) {
+    let sp = INITIAL_SP.load(Ordering::Relaxed);
+    assert!(!sp.is_null(), "mu::env used before _start");
+    // safety: the kernel built the stack in the layout we expect, and nobody
+    // writes over it
+    unsafe {
+        let (argv, envp) = null_terminated(sp.add(1));
+        let (envp, auxv) = null_terminated(envp);
+        (argv, envp, auxv)
+    }
+}
+
+/// Gets the nul terminated string at `addr`, if it is UTF-8
+unsafe fn c_str(addr: usize) -> Option<&'static str> {
+    let p = addr as *const u8;
+    let mut len = 0;
+    while *p.add(len) != 0 {
+        len += 1;
+    }
+    str::from_utf8(slice::from_raw_parts(p, len)).ok()
+}
+
+/// Iterates over the program's arguments, including the program name.
+/// Arguments that are not UTF-8 are skipped.
+pub fn args() -> impl Iterator<Item = &'static str> {
+    let (argv, _, _) = vectors();
+    argv.iter().filter_map(|&a| unsafe { c_str(a) })
+}
+
+/// Iterates over the environment variables as `(key, value)`. Variables that
+/// are not UTF-8 or have no `=` are skipped.
+pub fn vars() -> impl Iterator<Item = (&'static str, &'static str)> {
+    let (_, envp, _) = vectors();
+    envp.iter()
+        .filter_map(|&a| unsafe { c_str(a) })
+        .filter_map(|var| {
+            let eq = var.find('=')?;
+            Some((&var[..eq], &var[eq + 1..]))
+        })
+}
+
+/// Gets the value of an environment variable
+pub fn var(key: &str) -> Option<&'static str> {
+    vars().find(|&(k, _)| k == key).map(|(_, v)| v)
+}
+
+/// Gets the value of the auxiliary vector entry of type `ty`, one of the
+/// constants in [`mu_shared::auxv`]
+pub fn aux(ty: usize) -> Option<usize> {
+    let (_, _, mut auxv) = vectors();
+    // safety: the vector is terminated by AT_NULL
+    unsafe {
+        loop {
+            match *auxv {
+                AT_NULL => return None,
+                t if t == ty => return Some(*auxv.add(1)),
+                _ => auxv = auxv.add(2),
+            }
+        }
+    }
+}
+
+/// Gets the system information the kernel gives to init
+pub fn boot_info() -> Option<&'static BootInfo> {
+    // safety: the pointer points into the initial stack
+    aux(AT_MU_BOOT_INFO).map(|p| unsafe { &*(p as *const BootInfo) })
+}
@@ -1,31 +1,67 @@
 //! Base functions for building stuff on top of mu
 #![no_std]
 #![feature(asm)]
+#![feature(global_asm)]
 #![feature(lang_items)]
 #![feature(panic_info_message)]
 
+pub mod env;
 pub mod panic;
 pub mod print;
 pub mod syscall;
+pub mod tls;
+
+use core::fmt;
 
 extern "C" {
     fn main(argc: isize, argv: *const *const u8) -> isize;
 }
 
+// the kernel starts us with sp pointing at argc, followed by argv, envp and
+// the auxiliary vector. hand that to rust.
+global_asm!(
+    "
+    .section .text._start, "ax", @progbits
+    .globl _start
+_start:
+    mv a0, sp
+    call mu_start
+1:  j 1b
+"
+);
+
 #[no_mangle]
-extern "C" fn _start() -> ! {
-    let argv = [];
-    unsafe { main(0, argv.as_ptr()) };
-    unreachable!()
+unsafe extern "C" fn mu_start(sp: *mut usize) -> ! {
+    env::init(sp);
+    let argc = *sp as isize;
+    let argv = sp.add(1) as *const *const u8;
+    syscall::exit(main(argc, argv))
+}
+
+/// Something `main` can return, which becomes the exit code of the process
+pub trait Termination {
+    fn report(self) -> isize;
 }
 
-// convince rustc to not ICE us :D
-trait Termination {}
+impl Termination for () {
+    fn report(self) -> isize {
+        0
+    }
+}
 
-impl Termination for () {}
+impl<E: fmt::Debug> Termination for Result<(), E> {
+    fn report(self) -> isize {
+        match self {
+            Ok(()) => 0,
+            Err(e) => {
+                println!("Error: {:?}", e);
+                1
+            }
+        }
+    }
+}
 
 #[lang = "start"]
-fn lang_start<T: Termination>(main: fn() -> T, argc: isize, argv: *const *const u8) -> isize {
-    main();
-    2
+fn lang_start<T: Termination>(main: fn() -> T, _argc: isize, _argv: *const *const u8) -> isize {
+    main().report()
 }
@@ -1,16 +1,128 @@
 //! system calls
 
-use mu_shared::SyscallNum;
+use core::convert::TryFrom;
+use core::time::Duration;
 
-unsafe fn syscall2(num: SyscallNum, a1: usize, a2: usize) -> usize {
-    let ret: usize;
+use mu_shared::{KernErr, KernResult, ProcessHandle, SyscallNum, MAX_SPAWN_ARGS};
+
+/// Turns what the kernel returns in a0 and a1 into a result: a0 says whether
+/// the call worked, and a1 has the result or the error
+fn result(ok: usize, value: usize) -> KernResult<usize> {
+    if ok != 0 {
+        Ok(value)
+    } else {
+        Err(KernErr::try_from(value).expect("kernel returned an unknown error"))
+    }
+}
+
+unsafe fn syscall0(num: SyscallNum) -> KernResult<usize> {
+    let (ok, value): (usize, usize);
+    asm!("ecall",
+        inout("a0") num as usize => ok,
+        lateout("a1") value);
+    result(ok, value)
+}
+
+unsafe fn syscall1(num: SyscallNum, a1: usize) -> KernResult<usize> {
+    let (ok, value): (usize, usize);
+    asm!("ecall",
+        inout("a0") num as usize => ok,
+        inout("a1") a1 => value);
+    result(ok, value)
+}
+
+unsafe fn syscall2(num: SyscallNum, a1: usize, a2: usize) -> KernResult<usize> {
+    let (ok, value): (usize, usize);
     asm!("ecall",
-        inout("a0") num as usize => ret,
-        in("a1") a1,
+        inout("a0") num as usize => ok,
+        inout("a1") a1 => value,
         in("a2") a2);
-    ret
+    result(ok, value)
+}
+
+unsafe fn syscall4(
+    num: SyscallNum,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+    a4: usize,
+) -> KernResult<usize> {
+    let (ok, value): (usize, usize);
+    asm!("ecall",
+        inout("a0") num as usize => ok,
+        inout("a1") a1 => value,
+        in("a2") a2,
+        in("a3") a3,
+        in("a4") a4);
+    result(ok, value)
 }
 
 pub fn log(msg: &str) {
-    unsafe { syscall2(SyscallNum::LogMessage, msg.len(), msg.as_ptr() as usize) };
+    let _ = unsafe { syscall2(SyscallNum::LogMessage, msg.len(), msg.as_ptr() as usize) };
+}
+
+/// Asks the kernel to print the page table of the calling process to the
+/// serial console.
+pub fn debug_dump_page_table() {
+    let _ = unsafe { syscall0(SyscallNum::DebugDumpPageTable) };
+}
+
+/// Starts the program `name` from the initrd, with the arguments `args` after
+/// its name.
+///
+/// Arguments cannot contain nul bytes, and all together, with a byte each for
+/// a terminator, cannot be longer than [`MAX_SPAWN_ARGS`].
+pub fn spawn(name: &str, args: &[&str]) -> KernResult<ProcessHandle> {
+    let mut buf = [0u8; MAX_SPAWN_ARGS];
+    let mut len = 0;
+    for arg in args {
+        if arg.as_bytes().contains(&0) {
+            return Err(KernErr::InvalidArgument);
+        }
+        let dest = buf
+            .get_mut(len..len + arg.len() + 1)
+            .ok_or(KernErr::TooLong)?;
+        dest[..arg.len()].copy_from_slice(arg.as_bytes());
+        dest[arg.len()] = 0;
+        len += arg.len() + 1;
+    }
+
+    unsafe {
+        syscall4(
+            SyscallNum::ProcessSpawn,
+            name.len(),
+            name.as_ptr() as usize,
+            len,
+            buf.as_ptr() as usize,
+        )
+    }
+}
+
+/// Exits the process. Whatever waits for it gets `code`.
+pub fn exit(code: isize) -> ! {
+    let _ = unsafe { syscall1(SyscallNum::ProcessExit, code as usize) };
+    unreachable!("the kernel returned from exit")
+}
+
+/// Waits for `process` to exit and gets its exit code
+pub fn wait(process: ProcessHandle) -> KernResult<isize> {
+    unsafe { syscall1(SyscallNum::ProcessWait, process) }.map(|code| code as isize)
+}
+
+/// Gets the time since boot, which never goes backwards
+pub fn clock_get_monotonic() -> Duration {
+    let nanos =
+        unsafe { syscall0(SyscallNum::ClockGetMonotonic) }.expect("ClockGetMonotonic cannot fail");
+    Duration::from_nanos(nanos as u64)
+}
+
+/// Blocks until [`clock_get_monotonic`] reaches `deadline`
+pub fn sleep_until(deadline: Duration) {
+    let nanos = deadline.as_nanos().min(u64::MAX as u128) as usize;
+    let _ = unsafe { syscall1(SyscallNum::SleepUntil, nanos) };
+}
+
+/// Blocks for at least `duration`
+pub fn sleep(duration: Duration) {
+    sleep_until(clock_get_monotonic() + duration);
 }
@@ -0,0 +1,46 @@
+//! Thread local storage.
+//!
+//! Programs can use `#[thread_local]` statics with
+//! `#![feature(thread_local)]`. The main thread's TLS block is set up before
+//! the program starts; new threads need a block from [`init_block`].
+
+use mu_shared::auxv::AT_MU_TLS;
+use mu_shared::TlsInfo;
+
+/// The TLS layout of this program, if it uses TLS
+pub fn info() -> Option<&'static TlsInfo> {
+    // safety: the pointer points into the initial stack
+    crate::env::aux(AT_MU_TLS).map(|p| unsafe { &*(p as *const TlsInfo) })
+}
+
+/// Bytes of memory [`init_block`] needs for a new thread's block, allowing
+/// for alignment
+pub fn block_size() -> usize {
+    info().map_or(0, |tls| tls.mem_size + tls.align - 1)
+}
+
+/// Initializes a TLS block for a new thread within `mem`, which must be at
+/// least [`block_size`] bytes, and returns the thread pointer for the thread.
+/// Returns 0 if the program does not use TLS.
+pub fn init_block(mem: &mut [u8]) -> usize {
+    let tls = match info() {
+        Some(tls) => tls,
+        None => return 0,
+    };
+    assert!(mem.len() >= block_size(), "TLS block too small");
+    let start = mem.as_ptr() as usize;
+    let offs = ((start + tls.align - 1) & !(tls.align - 1)) - start;
+    let block = &mut mem[offs..offs + tls.mem_size];
+    // safety: the template is mapped for the life of the program
+    let template = unsafe { core::slice::from_raw_parts(tls.template as *const u8, tls.file_size) };
+    block[..tls.file_size].copy_from_slice(template);
+    block[tls.file_size..].fill(0);
+    block.as_ptr() as usize
+}
+
+/// Gets the current thread pointer, which points to the thread's TLS block
+pub fn thread_pointer() -> usize {
+    let tp: usize;
+    unsafe { asm!("mv {}, tp", out(reg) tp) };
+    tp
+}
@@ -0,0 +1,22 @@
+//! Auxiliary vector entry types, passed to new processes on the initial stack
+//! after the environment. These are the System V numbers where they exist.
+
+/// End of the vector
+pub const AT_NULL: usize = 0;
+/// Address of the program headers
+pub const AT_PHDR: usize = 3;
+/// Size of a program header
+pub const AT_PHENT: usize = 4;
+/// Number of program headers
+pub const AT_PHNUM: usize = 5;
+/// Page size
+pub const AT_PAGESZ: usize = 6;
+/// Entry point of the program
+pub const AT_ENTRY: usize = 9;
+/// Address of 16 random bytes
+pub const AT_RANDOM: usize = 25;
+
+/// Address of a [`crate::BootInfo`]. Only given to init.
+pub const AT_MU_BOOT_INFO: usize = 0x1000;
+/// Address of a [`crate::TlsInfo`], if the program uses thread local storage
+pub const AT_MU_TLS: usize = 0x1001;
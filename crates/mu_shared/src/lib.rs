@@ -1,6 +1,8 @@
 #![no_std]
 //! Shared constants and functions between userspace and kernel
 
+pub mod auxv;
+
 pub type KernResult<T> = Result<T, KernErr>;
 
 typesafe_ints::int_enum_only! (
@@ -9,6 +11,33 @@ typesafe_ints::int_enum_only! (
 pub enum SyscallNum(usize) {
     /// `LogMessage(len: usize, message: *const u8)`
     LogMessage = 0,
+    /// `DebugDumpPageTable()`
+    DebugDumpPageTable = 1,
+    /// `ProcessSpawn(name_len: usize, name: *const u8, args_len: usize,
+    /// args: *const u8) -> ProcessHandle`
+    ///
+    /// Starts the program at the path `name` in the initrd. Its arguments
+    /// after the program name are in `args`, each terminated by a nul byte.
+    ProcessSpawn = 2,
+    /// `ProcessExit(code: isize) -> !`
+    ///
+    /// Exits the calling process. Whatever waits for it gets `code`. A
+    /// process the kernel kills for faulting exits as if it had made this
+    /// call with [`EXIT_PAGE_FAULT`] or [`EXIT_EXCEPTION`].
+    ProcessExit = 3,
+    /// `ProcessWait(process: ProcessHandle) -> isize`
+    ///
+    /// Blocks until the process exits, and gets its exit code. A process can
+    /// only be waited for once after it exits.
+    ProcessWait = 4,
+    /// `ClockGetMonotonic() -> u64`
+    ///
+    /// Gets the time since boot in nanoseconds, which never goes backwards.
+    ClockGetMonotonic = 5,
+    /// `SleepUntil(deadline: u64)`
+    ///
+    /// Blocks until the monotonic clock reaches `deadline`, in nanoseconds.
+    SleepUntil = 6,
 }
 );
 
@@ -17,11 +46,63 @@ typesafe_ints::int_enum_only! (
 #[derive(Debug)]
 pub enum KernErr(usize) {
     BadUtf8 = 0,
+    /// There is no such file or process
+    NotFound = 1,
+    /// The kernel ran out of memory, or a table of processes or threads is
+    /// full
+    NoMemory = 2,
+    /// The file is not an executable the kernel can load
+    BadExecutable = 3,
+    /// An argument is longer than the kernel accepts
+    TooLong = 4,
+    /// A pointer from userspace does not point to user memory
+    BadAddress = 5,
+    /// An argument is malformed
+    InvalidArgument = 6,
 }
 );
 
+/// Exit code of a process killed for a page fault the kernel could not fix
+pub const EXIT_PAGE_FAULT: isize = -1;
+
+/// Exit code of a process killed for any other exception, such as an illegal
+/// instruction or a misaligned access
+pub const EXIT_EXCEPTION: isize = -2;
+
+/// Handle to a process, from [`SyscallNum::ProcessSpawn`]
+pub type ProcessHandle = usize;
+
+/// Most bytes of arguments, including their nul terminators, that can be
+/// given to [`SyscallNum::ProcessSpawn`]
+pub const MAX_SPAWN_ARGS: usize = 1024;
+
 impl From<core::str::Utf8Error> for KernErr {
     fn from(_: core::str::Utf8Error) -> Self {
         Self::BadUtf8
     }
 }
+
+/// Information about the system given to init, pointed to by the
+/// [`auxv::AT_MU_BOOT_INFO`] entry of its auxiliary vector
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct BootInfo {
+    /// Where init's heap should start
+    pub heap_base: usize,
+    /// Number of harts in the system
+    pub num_cpus: usize,
+}
+
+/// Where a program's thread local storage template is, pointed to by the
+/// [`auxv::AT_MU_TLS`] entry of its auxiliary vector. Each thread's TLS block
+/// is `mem_size` bytes aligned to `align`, starting with a copy of the
+/// `file_size` bytes at `template`, followed by zeros. The thread pointer
+/// points to the start of the block.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct TlsInfo {
+    pub template: usize,
+    pub file_size: usize,
+    pub mem_size: usize,
+    pub align: usize,
+}
@@ -2,7 +2,11 @@
 
 #![allow(dead_code)]
 
-use riscv_paging::VirtAddr;
+use core::ops::Range;
+
+use riscv_paging::{PageSize, PagingMode, VirtAddr};
+
+use crate::rand::Rng;
 // https://github.com/qemu/qemu/blob/master/hw/riscv/virt.c
 // static const struct MemmapEntry {
 //     hwaddr base;
@@ -26,6 +30,7 @@ use riscv_paging::VirtAddr;
 // note that this needs to be manually synced with vectors.s values
 pub const MAX_CPUS: usize = 8;
 pub const MAX_THREADS: usize = 64;
+pub const MAX_PROCESSES: usize = 32;
 
 pub const UART0: usize = 0x1000_0000;
 pub const UART0LEN: usize = 0x1000;
@@ -35,8 +40,116 @@ pub const PHYSMEM: usize = 0x8000_0000;
 // 128 MiB
 pub const PHYSMEM_LEN: usize = 128 * 1024 * 1024;
 
+/// Lowest address a randomized position independent program is loaded at.
+/// Below this, the devices and shoo itself are identity mapped into the user
+/// half of every address space.
+pub const USER_IMAGE_FLOOR: usize = PHYSMEM + PHYSMEM_LEN;
+
 pub const MAX_VIRT: usize = 0xffff_ffff_ffff_ffff; // sx(0x80_0000_0000)
-pub const PHYSMEM_MAP: usize = 0xffff_ffe0_0000_0000; // sx(0x60_0000_0000)
 
+// these are within the top 256GB so they are valid in every paging mode
 pub const TRAP_DATA: VirtAddr = VirtAddr(0xffff_ffc0_0000_1000);
-pub const USERSPACE_STACK_TOP: VirtAddr = VirtAddr(0x0000_0040_0000_0000);
+
+/// Where a position independent kernel may be placed by KASLR. This is the
+/// bottom 64GB of the top 256GB, above the trap data, so it is clear of the
+/// physical memory map and kernel stack in every paging mode. It starts on a
+/// 2MB boundary so the kernel base shoo picks in it is 2MB aligned.
+pub const KERNEL_WINDOW: Range<usize> = 0xffff_ffc0_0020_0000..0xffff_ffd0_0000_0000;
+
+/// Where the kernel maps temporary buffers, such as files it decompresses out
+/// of the initrd. This is just above [`KERNEL_WINDOW`].
+pub const KERNEL_SCRATCH: Range<usize> = 0xffff_ffd0_0000_0000..0xffff_ffd8_0000_0000;
+
+/// The parts of the virtual memory map that move around depending on how big
+/// the address space is. See `docs/memory_map.md`.
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryMap {
+    /// Start of the mapping of all of physical memory
+    pub physmem_map: usize,
+    /// Top of the init stack, one past the last userspace address
+    pub userspace_stack_top: VirtAddr,
+}
+
+impl MemoryMap {
+    pub const SV39: MemoryMap = MemoryMap {
+        physmem_map: 0xffff_ffe0_0000_0000, // sx(0x60_0000_0000)
+        userspace_stack_top: VirtAddr(0x0000_0040_0000_0000),
+    };
+
+    pub const SV48: MemoryMap = MemoryMap {
+        physmem_map: 0xffff_c000_0000_0000, // sx(0xc000_0000_0000)
+        userspace_stack_top: VirtAddr(0x0000_8000_0000_0000),
+    };
+
+    pub const SV57: MemoryMap = MemoryMap {
+        physmem_map: 0xff80_0000_0000_0000, // sx(0x180_0000_0000_0000)
+        userspace_stack_top: VirtAddr(0x0100_0000_0000_0000),
+    };
+
+    /// Gets the memory map for the given paging mode
+    pub const fn for_mode(mode: PagingMode) -> MemoryMap {
+        match mode {
+            PagingMode::Sv39 => Self::SV39,
+            PagingMode::Sv48 => Self::SV48,
+            PagingMode::Sv57 => Self::SV57,
+        }
+    }
+}
+
+/// Where things go in a user address space
+#[derive(Clone, Debug)]
+pub struct UserLayout {
+    /// Base for position independent executables
+    pub image_base: VirtAddr,
+    /// Start of the heap
+    pub heap_base: VirtAddr,
+    /// Top of the main thread's stack
+    pub stack_top: VirtAddr,
+}
+
+impl UserLayout {
+    /// Fixed layout, for when there is no randomness
+    pub fn fixed(map: &MemoryMap) -> UserLayout {
+        let top = map.userspace_stack_top.0;
+        UserLayout {
+            image_base: VirtAddr(0x10_0000),
+            heap_base: VirtAddr(top / 8),
+            stack_top: map.userspace_stack_top,
+        }
+    }
+
+    /// Randomized layout. The user half is split up as follows:
+    ///
+    /// - image: within a 16th of the address space, above [`USER_IMAGE_FLOOR`]
+    /// - heap: between 1/8 and 1/4
+    /// - stack: within the last 64th
+    pub fn randomized(map: &MemoryMap, rng: &mut Rng) -> UserLayout {
+        let fixed = Self::fixed(map);
+        let top = map.userspace_stack_top.0;
+        UserLayout {
+            image_base: VirtAddr(USER_IMAGE_FLOOR + rng.aligned_below(top / 16, PageSize::Page4k)),
+            heap_base: VirtAddr(fixed.heap_base.0 + rng.aligned_below(top / 8, PageSize::Page4k)),
+            stack_top: VirtAddr(fixed.stack_top.0 - rng.aligned_below(top / 64, PageSize::Page4k)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_randomized_layout() {
+        for map in &[MemoryMap::SV39, MemoryMap::SV48, MemoryMap::SV57] {
+            let mut rng = Rng::new(1);
+            for _ in 0..10_000 {
+                let layout = UserLayout::randomized(map, &mut rng);
+                let image = layout.image_base.0;
+                assert!(image >= USER_IMAGE_FLOOR, "{:x?}", layout);
+                assert_eq!(image % PageSize::Page4k.size(), 0, "{:x?}", layout);
+                assert!(image < layout.heap_base.0, "{:x?}", layout);
+                assert!(layout.heap_base.0 < layout.stack_top.0, "{:x?}", layout);
+            }
+        }
+    }
+}
@@ -6,11 +6,13 @@ use core::ptr;
 
 use fidget_spinner::ArchDetails;
 use riscv_paging::PAGE_MASK;
-use riscv_paging::{Addr, PageSize, PageTable, PhysAccess, PhysPageMetadata, PAGE_SIZE};
+use riscv_paging::{
+    Addr, PageSize, PageTable, PagingMode, PhysAccess, PhysPageMetadata, PAGE_SIZE,
+};
 
 use bitvec::prelude::*;
 
-use crate::addr::PHYSMEM_MAP;
+use crate::addr::MemoryMap;
 
 pub type Mutex<T> = fidget_spinner::Mutex<T, Arch>;
 pub type Phys<T> = riscv_paging::Phys<T, PhysMem>;
@@ -50,6 +52,8 @@ pub const SIE_SEIE: usize = 9;
 pub const SIE_STIE: usize = 5;
 pub const SIE_SSIE: usize = 1;
 
+pub const SIP_STIP: usize = 5;
+
 macro_rules! csrr {
     ($docs:expr, $fn_name:ident, $csr_name:ident) => {
         csrr!($docs, $fn_name, $csr_name, usize);
@@ -230,6 +234,12 @@ csrw!(
 
 csrr!("Gets the supervisor trap cause", get_scause, scause, enum ExceptionType);
 
+csrr!(
+    "Gets the supervisor trap value (e.g. the faulting address for page faults)",
+    get_stval,
+    stval
+);
+
 // ------------- Unprivileged Instructions ---------------
 
 pub fn set_core_id(new: usize) {
@@ -254,6 +264,12 @@ pub fn core_id() -> usize {
     }
 }
 
+/// Waits for an interrupt to be pending (`wfi`, § 3.3.3 Privileged). This may
+/// also return for no reason at all.
+pub fn wait_for_interrupt() {
+    unsafe { asm!("wfi") }
+}
+
 /// Freezes a hart unrecoverably.
 ///
 /// This is accomplished by turning off interrupts to S mode and then executing
@@ -278,6 +294,9 @@ pub enum MachineCall {
     InterruptHart = 0,
     /// Requests that the STIP flag be cleared in mstatus.
     ClearTimerInt = 1,
+    /// Requests a timer interrupt when `mtime` reaches `mc_arg`, replacing
+    /// any earlier request, and clears STIP. `u64::MAX` turns the timer off.
+    SetTimer = 2,
 }
 
 /// Call into machine mode.
@@ -296,6 +315,13 @@ pub fn clear_stip() {
     machinecall(MachineCall::ClearTimerInt, 0);
 }
 
+/// Calls into machine mode to have the timer go off on this hart when `mtime`
+/// reaches `deadline`, and clear STIP. There is only one deadline per hart;
+/// this replaces the last one.
+pub fn set_timer(deadline: u64) {
+    machinecall(MachineCall::SetTimer, deadline as usize);
+}
+
 #[allow(dead_code)]
 #[repr(u8)]
 #[derive(Debug)]
@@ -306,13 +332,60 @@ pub enum ArchPrivilegeLevel {
     Machine = 3,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TranslationMode {
     /// no translation/protection
     Bare,
     Sv39,
+    Sv48,
+    Sv57,
     Other(u8),
 }
 
+impl TranslationMode {
+    /// Gets the paging mode for walking page tables in this translation mode,
+    /// if it is one we support
+    pub fn paging_mode(self) -> Option<PagingMode> {
+        match self {
+            TranslationMode::Sv39 => Some(PagingMode::Sv39),
+            TranslationMode::Sv48 => Some(PagingMode::Sv48),
+            TranslationMode::Sv57 => Some(PagingMode::Sv57),
+            TranslationMode::Bare | TranslationMode::Other(_) => None,
+        }
+    }
+}
+
+impl From<PagingMode> for TranslationMode {
+    fn from(mode: PagingMode) -> TranslationMode {
+        match mode {
+            PagingMode::Sv39 => TranslationMode::Sv39,
+            PagingMode::Sv48 => TranslationMode::Sv48,
+            PagingMode::Sv57 => TranslationMode::Sv57,
+        }
+    }
+}
+
+/// Finds the largest paging mode this hart supports.
+///
+/// `satp` is WARL and writes of unsupported modes leave it unchanged (§ 4.1.11
+/// Privileged), so we try each mode in turn and see if it sticks. This must be
+/// called from M-mode, where `satp` does not affect translation. Leaves paging
+/// disabled.
+pub unsafe fn probe_paging_mode() -> Option<PagingMode> {
+    for &mode in PagingMode::ALL_DESC.iter() {
+        set_satp(Satp::DISABLED);
+        let mut satp = Satp::DISABLED;
+        satp.set_mode(mode.into());
+        set_satp(satp);
+        let stuck = get_satp().mode() == TranslationMode::from(mode);
+        set_satp(Satp::DISABLED);
+        if stuck {
+            return Some(mode);
+        }
+    }
+    None
+}
+
 /// `satp` CSR
 #[derive(Debug, Clone, Copy)]
 #[repr(transparent)]
@@ -321,8 +394,9 @@ pub struct Satp(pub u64);
 impl Satp {
     pub const DISABLED: Satp = Satp(0);
 
-    /// Makes the `satp` register required for using the given page table
-    pub fn new(pt: &PageTable<PhysMem>, asid: u16, mode: TranslationMode) -> Satp {
+    /// Makes the `satp` register required for using the given page table, in
+    /// its paging mode
+    pub fn new(pt: &PageTable<PhysMem>, asid: u16) -> Satp {
         let ppn = pt.get_base().get() / (PAGE_SIZE as usize);
         assert!(ppn < 1 << 43, "ppn out of range");
         let mut v = 0u64;
@@ -330,7 +404,7 @@ impl Satp {
         bits[0..=43].store(ppn);
         bits[44..=59].store(asid);
         let mut satp = Satp(v);
-        satp.set_mode(mode);
+        satp.set_mode(pt.mode().into());
         satp
     }
 
@@ -339,16 +413,20 @@ impl Satp {
         unsafe { get_satp() }
     }
 
-    /// Turns the Satp into a page table. Will fail if the Satp has the wrong
-    /// mode.
+    /// Switches to this address space and flushes the TLB
+    pub unsafe fn activate(self) {
+        set_satp(self);
+        asm!("sfence.vma");
+    }
+
+    /// Turns the Satp into a page table. Will fail if paging is disabled or
+    /// the mode is not supported.
     pub unsafe fn as_pagetable(&self) -> Option<PageTable<PhysMem>> {
-        if matches!(self.mode(), TranslationMode::Sv39) {
-            Some(PageTable::from_raw(Phys::new_raw(
-                (self.ppn() * PAGE_SIZE) as usize,
-            )))
-        } else {
-            None
-        }
+        let mode = self.mode().paging_mode()?;
+        Some(PageTable::from_raw(
+            Phys::new_raw((self.ppn() * PAGE_SIZE) as usize),
+            mode,
+        ))
     }
 
     /// Gets the physical page number in the Satp
@@ -363,6 +441,8 @@ impl Satp {
         match mode_raw {
             0 => TranslationMode::Bare,
             8 => TranslationMode::Sv39,
+            9 => TranslationMode::Sv48,
+            10 => TranslationMode::Sv57,
             other => TranslationMode::Other(other),
         }
     }
@@ -372,6 +452,8 @@ impl Satp {
         self.0.view_bits_mut::<Lsb0>()[60..=63].store(match new {
             TranslationMode::Bare => 0,
             TranslationMode::Sv39 => 8,
+            TranslationMode::Sv48 => 9,
+            TranslationMode::Sv57 => 10,
             TranslationMode::Other(o) => o,
         })
     }
@@ -449,10 +531,9 @@ static PHYS_FREELIST: Mutex<Option<Phys<PhysPageMetadata<PhysMem>>>> = Mutex::ne
 pub struct PhysMem;
 
 fn pm_base() -> usize {
-    if unsafe { get_satp().paging_enabled() } {
-        PHYSMEM_MAP
-    } else {
-        0
+    match unsafe { get_satp() }.mode().paging_mode() {
+        Some(mode) => MemoryMap::for_mode(mode).physmem_map,
+        None => 0,
     }
 }
 
@@ -497,6 +578,28 @@ impl PhysAccess for PhysMem {
     }
 }
 
+impl PhysMem {
+    /// Takes the whole free list, leaving it empty, and returns the physical
+    /// address of its first page, or zero if it was empty. shoo and the kernel
+    /// each have their own copy of the free list, so this is how shoo hands
+    /// over the free memory.
+    pub unsafe fn take_free_list() -> usize {
+        match PHYS_FREELIST.lock().take() {
+            Some(head) => head.addr().get(),
+            None => 0,
+        }
+    }
+
+    /// Replaces the free list with the one starting at the physical address
+    /// `head`, from [`PhysMem::take_free_list`]
+    pub unsafe fn adopt_free_list(head: usize) {
+        *PHYS_FREELIST.lock() = match head {
+            0 => None,
+            head => Some(Phys::new(PhysAddr::new(head))),
+        };
+    }
+}
+
 // ---------------------------- Faults ----------------------------
 
 // has only the top bit set
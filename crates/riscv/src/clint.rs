@@ -0,0 +1,42 @@
+//! The SiFive CLINT (core local interruptor), which has the timer
+
+use crate::addr;
+
+pub static CLINT: Clint = Clint {
+    base: addr::CLINT as *mut _,
+};
+
+/// Some dude that gives you interrupts
+/// jk it's the SiFive CLINT interrupt controller
+pub struct Clint {
+    base: *mut (),
+}
+
+/// safety: the accesses don't step on each other
+unsafe impl Sync for Clint {}
+
+impl Clint {
+    /// Gets a pointer to the `mtimecmp` register of the hart `hart_id`
+    pub unsafe fn mtimecmp(&self, hart_id: u8) -> *mut u64 {
+        let mtimecmp_base = self.base as usize + 0x4000;
+        let addr = (mtimecmp_base as *mut u64).offset(hart_id as isize);
+        addr
+    }
+
+    /// Reads the current time
+    pub unsafe fn mtime(&self) -> u64 {
+        let mtime_base = self.base as usize + 0xbff8;
+        (mtime_base as *const u64).read_volatile()
+    }
+
+    /// Sets the next mtimecmp interrupt time in ~cycles from now
+    pub unsafe fn schedule_interrupt(&self, hart_id: u8, int_time: u64) {
+        // documented in SiFive U74MC manual, section 9.5
+        // available at https://sifive.cdn.prismic.io/sifive/aee0dd4c-d156-496e-a6c4-db0cf54bbe68_sifive_U74MC_rtl_full_20G1.03.00_manual.pdf
+        let addr = self.mtimecmp(hart_id);
+
+        let now = self.mtime();
+        let next_time = now.wrapping_add(int_time);
+        addr.write_volatile(next_time);
+    }
+}
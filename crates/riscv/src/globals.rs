@@ -8,9 +8,10 @@ pub struct AsmOnly<T>(UnsafeCell<T>);
 unsafe impl<T> Sync for AsmOnly<T> {}
 
 #[no_mangle]
-// do not change this size without also changing it in init.s
-pub static STACKS: AsmOnly<[u8; 16384 * addr::MAX_CPUS]> =
-    AsmOnly(UnsafeCell::new([0u8; 16384 * addr::MAX_CPUS]));
+// do not change this size without also changing it in init.s. shoo needs
+// about 11k of this to decompress initrd entries
+pub static STACKS: AsmOnly<[u8; 32768 * addr::MAX_CPUS]> =
+    AsmOnly(UnsafeCell::new([0u8; 32768 * addr::MAX_CPUS]));
 
 impl HasEmpty for [u8; 8192] {
     const EMPTY: UnsafeCell<Self> = UnsafeCell::new([0u8; 8192]);
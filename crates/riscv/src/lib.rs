@@ -6,8 +6,10 @@
 
 pub mod addr;
 pub mod arch;
+pub mod clint;
 pub mod globals;
 pub mod print;
+pub mod rand;
 
 use paging::VirtAddr;
 pub use riscv_paging as paging;
@@ -23,10 +25,24 @@ pub static PANICKED: AtomicBool = AtomicBool::new(false);
 pub static PANIC_CHECKIN: AtomicUsize = AtomicUsize::new(0);
 pub static NUM_CPUS: AtomicUsize = AtomicUsize::new(0);
 
+/// Whether to dump the current page table when panicking
+pub static DUMP_PT_ON_PANIC: AtomicBool = AtomicBool::new(true);
+/// Set while dumping the page table in the panic handler, so a fault from
+/// walking a broken table doesn't come back around to dumping it again
+static PANIC_DUMPING: AtomicBool = AtomicBool::new(false);
+
 pub type KernEntry = extern "C" fn(core_id: &KernelEntryParams) -> !;
 
 #[panic_handler]
 fn panic_handler(info: &PanicInfo) -> ! {
+    if PANIC_DUMPING.load(Ordering::SeqCst) {
+        // the other cores have already checked in for the first panic, and
+        // the serial port is set up, so just say what happened and stop
+        unsafe { print::Serial::new(addr::UART0 as *mut _) }
+            .transmit(b"!!! Panic !!! while dumping the page table\n");
+        freeze_hart()
+    }
+
     // We implement panicking across cores by having the panicking core
     // send machine software interrupts to all the other cores, which
     // will then, in the handler, detect that PANICKED is true, and halt
@@ -74,6 +90,14 @@ fn panic_handler(info: &PanicInfo) -> ! {
         let _ = write!(serial, "@ {}\n", loc);
     }
 
+    // if paging is on, what is mapped is quite likely relevant to the crash
+    if DUMP_PT_ON_PANIC.load(Ordering::SeqCst) {
+        if let Some(pt) = unsafe { Satp::current().as_pagetable() } {
+            PANIC_DUMPING.store(true, Ordering::SeqCst);
+            let _ = write!(serial, "{}", unsafe { pt.dump() });
+        }
+    }
+
     freeze_hart()
 }
 
@@ -82,6 +106,9 @@ pub struct KernelEntryParams {
     pub core_id: usize,
     pub init_sp: VirtAddr,
     pub init_entrypoint: VirtAddr,
+    /// Thread pointer for init's main thread, pointing to its thread local
+    /// storage block, or zero if it has none
+    pub init_tp: VirtAddr,
     pub stack_pointer: VirtAddr,
     /// number of cpus in the system.
     // TODO(smp): this probably needs to be redesigned along with the boot
@@ -89,4 +116,14 @@ pub struct KernelEntryParams {
     // other cores from halt, bypassing shoo??). but we need it for now for
     // panic handlers
     pub num_cpus: usize,
+    /// The initrd, in the mapping of physical memory
+    pub initrd: VirtAddr,
+    pub initrd_len: usize,
+    /// Physical address of the first page on the free list, from
+    /// [`PhysMem::take_free_list`]
+    pub free_list: usize,
+    /// Seed for randomizing the layout of the processes the kernel starts
+    pub rng_seed: u64,
+    /// Frequency the CLINT `mtime` counter counts at, in Hz
+    pub timebase_freq: u64,
 }
@@ -0,0 +1,83 @@
+//! Randomness for address space layout randomization.
+//!
+//! None of this is cryptographically secure: it is only meant to make
+//! addresses hard to guess, from whatever entropy the machine gives us at boot.
+
+use riscv_paging::PageSize;
+
+/// SplitMix64 finalizer, which scrambles all the bits of `x` into each other
+const fn mix64(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Accumulates entropy from several weak sources into a seed
+#[derive(Clone, Debug, Default)]
+pub struct EntropyPool {
+    state: u64,
+    /// Number of bytes of input so far
+    fed: usize,
+}
+
+impl EntropyPool {
+    pub const fn new() -> EntropyPool {
+        EntropyPool { state: 0, fed: 0 }
+    }
+
+    /// Mixes in a 64 bit value
+    pub fn add_u64(&mut self, v: u64) {
+        self.state = mix64(self.state.wrapping_add(GOLDEN_GAMMA) ^ v);
+        self.fed += 8;
+    }
+
+    /// Mixes in some bytes
+    pub fn add_bytes(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut buf = [0u8; 8];
+            buf[..chunk.len()].copy_from_slice(chunk);
+            self.add_u64(u64::from_le_bytes(buf));
+        }
+    }
+
+    /// Number of bytes that have been mixed in, to tell if we got anything
+    pub fn bytes_fed(&self) -> usize {
+        self.fed
+    }
+
+    /// Makes a random number generator seeded from the pool
+    pub fn into_rng(self) -> Rng {
+        Rng::new(self.state)
+    }
+}
+
+/// SplitMix64 random number generator
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub const fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(GOLDEN_GAMMA);
+        mix64(self.state)
+    }
+
+    /// Gets a random number in `0..bound`, or 0 if `bound` is 0
+    pub fn below(&mut self, bound: u64) -> u64 {
+        // Lemire's multiply-shift; the bias is irrelevant at these sizes
+        ((self.next_u64() as u128 * bound as u128) >> 64) as u64
+    }
+
+    /// Gets a random multiple of `align` in `0..len`
+    pub fn aligned_below(&mut self, len: usize, align: PageSize) -> usize {
+        let slots = len / align.size();
+        self.below(slots as u64) as usize * align.size()
+    }
+}
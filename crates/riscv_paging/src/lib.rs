@@ -1,20 +1,77 @@
-//! A memory manager for RISC-V. Currently only Sv39 is supported (512GB address
-//! space).
+//! A memory manager for RISC-V. Supports the Sv39, Sv48 and Sv57 translation
+//! schemes (3, 4 and 5 level page tables respectively). See [`PagingMode`].
+//!
+//! With the `std` feature, [`mock::MockPhysMem`] is available to run the page
+//! table code against fake physical memory on the host.
 // TODO: This is doing a whole load of unsound shit with forgetting to use
 // volatile ops with pointers
-#![cfg(any(all(target_pointer_width = "64", test), target_arch = "riscv64"))]
+#![cfg(any(
+    all(target_pointer_width = "64", any(test, feature = "std")),
+    target_arch = "riscv64"
+))]
 #![feature(asm)]
 #![allow(non_upper_case_globals)]
-#![no_std]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 
+#[cfg(any(test, feature = "std"))]
+pub mod mock;
+
+use core::ops::Range;
 use core::ptr;
-use core::{marker::PhantomData, mem};
+use core::{fmt, marker::PhantomData, mem};
 
 use bitvec::prelude::*;
 
 pub const PAGE_SIZE: u64 = 4096;
 pub const PT_ENTRIES: usize = PAGE_SIZE as usize / mem::size_of::<Pte>();
 pub const PAGE_MASK: usize = PAGE_SIZE as usize - 1;
+/// Number of levels of page tables in the largest supported [`PagingMode`]
+pub const MAX_LEVELS: usize = 5;
+
+/// Virtual memory translation scheme, determining how many levels of page
+/// table there are and thus how large the virtual address space is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PagingMode {
+    /// 3 levels, 39 bit virtual addresses (512GB address space)
+    Sv39,
+    /// 4 levels, 48 bit virtual addresses (256TB address space)
+    Sv48,
+    /// 5 levels, 57 bit virtual addresses (128PB address space)
+    Sv57,
+}
+
+impl PagingMode {
+    /// All the paging modes, from largest to smallest
+    pub const ALL_DESC: [PagingMode; 3] = [PagingMode::Sv57, PagingMode::Sv48, PagingMode::Sv39];
+
+    /// Number of levels of page table
+    pub const fn levels(self) -> usize {
+        match self {
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+            PagingMode::Sv57 => 5,
+        }
+    }
+
+    /// Gets the paging mode with the given number of levels
+    pub fn from_levels(levels: usize) -> Option<PagingMode> {
+        Self::ALL_DESC
+            .iter()
+            .copied()
+            .find(|m| m.levels() == levels)
+    }
+
+    /// Number of significant bits in a virtual address. The bits above these
+    /// must all be copies of the top significant bit.
+    pub const fn va_bits(self) -> usize {
+        12 + 9 * self.levels()
+    }
+
+    /// Level of the root page table
+    fn top_level(self) -> usize {
+        self.levels() - 1
+    }
+}
 
 /// A newtype wrapper around a physical address.
 #[repr(transparent)]
@@ -156,15 +213,36 @@ impl VirtAddr {
         self.0 as *mut _
     }
 
-    /// Only considers the lower 39 bits, chopping off the top bits
-    fn canonicalize(self) -> VirtAddr {
-        VirtAddr(self.0.view_bits::<Lsb0>()[0..=38].load())
+    /// Only considers the lower bits that are significant in the given
+    /// [`PagingMode`], chopping off the top bits
+    fn canonicalize(self, mode: PagingMode) -> VirtAddr {
+        VirtAddr(self.0.view_bits::<Lsb0>()[0..mode.va_bits()].load())
+    }
+
+    /// Makes an address from its significant bits in the given [`PagingMode`]
+    /// by sign extending the top one
+    fn sign_extend(va: usize, mode: PagingMode) -> VirtAddr {
+        let shift = 64 - mode.va_bits();
+        VirtAddr(((va << shift) as isize >> shift) as usize)
+    }
+
+    /// Is this address valid in the given [`PagingMode`], that is, are all the
+    /// bits above the significant ones equal to the top significant one?
+    pub fn is_canonical(self, mode: PagingMode) -> bool {
+        Self::sign_extend(self.0, mode) == self
     }
 
-    /// Decomposes the address into an array `VPN[0]`, `VPN[1]`, `VPN[2]`
-    fn parts(self) -> [u16; 3] {
+    /// Decomposes the address into an array `VPN[0]` through `VPN[4]`. Only the
+    /// first [`PagingMode::levels`] of these are meaningful.
+    fn parts(self) -> [u16; MAX_LEVELS] {
         let h = self.0.view_bits::<Lsb0>();
-        [h[12..=20].load(), h[21..=29].load(), h[30..=38].load()]
+        [
+            h[12..=20].load(),
+            h[21..=29].load(),
+            h[30..=38].load(),
+            h[39..=47].load(),
+            h[48..=56].load(),
+        ]
     }
 }
 
@@ -263,6 +341,33 @@ impl PteAttrs {
     fn is_leaf(self) -> bool {
         self.intersects(PteAttrs::R | PteAttrs::W | PteAttrs::X)
     }
+
+    /// Does a leaf with these attributes allow the given kind of access (not
+    /// considering the `User` bit)?
+    fn allows(self, access: AccessType) -> bool {
+        match access {
+            AccessType::Read => self.contains(PteAttrs::R),
+            AccessType::Write => self.contains(PteAttrs::W),
+            AccessType::Execute => self.contains(PteAttrs::X),
+        }
+    }
+
+    /// Gets the accessed and dirty bits that the hardware would set when
+    /// performing the given kind of access to a page
+    fn touched_by(access: AccessType) -> PteAttrs {
+        match access {
+            AccessType::Write => PteAttrs::Accessed | PteAttrs::Dirty,
+            AccessType::Read | AccessType::Execute => PteAttrs::Accessed,
+        }
+    }
+}
+
+/// Kind of memory access, for checking permissions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessType {
+    Read,
+    Write,
+    Execute,
 }
 
 impl Pte {
@@ -287,6 +392,13 @@ impl Pte {
             PteAttrs::from_bits_truncate(h[0..=7].load()),
         )
     }
+
+    /// Makes a copy of this entry with the attributes replaced by `attrs`
+    fn with_attrs(self, attrs: PteAttrs) -> Pte {
+        let mut inner = self.0;
+        inner.view_bits_mut::<Lsb0>()[0..=7].store(attrs.bits());
+        Pte(inner)
+    }
 }
 
 impl core::fmt::Debug for Pte {
@@ -308,6 +420,8 @@ pub struct PhysPageMetadata<P: PhysAccess> {
 #[derive(Clone, Copy, Debug)]
 pub struct PageTable<P: PhysAccess> {
     base: Phys<Pte, P>,
+    /// Mode of the tree of page tables this one is part of
+    mode: PagingMode,
 }
 
 /// Provides access to read/write to physical memory
@@ -324,13 +438,14 @@ pub trait PhysAccess: Copy {
 }
 
 impl<P: PhysAccess> PageTable<P> {
-    /// Creates a PageTable based at the given address
-    pub unsafe fn from_raw(base: Phys<Pte, P>) -> PageTable<P> {
+    /// Creates a PageTable based at the given address, that is walked with the
+    /// given [`PagingMode`]
+    pub unsafe fn from_raw(base: Phys<Pte, P>, mode: PagingMode) -> PageTable<P> {
         assert!(
             base.addr.is_page_aligned(PageSize::Page4k),
             "base addr must be aligned to a page"
         );
-        PageTable { base }
+        PageTable { base, mode }
     }
 
     /// Gets the entry at the index `num` in the page table. Panics if it is out
@@ -351,15 +466,26 @@ impl<P: PhysAccess> PageTable<P> {
         unsafe { self.base.addr() }
     }
 
+    /// Gets the paging mode this page table is walked with
+    pub fn mode(&self) -> PagingMode {
+        self.mode
+    }
+
     /// Allocates a page table and zeroes it
-    pub unsafe fn alloc() -> Option<PageTable<P>> {
+    pub unsafe fn alloc(mode: PagingMode) -> Option<PageTable<P>> {
         let allocation = P::alloc()?;
         let base = Phys::new(allocation);
         let base_: *mut Pte = base.as_ptr();
 
         // zeroing the page sets it all to V = 0
         ptr::write_bytes(base_, 0, PT_ENTRIES);
-        Some(PageTable { base })
+        Some(PageTable { base, mode })
+    }
+
+    /// Makes a PageTable for the next level table pointed to by a non-leaf
+    /// entry with the given PPN
+    unsafe fn subtable(&self, ppn: u64) -> PageTable<P> {
+        PageTable::from_raw(Phys::new_raw((ppn * PAGE_SIZE) as usize), self.mode)
     }
 
     /// Initializes the page table to all invalid entries
@@ -374,11 +500,21 @@ pub enum PageSize {
     Page4k = 0,
     Page2m = 1,
     Page1g = 2,
+    /// Only available in Sv48 and larger
+    Page512g = 3,
+    /// Only available in Sv57
+    Page256t = 4,
 }
 
 impl PageSize {
     /// An iterator of page sizes in descending size order
-    const SIZES_DESC: [PageSize; 3] = [PageSize::Page1g, PageSize::Page2m, PageSize::Page4k];
+    const SIZES_DESC: [PageSize; 5] = [
+        PageSize::Page256t,
+        PageSize::Page512g,
+        PageSize::Page1g,
+        PageSize::Page2m,
+        PageSize::Page4k,
+    ];
 
     /// Returns the numeric size of the page.
     #[inline]
@@ -387,6 +523,20 @@ impl PageSize {
             PageSize::Page4k => 4096,
             PageSize::Page2m => 2 * 1024 * 1024,
             PageSize::Page1g => 1 * 1024 * 1024 * 1024,
+            PageSize::Page512g => 512 * 1024 * 1024 * 1024,
+            PageSize::Page256t => 256 * 1024 * 1024 * 1024 * 1024,
+        }
+    }
+
+    /// Gets the size of a leaf page at the given page table level
+    fn from_level(level: usize) -> PageSize {
+        match level {
+            0 => PageSize::Page4k,
+            1 => PageSize::Page2m,
+            2 => PageSize::Page1g,
+            3 => PageSize::Page512g,
+            4 => PageSize::Page256t,
+            _ => panic!("no page size for level {}", level),
         }
     }
 
@@ -401,8 +551,9 @@ impl PageSize {
 /// The result of walking the page table for some virtual address.
 #[derive(Debug, Clone, Copy, Default)]
 pub struct PageWalkResult {
-    /// The up to three Ptes that are encountered on the walk
-    pub parts: [Option<Pte>; 3],
+    /// The up to [`MAX_LEVELS`] Ptes that are encountered on the walk, indexed
+    /// by level
+    pub parts: [Option<Pte>; MAX_LEVELS],
 
     /// If the resolution succeeded, this will be Some with the last level PTE
     pub last_level: Option<Pte>,
@@ -410,11 +561,16 @@ pub struct PageWalkResult {
 
 /// Invalidates the page table cache for all the asids for the given address
 // TODO: do this smarter
+#[cfg(target_arch = "riscv64")]
 unsafe fn invalidate_cache(vaddr: VirtAddr) {
     asm!("sfence.vma x0, {vaddr}",
         vaddr = in (reg) vaddr.0);
 }
 
+/// There is no TLB to invalidate when running on the host
+#[cfg(not(target_arch = "riscv64"))]
+unsafe fn invalidate_cache(_vaddr: VirtAddr) {}
+
 impl<P: PhysAccess> PageTable<P> {
     /// Resolves a virtual address using a page table, returning all the relevant PTEs.
     pub unsafe fn resolve(self, va: VirtAddr) -> Result<PageWalkResult, MapError> {
@@ -422,7 +578,7 @@ impl<P: PhysAccess> PageTable<P> {
         let parts = va.parts();
         let mut res: PageWalkResult = Default::default();
         let mut pt = self;
-        for level in (0..=2).rev() {
+        for level in (0..self.mode.levels()).rev() {
             let part = parts[level];
             log::debug!(
                 "level {:?} part {:?} table at {:?}",
@@ -443,7 +599,7 @@ impl<P: PhysAccess> PageTable<P> {
                 res.last_level = Some(pte);
                 return Ok(res);
             }
-            pt = PageTable::from_raw(Phys::new_raw((pnum * PAGE_SIZE) as usize));
+            pt = pt.subtable(pnum);
         }
         Ok(res)
     }
@@ -454,6 +610,10 @@ impl<P: PhysAccess> PageTable<P> {
     /// Assumes the root_pt is already present and initialized, that we have exclusive
     /// access, and that interrupts are disabled.
     ///
+    /// The page is mapped with `Accessed` set, and `Dirty` set if it is
+    /// writable, since some hardware faults on access to pages without these
+    /// rather than setting them itself.
+    ///
     /// Fails if it is trying to map something already mapped.
     pub unsafe fn virt_map_one(
         self,
@@ -463,7 +623,10 @@ impl<P: PhysAccess> PageTable<P> {
         attrs: PteAttrs,
     ) -> Result<(), MapError> {
         // there is no reason you would want to map something invalid
-        let attrs = attrs | PteAttrs::V;
+        let mut attrs = attrs | PteAttrs::V | PteAttrs::Accessed;
+        if attrs.contains(PteAttrs::W) {
+            attrs |= PteAttrs::Dirty;
+        }
         log::debug!(
             "virt_map_one pa={:?}, va={:?}, size={:?}, attrs={:?}",
             pa,
@@ -480,14 +643,25 @@ impl<P: PhysAccess> PageTable<P> {
             va.is_page_aligned(size),
             "mapped virt address must be page aligned"
         );
+        let top = self.mode.top_level();
+        assert!(
+            size as usize <= top,
+            "page size {:?} not available in {:?}",
+            size,
+            self.mode
+        );
         let pa = PhysAddr::<P>::new(pa.get());
-        let va = va.canonicalize().round_up(PageSize::Page4k).check_ovf()?;
+        let orig_va = va;
+        let va = va
+            .canonicalize(self.mode)
+            .round_up(PageSize::Page4k)
+            .check_ovf()?;
         let va_parts = va.parts();
 
         let mut table = self;
         let mut pte_addr;
-        let mut level = 2;
-        for i in (size as usize..=2).rev() {
+        let mut level = top;
+        for i in (size as usize..=top).rev() {
             log::debug!(
                 "look level {} index {:3} at {:?}",
                 i,
@@ -514,15 +688,13 @@ impl<P: PhysAccess> PageTable<P> {
                 i != 0,
                 "we should never find non-leaf Ptes at the last level table"
             );
-            table = PageTable {
-                base: Phys::new_raw((next_ppn * PAGE_SIZE) as usize),
-            };
+            table = table.subtable(next_ppn);
         }
 
         // we need to allocate some page tables now if we are not at level 0 already
         for i in (size as usize + 1..=level).rev() {
             let entry = table.base.as_ptr().offset(va_parts[i] as isize);
-            let next_pt = PageTable::<P>::alloc().ok_or(MapError::OOM)?;
+            let next_pt = PageTable::<P>::alloc(self.mode).ok_or(MapError::OOM)?;
             let pte = Pte::new(next_pt.base.addr(), PteAttrs::V);
             log::debug!(
                 "write level {} index {:3} at {:?} pte {:?}",
@@ -553,7 +725,7 @@ impl<P: PhysAccess> PageTable<P> {
         //
         // For the minute, we will clear the TLB for the task's ASID on task entry.
         // It's easy but not very good.
-        invalidate_cache(va);
+        invalidate_cache(orig_va);
         Ok(())
     }
 
@@ -594,7 +766,7 @@ impl<P: PhysAccess> PageTable<P> {
     pub unsafe fn virt_unmap_one(self, va: VirtAddr) -> Result<(), UnmapError> {
         let parts = va.parts();
         let mut pt = self;
-        for i in 0..=2 {
+        for i in (0..self.mode.levels()).rev() {
             let pte = pt.entry(parts[i]);
             let pte_p = pt.entry_ptr(parts[i]);
             let (next_ppn, attrs) = pte.decompose();
@@ -604,10 +776,11 @@ impl<P: PhysAccess> PageTable<P> {
 
             if attrs.is_leaf() {
                 pte_p.write_volatile(Pte::UNMAPPED);
+                invalidate_cache(va);
                 return Ok(());
             }
 
-            pt = PageTable::from_raw(Phys::new_raw((next_ppn * PAGE_SIZE) as usize));
+            pt = pt.subtable(next_ppn);
         }
         Ok(())
     }
@@ -635,6 +808,523 @@ impl<P: PhysAccess> PageTable<P> {
 
         Ok(())
     }
+
+    /// Frees the user half of the address space, which is the lower half of
+    /// the root table: the page tables in it, and the 4k pages mapped `User`,
+    /// which must have come from the allocator. Other leaves, such as device
+    /// memory, are only unmapped. The kernel half may be shared with other
+    /// page tables so it is left alone.
+    ///
+    /// The page table must not be active.
+    pub unsafe fn free_user_half(self) {
+        for idx in 0..PT_ENTRIES / 2 {
+            let pte_p = self.entry_ptr(idx as u16);
+            self.free_entry(pte_p.read_volatile(), self.mode.top_level());
+            pte_p.write_volatile(Pte::UNMAPPED);
+        }
+    }
+
+    /// Frees whatever the entry `pte` at `level` points to
+    unsafe fn free_entry(self, pte: Pte, level: usize) {
+        let (ppn, attrs) = pte.decompose();
+        if !attrs.contains(PteAttrs::V) {
+            return;
+        }
+        if attrs.is_leaf() {
+            if level == 0 && attrs.contains(PteAttrs::User) {
+                P::free(PhysAddr::new((ppn * PAGE_SIZE) as usize));
+            }
+            return;
+        }
+        if level == 0 {
+            log::warn!("non-leaf pte at level 0 in table {:?}", self.base);
+            return;
+        }
+        let table = self.subtable(ppn);
+        for idx in 0..PT_ENTRIES {
+            table.free_entry(table.entry(idx as u16), level - 1);
+        }
+        P::free(table.get_base());
+    }
+
+    /// Finds the leaf entry mapping `va`, if there is one
+    unsafe fn find_leaf(self, va: VirtAddr) -> Option<Leaf> {
+        // the walk only looks at the low bits, so anything above them would
+        // otherwise alias a canonical address
+        if !va.is_canonical(self.mode) {
+            return None;
+        }
+        let parts = va.parts();
+        let mut pt = self;
+        for level in (0..self.mode.levels()).rev() {
+            let pte_p = pt.entry_ptr(parts[level]);
+            let (next_ppn, attrs) = pte_p.read_volatile().decompose();
+            if !attrs.contains(PteAttrs::V) {
+                return None;
+            }
+            if attrs.is_leaf() {
+                return Some(Leaf {
+                    va: va.round_down(PageSize::from_level(level))?,
+                    pte: pte_p,
+                    size: PageSize::from_level(level),
+                });
+            }
+            pt = pt.subtable(next_ppn);
+        }
+        None
+    }
+
+    /// Handles a page fault caused by the `Accessed` or `Dirty` bit being
+    /// clear on hardware that does not set them itself, by setting them as the
+    /// hardware would have.
+    ///
+    /// Returns whether the fault was fixed up and the access should be retried.
+    /// If it returns false, the fault is a genuine one.
+    pub unsafe fn fixup_accessed_dirty(self, va: VirtAddr, access: AccessType) -> bool {
+        let leaf = match self.find_leaf(va) {
+            Some(leaf) => leaf,
+            None => return false,
+        };
+        let pte = leaf.pte.read_volatile();
+        let (_, attrs) = pte.decompose();
+        let needed = PteAttrs::touched_by(access);
+        if !attrs.allows(access) || attrs.contains(needed) {
+            return false;
+        }
+        leaf.pte.write_volatile(pte.with_attrs(attrs | needed));
+        invalidate_cache(leaf.va);
+        true
+    }
+
+    /// Clears the `Accessed` bit on every page overlapping `range`, returning
+    /// an iterator over the pages that had it set, that is, those that have
+    /// been touched since the last scan. Pages are only scanned as the
+    /// iterator is advanced.
+    ///
+    /// Pages touched by another hart running this page table during the scan
+    /// may or may not be reported in this scan.
+    pub unsafe fn scan_and_clear_accessed(self, range: Range<VirtAddr>) -> ScanAndClear<P> {
+        ScanAndClear {
+            walk: LeafWalk::with_range(self, range),
+            bit: PteAttrs::Accessed,
+        }
+    }
+
+    /// Clears the `Dirty` bit on every page overlapping `range`, returning an
+    /// iterator over the pages that had it set, that is, those that have been
+    /// written since the last scan. See
+    /// [`PageTable::scan_and_clear_accessed`].
+    pub unsafe fn scan_and_clear_dirty(self, range: Range<VirtAddr>) -> ScanAndClear<P> {
+        ScanAndClear {
+            walk: LeafWalk::with_range(self, range),
+            bit: PteAttrs::Dirty,
+        }
+    }
+}
+
+impl<P: PhysAccess> PageTable<P> {
+    /// Translates `va` to the physical address it is mapped to, along with the
+    /// attributes of the page it is in.
+    pub unsafe fn translate(self, va: VirtAddr) -> Option<(PhysAddr<P>, PteAttrs)> {
+        let leaf = self.find_leaf(va)?;
+        let (ppn, attrs) = leaf.pte.read_volatile().decompose();
+        let offs = va.0 & leaf.size.offs_mask();
+        Some((PhysAddr::new((ppn * PAGE_SIZE) as usize + offs), attrs))
+    }
+
+    /// Copies user memory at `va` in this page table into `buf`. The page
+    /// table does not need to be the active one.
+    ///
+    /// Every page touched must be mapped `User` and readable. Pages are marked
+    /// accessed as if the user had read them. On failure, part of `buf` may
+    /// have been written.
+    pub unsafe fn read_virt(self, va: VirtAddr, buf: &mut [u8]) -> Result<(), AccessError> {
+        let mut done = 0;
+        self.access_virt(va, buf.len(), AccessType::Read, |phys, len| {
+            ptr::copy_nonoverlapping(phys, buf[done..].as_mut_ptr(), len);
+            done += len;
+        })
+    }
+
+    /// Copies `buf` into user memory at `va` in this page table. The page table
+    /// does not need to be the active one.
+    ///
+    /// Every page touched must be mapped `User` and writable. Pages are marked
+    /// accessed and dirty as if the user had written them. On failure, part of
+    /// `buf` may have been written.
+    pub unsafe fn write_virt(self, va: VirtAddr, buf: &[u8]) -> Result<(), AccessError> {
+        let mut done = 0;
+        self.access_virt(va, buf.len(), AccessType::Write, |phys, len| {
+            ptr::copy_nonoverlapping(buf[done..].as_ptr(), phys, len);
+            done += len;
+        })
+    }
+
+    /// Calls `f` with a pointer to and length of each physically contiguous
+    /// piece of the `len` bytes at `va`, after checking the user is allowed to
+    /// perform `access` on it.
+    unsafe fn access_virt(
+        self,
+        va: VirtAddr,
+        len: usize,
+        access: AccessType,
+        mut f: impl FnMut(*mut u8, usize),
+    ) -> Result<(), AccessError> {
+        va.0.checked_add(len).ok_or(AccessError::ArithOvf)?;
+        if len != 0 {
+            let last = VirtAddr(va.0 + len - 1);
+            if !va.is_canonical(self.mode) || !last.is_canonical(self.mode) {
+                return Err(AccessError::NonCanonical(va));
+            }
+        }
+        let mut done = 0;
+        while done < len {
+            let here = VirtAddr(va.0 + done);
+            let leaf = self.find_leaf(here).ok_or(AccessError::NotMapped(here))?;
+            let pte = leaf.pte.read_volatile();
+            let (ppn, attrs) = pte.decompose();
+            if !attrs.contains(PteAttrs::User) || !attrs.allows(access) {
+                return Err(AccessError::PermissionDenied(here));
+            }
+            let needed = PteAttrs::touched_by(access);
+            if !attrs.contains(needed) {
+                leaf.pte.write_volatile(pte.with_attrs(attrs | needed));
+                invalidate_cache(leaf.va);
+            }
+
+            let offs = here.0 & leaf.size.offs_mask();
+            let chunk = (leaf.size.size() - offs).min(len - done);
+            let phys = PhysAddr::<P>::new((ppn * PAGE_SIZE) as usize + offs);
+            f(phys.as_u8_ptr(), chunk);
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+/// Iterator over the pages that had an attribute bit set, clearing it as it
+/// goes. See [`PageTable::scan_and_clear_accessed`].
+pub struct ScanAndClear<P: PhysAccess> {
+    walk: LeafWalk<P>,
+    bit: PteAttrs,
+}
+
+impl<P: PhysAccess> Iterator for ScanAndClear<P> {
+    /// Start address and size of a page that had the bit set
+    type Item = (VirtAddr, PageSize);
+
+    fn next(&mut self) -> Option<(VirtAddr, PageSize)> {
+        for leaf in &mut self.walk {
+            // safety: the page table is valid as a precondition of walking it
+            unsafe {
+                let pte = leaf.pte.read_volatile();
+                let (_, attrs) = pte.decompose();
+                if !attrs.contains(self.bit) {
+                    continue;
+                }
+                leaf.pte.write_volatile(pte.with_attrs(attrs - self.bit));
+                invalidate_cache(leaf.va);
+            }
+            return Some((leaf.va, leaf.size));
+        }
+        None
+    }
+}
+
+/// A run of virtual memory that is mapped to contiguous physical memory with
+/// the same page size and attributes.
+#[derive(Clone)]
+pub struct Mapping<P: PhysAccess> {
+    /// Virtual addresses covered by the run
+    pub virt: Range<VirtAddr>,
+    /// Physical address that the start of `virt` is mapped to
+    pub phys: PhysAddr<P>,
+    /// Size of the pages the run is made of
+    pub size: PageSize,
+    /// Attributes of every page in the run
+    pub attrs: PteAttrs,
+}
+
+impl<P: PhysAccess> fmt::Debug for Mapping<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mapping")
+            .field("virt", &self.virt)
+            .field("phys", &self.phys)
+            .field("size", &self.size)
+            .field("attrs", &self.attrs)
+            .finish()
+    }
+}
+
+impl<P: PhysAccess> PartialEq for Mapping<P> {
+    fn eq(&self, other: &Self) -> bool {
+        self.virt == other.virt
+            && self.phys == other.phys
+            && self.size == other.size
+            && self.attrs == other.attrs
+    }
+}
+
+/// A leaf entry found while walking a page table
+#[derive(Clone, Copy, Debug)]
+struct Leaf {
+    va: VirtAddr,
+    pte: *mut Pte,
+    size: PageSize,
+}
+
+/// Walks all the leaf entries of a page table in ascending order of virtual
+/// address.
+struct LeafWalk<P: PhysAccess> {
+    /// The table being walked at each level, along with the index of the next
+    /// entry to look at in it
+    tables: [(Option<PageTable<P>>, u16); MAX_LEVELS],
+    /// Level currently being walked
+    level: usize,
+    mode: PagingMode,
+    /// Only entries overlapping this range are visited
+    range: Range<VirtAddr>,
+}
+
+impl<P: PhysAccess> LeafWalk<P> {
+    fn new(root: PageTable<P>) -> LeafWalk<P> {
+        Self::with_range(root, VirtAddr(0)..VirtAddr(usize::MAX))
+    }
+
+    fn with_range(root: PageTable<P>, range: Range<VirtAddr>) -> LeafWalk<P> {
+        let mode = root.mode;
+        let mut tables = [(None, 0); MAX_LEVELS];
+        tables[mode.top_level()] = (Some(root), 0);
+        LeafWalk {
+            tables,
+            level: mode.top_level(),
+            mode,
+            range,
+        }
+    }
+
+    /// Computes the virtual address for the entry before the cursor at each
+    /// level from `level` upwards
+    fn current_va(&self, level: usize) -> VirtAddr {
+        let mut va = 0usize;
+        for l in level..self.mode.levels() {
+            let idx = self.tables[l].1 as usize - 1;
+            va |= idx << (12 + 9 * l);
+        }
+        VirtAddr::sign_extend(va, self.mode)
+    }
+}
+
+impl<P: PhysAccess> Iterator for LeafWalk<P> {
+    type Item = Leaf;
+
+    fn next(&mut self) -> Option<Leaf> {
+        loop {
+            let level = self.level;
+            let (table, idx) = self.tables[level];
+            let table = table?;
+            if idx as usize == PT_ENTRIES {
+                if level == self.mode.top_level() {
+                    self.tables[level].0 = None;
+                    return None;
+                }
+                // done with this table, go back up
+                self.level += 1;
+                continue;
+            }
+            self.tables[level].1 += 1;
+
+            // we walk in ascending address order, so skip entries before the
+            // range and stop at the first one after it
+            let va = self.current_va(level);
+            let last = va.0 + (PageSize::from_level(level).size() - 1);
+            if last < self.range.start.0 {
+                continue;
+            }
+            if va >= self.range.end {
+                self.level = self.mode.top_level();
+                self.tables[self.level].0 = None;
+                return None;
+            }
+
+            let pte_p = table.entry_ptr(idx);
+            // safety: the page table is valid as a precondition of walking it
+            let (ppn, attrs) = unsafe { pte_p.read_volatile() }.decompose();
+            if !attrs.contains(PteAttrs::V) {
+                continue;
+            }
+
+            if attrs.is_leaf() {
+                return Some(Leaf {
+                    va,
+                    pte: pte_p,
+                    size: PageSize::from_level(level),
+                });
+            }
+
+            if level == 0 {
+                log::warn!("non-leaf pte at level 0 in table {:?}", table.base);
+                continue;
+            }
+            self.level -= 1;
+            self.tables[self.level] = (Some(unsafe { table.subtable(ppn) }), 0);
+        }
+    }
+}
+
+/// Iterator over the coalesced mappings of a page table. See
+/// [`PageTable::iter_mappings`].
+pub struct Mappings<P: PhysAccess> {
+    walk: LeafWalk<P>,
+    pending: Option<Mapping<P>>,
+}
+
+impl<P: PhysAccess> Iterator for Mappings<P> {
+    type Item = Mapping<P>;
+
+    fn next(&mut self) -> Option<Mapping<P>> {
+        for leaf in &mut self.walk {
+            // safety: LeafWalk only gives out pointers to valid entries
+            let (ppn, attrs) = unsafe { leaf.pte.read_volatile() }.decompose();
+            let phys = PhysAddr::new((ppn * PAGE_SIZE) as usize);
+            let page_end = VirtAddr(leaf.va.0.wrapping_add(leaf.size.size()));
+
+            match &mut self.pending {
+                Some(run)
+                    if run.size == leaf.size
+                        && run.attrs == attrs
+                        && run.virt.end == leaf.va
+                        && run
+                            .phys
+                            .get()
+                            .wrapping_add(run.virt.end.0 - run.virt.start.0)
+                            == phys.get() =>
+                {
+                    run.virt.end = page_end;
+                }
+                _ => {
+                    let finished = self.pending.replace(Mapping {
+                        virt: leaf.va..page_end,
+                        phys,
+                        size: leaf.size,
+                        attrs,
+                    });
+                    if finished.is_some() {
+                        return finished;
+                    }
+                }
+            }
+        }
+        self.pending.take()
+    }
+}
+
+/// Helper struct that `impl`s [`Display`](fmt::Display) for the mappings of a
+/// page table, in the style of Linux's `ptdump`. See [`PageTable::dump`].
+pub struct PageTableDump<P: PhysAccess>(PageTable<P>);
+
+/// Formats a size in bytes in the largest unit that divides it
+struct HumanSize(usize);
+
+impl fmt::Display for HumanSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [(usize, &str); 4] = [
+            (1 << 40, "T"),
+            (1 << 30, "G"),
+            (1 << 20, "M"),
+            (1 << 10, "K"),
+        ];
+        for &(unit, suffix) in UNITS.iter() {
+            if self.0 % unit == 0 {
+                return write!(f, "{:>6}{}", self.0 / unit, suffix);
+            }
+        }
+        write!(f, "{:>6}B", self.0)
+    }
+}
+
+impl fmt::Display for PteAttrs {
+    /// Formats the attributes as `DAGUXWRV`, with unset bits replaced by `-`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const NAMES: [(PteAttrs, char); 8] = [
+            (PteAttrs::Dirty, 'D'),
+            (PteAttrs::Accessed, 'A'),
+            (PteAttrs::Global, 'G'),
+            (PteAttrs::User, 'U'),
+            (PteAttrs::X, 'X'),
+            (PteAttrs::W, 'W'),
+            (PteAttrs::R, 'R'),
+            (PteAttrs::V, 'V'),
+        ];
+        for &(flag, name) in NAMES.iter() {
+            let c = if self.contains(flag) { name } else { '-' };
+            fmt::Write::write_char(f, c)?;
+        }
+        Ok(())
+    }
+}
+
+impl<P: PhysAccess> fmt::Display for PageTableDump<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "---[ {:?} page table at {:?} ]---",
+            self.0.mode,
+            self.0.get_base()
+        )?;
+        let mut in_kernel_half = false;
+        // safety: upheld by the caller of PageTable::dump
+        for mapping in unsafe { self.0.iter_mappings() } {
+            let start = mapping.virt.start.0;
+            if !in_kernel_half && (start as isize) < 0 {
+                in_kernel_half = true;
+                writeln!(f, "---[ kernel half ]---")?;
+            }
+            let level = match mapping.size {
+                PageSize::Page4k => "PTE",
+                PageSize::Page2m => "PMD",
+                PageSize::Page1g => "PUD",
+                PageSize::Page512g => "P4D",
+                PageSize::Page256t => "PGD",
+            };
+            writeln!(
+                f,
+                "0x{:016x}-0x{:016x} {} {} {} -> 0x{:016x}",
+                start,
+                mapping.virt.end.0,
+                HumanSize(mapping.virt.end.0.wrapping_sub(start)),
+                level,
+                mapping.attrs,
+                mapping.phys.get(),
+            )?;
+        }
+        writeln!(f, "---[ end ]---")
+    }
+}
+
+impl<P: PhysAccess> PageTable<P> {
+    /// Iterates over the mappings in the page table in order of ascending
+    /// virtual address, merging adjacent pages with the same size and
+    /// attributes that are also contiguous in physical memory into one
+    /// [`Mapping`].
+    ///
+    /// The page table must not be modified while iterating.
+    pub unsafe fn iter_mappings(self) -> Mappings<P> {
+        Mappings {
+            walk: LeafWalk::new(self),
+            pending: None,
+        }
+    }
+
+    /// Gets an object that [`Display`](fmt::Display)s all the mappings in
+    /// the page table. This does not allocate, so it is usable from the panic
+    /// path.
+    ///
+    /// The same requirements as [`PageTable::iter_mappings`] apply while the
+    /// result is being formatted.
+    pub unsafe fn dump(self) -> PageTableDump<P> {
+        PageTableDump(self)
+    }
 }
 
 trait OvfFail {
@@ -667,6 +1357,19 @@ pub enum UnmapError {
     NotMapped,
 }
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum AccessError {
+    /// arithmetic overflow
+    ArithOvf,
+    /// the given address is not mapped
+    NotMapped(VirtAddr),
+    /// the access starting at the given address goes outside of the
+    /// addresses that are valid in the paging mode
+    NonCanonical(VirtAddr),
+    /// the given address is mapped but the user may not access it that way
+    PermissionDenied(VirtAddr),
+}
+
 /*
 /// Performs the same function as [`virt_map`] but uses large pages automatically
 ///
@@ -722,10 +1425,468 @@ pub unsafe fn virt_map_large<P: PhysAccess>(
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::mock::{MockPhysMem, MOCK_PHYS_BASE};
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+    use std::vec::Vec;
+
+    type Pt = PageTable<MockPhysMem>;
+
+    fn new_pt(pages: usize) -> Pt {
+        new_pt_mode(pages, PagingMode::Sv39)
+    }
+
+    fn new_pt_mode(pages: usize, mode: PagingMode) -> Pt {
+        MockPhysMem::init(pages);
+        unsafe { Pt::alloc(mode) }.unwrap()
+    }
+
+    fn pa(addr: usize) -> PhysAddr<MockPhysMem> {
+        PhysAddr::new(addr)
+    }
+
+    /// Attributes a leaf mapped with `attrs` ends up with
+    fn mapped(attrs: PteAttrs) -> PteAttrs {
+        let mut attrs = attrs | PteAttrs::V | PteAttrs::Accessed;
+        if attrs.contains(PteAttrs::W) {
+            attrs |= PteAttrs::Dirty;
+        }
+        attrs
+    }
+
+    /// Translates `va` to the physical address and attributes it maps to
+    fn translate(pt: Pt, va: VirtAddr) -> Option<(usize, PteAttrs)> {
+        let res = unsafe { pt.resolve(va) }.unwrap();
+        let (ppn, attrs) = res.last_level?.decompose();
+        // all the tests use 4k pages except where they check this themselves
+        Some(((ppn * PAGE_SIZE) as usize + (va.0 & PAGE_MASK), attrs))
+    }
+
+    #[test]
+    fn test_map_resolve() {
+        let pt = new_pt(16);
+        let va = VirtAddr(0x10_0000);
+        unsafe { pt.virt_map(pa(0x1234_5000), va, 3 * 4096, PteAttrs::R | PteAttrs::W) }
+            .unwrap();
+
+        for page in 0..3 {
+            let (addr, attrs) = translate(pt, va.offset(page * 4096 + 12)).unwrap();
+            assert_eq!(addr, 0x1234_5000 + page as usize * 4096 + 12);
+            assert_eq!(attrs, mapped(PteAttrs::R | PteAttrs::W));
+        }
+        assert_eq!(translate(pt, va.offset(3 * 4096)), None);
+        assert_eq!(translate(pt, va.offset(-4096)), None);
+
+        let res = unsafe { pt.virt_map_one(pa(0x5000), va, PageSize::Page4k, PteAttrs::R) };
+        assert!(matches!(res, Err(MapError::AlreadyMapped)));
+    }
+
+    #[test]
+    fn test_unmap() {
+        let pt = new_pt(16);
+        let va = VirtAddr(0x40_0000);
+        unsafe { pt.virt_map(pa(0x9000_0000), va, 2 * 4096, PteAttrs::R) }.unwrap();
+
+        unsafe { pt.virt_unmap_one(va) }.unwrap();
+        assert_eq!(translate(pt, va), None);
+        assert_eq!(
+            translate(pt, va.offset(4096)),
+            Some((0x9000_1000, mapped(PteAttrs::R)))
+        );
+        assert!(matches!(
+            unsafe { pt.virt_unmap_one(va) },
+            Err(UnmapError::NotMapped)
+        ));
+
+        // and it can be mapped again
+        unsafe { pt.virt_map(pa(0x9000_0000), va, 4096, PteAttrs::R) }.unwrap();
+    }
+
+    #[test]
+    fn test_virt_alloc() {
+        let pt = new_pt(8);
+        // root table
+        assert_eq!(MockPhysMem::allocated_pages(), 1);
+
+        let va = VirtAddr(0x20_0000);
+        unsafe { pt.virt_alloc(va, 3 * 4096 - 1, PteAttrs::R | PteAttrs::W) }.unwrap();
+        // two more levels of table plus three pages
+        assert_eq!(MockPhysMem::allocated_pages(), 6);
+
+        for page in 0..3 {
+            let (addr, _) = translate(pt, va.offset(page * 4096)).unwrap();
+            assert!(addr >= MOCK_PHYS_BASE);
+            // the memory is usable
+            unsafe { PhysAddr::<MockPhysMem>::new(addr).as_u8_ptr().write(0xaa) };
+        }
+
+        let res = unsafe { pt.virt_alloc(VirtAddr(0x4000_0000), 4 * 4096, PteAttrs::R) };
+        assert!(matches!(res, Err(MapError::OOM)));
+    }
+
+    #[test]
+    fn test_free_user_half() {
+        let pt = new_pt(16);
+        let user = VirtAddr(0x20_0000);
+        let device = VirtAddr(0x1000_0000);
+        let kernel = VirtAddr(0xffff_ffc0_0000_0000);
+        unsafe {
+            pt.virt_alloc(user, 2 * 4096, PteAttrs::R | PteAttrs::W | PteAttrs::User)
+                .unwrap();
+            pt.virt_map(pa(device.0), device, 4096, PteAttrs::R | PteAttrs::W)
+                .unwrap();
+            pt.virt_alloc(kernel, 4096, PteAttrs::R).unwrap();
+        }
+        // root, three tables and two pages for the user half, then two tables
+        // and a page for the kernel half
+        assert_eq!(MockPhysMem::allocated_pages(), 9);
+
+        unsafe { pt.free_user_half() };
+        assert_eq!(MockPhysMem::allocated_pages(), 4);
+        assert_eq!(translate(pt, user), None);
+        assert_eq!(translate(pt, device), None);
+        assert!(translate(pt, kernel).is_some());
+    }
+
+    #[test]
+    fn test_iter_mappings() {
+        let pt = new_pt(16);
+        let kern = VirtAddr(0xffff_ffc0_0010_0000);
+        unsafe {
+            pt.virt_map(pa(0x8000_0000), VirtAddr(0x1000), 2 * 4096, PteAttrs::R)
+                .unwrap();
+            // physically discontiguous, so must be a new run
+            pt.virt_map(pa(0x8001_0000), VirtAddr(0x3000), 4096, PteAttrs::R)
+                .unwrap();
+            // different attrs, so must be a new run
+            pt.virt_map(pa(0x8001_1000), VirtAddr(0x4000), 4096, PteAttrs::W | PteAttrs::R)
+                .unwrap();
+            pt.virt_map_one(
+                pa(0x4000_0000),
+                VirtAddr(0x4000_0000),
+                PageSize::Page1g,
+                PteAttrs::R,
+            )
+            .unwrap();
+            pt.virt_map(pa(0x8020_0000), kern, 4096, PteAttrs::X | PteAttrs::R)
+                .unwrap();
+        }
+
+        let mapping = |virt: core::ops::Range<usize>, phys, size, attrs| Mapping {
+            virt: VirtAddr(virt.start)..VirtAddr(virt.end),
+            phys: pa(phys),
+            size,
+            attrs: mapped(attrs),
+        };
+        let mappings: Vec<_> = unsafe { pt.iter_mappings() }.collect();
+        assert_eq!(
+            mappings,
+            [
+                mapping(0x1000..0x3000, 0x8000_0000, PageSize::Page4k, PteAttrs::R),
+                mapping(0x3000..0x4000, 0x8001_0000, PageSize::Page4k, PteAttrs::R),
+                mapping(
+                    0x4000..0x5000,
+                    0x8001_1000,
+                    PageSize::Page4k,
+                    PteAttrs::R | PteAttrs::W
+                ),
+                mapping(
+                    0x4000_0000..0x8000_0000,
+                    0x4000_0000,
+                    PageSize::Page1g,
+                    PteAttrs::R
+                ),
+                mapping(
+                    kern.0..kern.0 + 0x1000,
+                    0x8020_0000,
+                    PageSize::Page4k,
+                    PteAttrs::R | PteAttrs::X
+                ),
+            ]
+        );
+
+        let dump = std::format!("{}", unsafe { pt.dump() });
+        assert!(
+            dump.contains(
+                "0x0000000040000000-0x0000000080000000      1G PUD -A----RV -> 0x0000000040000000"
+            ),
+            "{}",
+            dump
+        );
+        assert!(dump.contains("---[ kernel half ]---"), "{}", dump);
+    }
+
+    #[test]
+    fn test_sv48() {
+        let pt = new_pt_mode(16, PagingMode::Sv48);
+        // top of the lower half, which is out of range in Sv39
+        let low = VirtAddr(0x0000_7fff_ffff_f000);
+        let kern = VirtAddr(0xffff_8000_0000_0000);
+        unsafe { pt.virt_map(pa(0x8000_0000), low, 4096, PteAttrs::R) }.unwrap();
+        unsafe { pt.virt_map(pa(0x8000_1000), kern, 4096, PteAttrs::R | PteAttrs::W) }.unwrap();
+        let huge = VirtAddr(0x0000_0080_0000_0000);
+        unsafe { pt.virt_map_one(pa(0), huge, PageSize::Page512g, PteAttrs::R) }.unwrap();
+        // root plus three levels for each 4k page
+        assert_eq!(MockPhysMem::allocated_pages(), 7);
+
+        assert_eq!(
+            translate(pt, low.offset(0x10)),
+            Some((0x8000_0010, mapped(PteAttrs::R)))
+        );
+        assert_eq!(
+            translate(pt, kern),
+            Some((0x8000_1000, mapped(PteAttrs::R | PteAttrs::W)))
+        );
+        let res = unsafe { pt.resolve(huge) }.unwrap();
+        assert_eq!(res.last_level.unwrap().decompose().0, 0);
+        assert_eq!(res.parts[3], res.last_level);
+
+        let virts: Vec<_> = unsafe { pt.iter_mappings() }
+            .map(|m| (m.virt.start.0, m.size))
+            .collect();
+        assert_eq!(
+            virts,
+            [
+                (huge.0, PageSize::Page512g),
+                (low.0, PageSize::Page4k),
+                (kern.0, PageSize::Page4k),
+            ]
+        );
+        let dump = std::format!("{}", unsafe { pt.dump() });
+        assert!(dump.contains("Sv48 page table"), "{}", dump);
+        assert!(dump.contains("    512G P4D"), "{}", dump);
+    }
+
+    #[test]
+    fn test_accessed_dirty() {
+        let pt = new_pt(16);
+        let base = VirtAddr(0x10_0000);
+        unsafe {
+            pt.virt_map(pa(0x8000_0000), base, 4 * 4096, PteAttrs::R | PteAttrs::W)
+                .unwrap();
+            pt.virt_map(pa(0x8010_0000), base.offset(4 * 4096), 4096, PteAttrs::R)
+                .unwrap();
+        }
+        let page = |n: isize| (base.offset(n * 4096), PageSize::Page4k);
+
+        // everything starts out accessed
+        let touched: Vec<_> =
+            unsafe { pt.scan_and_clear_accessed(base.offset(4096)..base.offset(3 * 4096)) }
+                .collect();
+        assert_eq!(touched, [page(1), page(2)]);
+        let touched: Vec<_> =
+            unsafe { pt.scan_and_clear_accessed(VirtAddr(0)..VirtAddr(usize::MAX)) }.collect();
+        assert_eq!(touched, [page(0), page(3), page(4)]);
+        let touched: Vec<_> =
+            unsafe { pt.scan_and_clear_accessed(VirtAddr(0)..VirtAddr(usize::MAX)) }.collect();
+        assert_eq!(touched, []);
+
+        // pretend the hardware faulted on a write then a read
+        unsafe {
+            assert!(pt.fixup_accessed_dirty(base.offset(2 * 4096 + 8), AccessType::Write));
+            assert!(pt.fixup_accessed_dirty(base.offset(4 * 4096), AccessType::Read));
+            // already fixed up, so it would be a real fault
+            assert!(!pt.fixup_accessed_dirty(base.offset(4 * 4096), AccessType::Read));
+            // not permitted
+            assert!(!pt.fixup_accessed_dirty(base.offset(4 * 4096), AccessType::Write));
+            assert!(!pt.fixup_accessed_dirty(base, AccessType::Execute));
+            // not mapped
+            assert!(!pt.fixup_accessed_dirty(base.offset(-4096), AccessType::Read));
+        }
+        let touched: Vec<_> =
+            unsafe { pt.scan_and_clear_accessed(VirtAddr(0)..VirtAddr(usize::MAX)) }.collect();
+        assert_eq!(touched, [page(2), page(4)]);
+
+        // the writable pages start out dirty, page 2 has been dirtied again
+        let dirty: Vec<_> =
+            unsafe { pt.scan_and_clear_dirty(base..base.offset(5 * 4096)) }.collect();
+        assert_eq!(dirty, [page(0), page(1), page(2), page(3)]);
+        unsafe { pt.fixup_accessed_dirty(base.offset(2 * 4096), AccessType::Write) };
+        let dirty: Vec<_> =
+            unsafe { pt.scan_and_clear_dirty(base..base.offset(5 * 4096)) }.collect();
+        assert_eq!(dirty, [page(2)]);
+    }
+
+    #[test]
+    fn test_read_write_virt() {
+        let pt = new_pt(16);
+        let va = VirtAddr(0x10_0000);
+        let user_rw = PteAttrs::R | PteAttrs::W | PteAttrs::User;
+        unsafe {
+            pt.virt_alloc(va, 2 * 4096, user_rw).unwrap();
+            pt.virt_alloc(va.offset(2 * 4096), 4096, PteAttrs::R | PteAttrs::User)
+                .unwrap();
+            pt.virt_alloc(va.offset(3 * 4096), 4096, PteAttrs::R | PteAttrs::W)
+                .unwrap();
+        }
+
+        // crosses a page boundary, and the pages are not physically adjacent
+        // in general
+        let data: Vec<u8> = (0..64).collect();
+        let straddle = va.offset(4096 - 32);
+        unsafe { pt.write_virt(straddle, &data) }.unwrap();
+        let mut buf = [0u8; 64];
+        unsafe { pt.read_virt(straddle, &mut buf) }.unwrap();
+        assert_eq!(&buf[..], &data[..]);
+
+        let (phys, attrs) = unsafe { pt.translate(va.offset(4096)) }.unwrap();
+        assert_eq!(attrs, mapped(user_rw));
+        assert_eq!(unsafe { *phys.as_u8_ptr() }, 32);
+
+        let ro = va.offset(2 * 4096);
+        assert_eq!(
+            unsafe { pt.write_virt(ro.offset(-1), &[1, 2]) },
+            Err(AccessError::PermissionDenied(ro))
+        );
+        unsafe { pt.read_virt(ro, &mut buf) }.unwrap();
+        // kernel only
+        let kern = va.offset(3 * 4096);
+        assert_eq!(
+            unsafe { pt.read_virt(kern, &mut buf) },
+            Err(AccessError::PermissionDenied(kern))
+        );
+        // fails partway through
+        assert_eq!(
+            unsafe { pt.read_virt(kern.offset(-1), &mut buf) },
+            Err(AccessError::PermissionDenied(kern))
+        );
+        let unmapped = va.offset(4 * 4096);
+        assert_eq!(
+            unsafe { pt.read_virt(unmapped, &mut buf) },
+            Err(AccessError::NotMapped(unmapped))
+        );
+        assert_eq!(
+            unsafe { pt.read_virt(VirtAddr(usize::MAX), &mut buf) },
+            Err(AccessError::ArithOvf)
+        );
+    }
+
+    #[test]
+    fn test_non_canonical() {
+        let pt = new_pt(16);
+        let user_rw = PteAttrs::R | PteAttrs::W | PteAttrs::User;
+        let page = VirtAddr(0x1000);
+        let top = VirtAddr(0x3f_ffff_f000);
+        unsafe {
+            pt.virt_alloc(page, 4096, user_rw).unwrap();
+            pt.virt_alloc(top, 4096, user_rw).unwrap();
+        }
+        assert!(unsafe { pt.translate(page) }.is_some());
+
+        // the same low bits as `page`, which a walk of only the Sv39 levels
+        // would land on
+        let alias = VirtAddr(0x80_0000_1000);
+        let mut buf = [0u8; 16];
+        assert_eq!(unsafe { pt.translate(alias) }, None);
+        assert_eq!(
+            unsafe { pt.read_virt(alias, &mut buf) },
+            Err(AccessError::NonCanonical(alias))
+        );
+        assert_eq!(
+            unsafe { pt.write_virt(alias, &buf) },
+            Err(AccessError::NonCanonical(alias))
+        );
+        assert!(!unsafe { pt.fixup_accessed_dirty(alias, AccessType::Write) });
+
+        // starts canonical but runs off the end of the lower half
+        let end = VirtAddr(0x3f_ffff_fff8);
+        assert_eq!(
+            unsafe { pt.read_virt(end, &mut buf) },
+            Err(AccessError::NonCanonical(end))
+        );
+        unsafe { pt.read_virt(end, &mut buf[..8]) }.unwrap();
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Map { page: usize, frame: usize, writable: bool },
+        Unmap { page: usize },
+    }
+
+    fn mode() -> impl Strategy<Value = PagingMode> {
+        prop_oneof![
+            Just(PagingMode::Sv39),
+            Just(PagingMode::Sv48),
+            Just(PagingMode::Sv57),
+        ]
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        // a window of pages that straddles a 2M and a 1G boundary, to get
+        // several second and last level tables involved
+        let page = (0x3ff00usize..0x40100).prop_map(|p| p * 4096);
+        prop_oneof![
+            (page.clone(), 0usize..0x1000, any::<bool>()).prop_map(|(page, frame, writable)| {
+                Op::Map {
+                    page,
+                    frame: frame * 4096,
+                    writable,
+                }
+            }),
+            page.prop_map(|page| Op::Unmap { page }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_matches_model(mode in mode(), ops in proptest::collection::vec(op(), 1..64)) {
+            let pt = new_pt_mode(96, mode);
+            // virtual page -> (frame, attrs)
+            let mut model = BTreeMap::new();
+
+            for op in ops {
+                match op {
+                    Op::Map { page, frame, writable } => {
+                        let attrs = if writable { PteAttrs::R | PteAttrs::W } else { PteAttrs::R };
+                        let res = unsafe {
+                            pt.virt_map_one(pa(frame), VirtAddr(page), PageSize::Page4k, attrs)
+                        };
+                        if model.contains_key(&page) {
+                            prop_assert!(matches!(res, Err(MapError::AlreadyMapped)));
+                        } else {
+                            prop_assert!(res.is_ok());
+                            model.insert(page, (frame, mapped(attrs)));
+                        }
+                    }
+                    Op::Unmap { page } => {
+                        let res = unsafe { pt.virt_unmap_one(VirtAddr(page)) };
+                        prop_assert_eq!(res.is_ok(), model.remove(&page).is_some());
+                    }
+                }
+            }
+
+            for page in (0x3ff00usize..0x40100).map(|p| p * 4096) {
+                prop_assert_eq!(translate(pt, VirtAddr(page)), model.get(&page).copied());
+            }
+
+            let mapped_pages: usize = unsafe { pt.iter_mappings() }
+                .map(|m| (m.virt.end.0 - m.virt.start.0) / 4096)
+                .sum();
+            prop_assert_eq!(mapped_pages, model.len());
+        }
+    }
+
     #[test]
     fn test_canonicalize() {
         let addr = 0xff00_0010_1234_5789;
-        assert_eq!(VirtAddr(addr).canonicalize().0, 0x0000_0010_1234_5789);
+        assert_eq!(
+            VirtAddr(addr).canonicalize(PagingMode::Sv39).0,
+            0x0000_0010_1234_5789
+        );
+        assert_eq!(
+            VirtAddr(addr).canonicalize(PagingMode::Sv48).0,
+            0x0000_0010_1234_5789
+        );
+        assert_eq!(
+            VirtAddr(addr).canonicalize(PagingMode::Sv57).0,
+            0x0100_0010_1234_5789
+        );
+
+        assert!(VirtAddr(0xffff_ffc0_0000_0000).is_canonical(PagingMode::Sv39));
+        assert!(!VirtAddr(0xffff_ff80_0000_0000).is_canonical(PagingMode::Sv39));
+        assert!(VirtAddr(0xffff_ff80_0000_0000).is_canonical(PagingMode::Sv48));
+        assert!(!VirtAddr(0x0000_8000_0000_0000).is_canonical(PagingMode::Sv48));
+        assert!(VirtAddr(0x0000_8000_0000_0000).is_canonical(PagingMode::Sv57));
     }
 
     #[test]
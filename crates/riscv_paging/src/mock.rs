@@ -0,0 +1,104 @@
+//! A fake physical memory backed by host memory, for testing the page table
+//! code with `cargo test`.
+//!
+//! Each thread gets its own physical memory, so tests running in parallel do
+//! not step on each other.
+
+use std::boxed::Box;
+use std::cell::RefCell;
+use std::vec::Vec;
+
+use crate::{Addr, PhysAccess, PhysAddr, PAGE_SIZE};
+
+/// Physical address of the first page of mock memory. This matches where RAM
+/// starts on the QEMU `virt` machine.
+pub const MOCK_PHYS_BASE: usize = 0x8000_0000;
+
+#[repr(C, align(4096))]
+#[derive(Clone, Copy)]
+struct Page([u8; PAGE_SIZE as usize]);
+
+struct MockMemory {
+    pages: Box<[Page]>,
+    /// Indexes of the free pages. Allocation pops off the end.
+    free: Vec<usize>,
+}
+
+std::thread_local! {
+    static MEMORY: RefCell<Option<MockMemory>> = RefCell::new(None);
+}
+
+/// [`PhysAccess`] implementation backed by a host allocation.
+///
+/// Call [`MockPhysMem::init`] on the thread before using it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MockPhysMem;
+
+impl MockPhysMem {
+    /// Replaces the physical memory of the calling thread with `pages` zeroed
+    /// pages, all of which are free. Any pointers into the old memory become
+    /// dangling.
+    pub fn init(pages: usize) {
+        let memory = MockMemory {
+            pages: std::vec![Page([0u8; PAGE_SIZE as usize]); pages].into_boxed_slice(),
+            // hand out pages in ascending order, which makes dumps easier to read
+            free: (0..pages).rev().collect(),
+        };
+        MEMORY.with(|m| *m.borrow_mut() = Some(memory));
+    }
+
+    /// Number of pages that are currently allocated
+    pub fn allocated_pages() -> usize {
+        Self::with(|m| m.pages.len() - m.free.len())
+    }
+
+    fn with<R>(f: impl FnOnce(&mut MockMemory) -> R) -> R {
+        MEMORY.with(|m| {
+            f(m.borrow_mut()
+                .as_mut()
+                .expect("MockPhysMem::init was not called on this thread"))
+        })
+    }
+
+    /// Gets the index of the page containing `addr`, panicking if it is not in
+    /// mock memory.
+    fn page_index(m: &MockMemory, addr: usize) -> usize {
+        let idx = addr
+            .checked_sub(MOCK_PHYS_BASE)
+            .map(|offs| offs / PAGE_SIZE as usize)
+            .filter(|&idx| idx < m.pages.len());
+        match idx {
+            Some(idx) => idx,
+            None => panic!("physical address {:#x} is outside mock memory", addr),
+        }
+    }
+}
+
+impl PhysAccess for MockPhysMem {
+    unsafe fn address<T>(ptr: PhysAddr<Self>) -> *mut T {
+        Self::with(|m| {
+            let idx = Self::page_index(m, ptr.get());
+            let page_offs = ptr.get() % PAGE_SIZE as usize;
+            (m.pages[idx].0.as_mut_ptr()).add(page_offs) as *mut T
+        })
+    }
+
+    unsafe fn alloc() -> Option<PhysAddr<Self>> {
+        Self::with(|m| {
+            let idx = m.free.pop()?;
+            Some(PhysAddr::new(MOCK_PHYS_BASE + idx * PAGE_SIZE as usize))
+        })
+    }
+
+    unsafe fn free(addr: PhysAddr<Self>) {
+        assert!(
+            addr.get() % PAGE_SIZE as usize == 0,
+            "Freed page address must be page aligned"
+        );
+        Self::with(|m| {
+            let idx = Self::page_index(m, addr.get());
+            assert!(!m.free.contains(&idx), "double free of {:?}", addr);
+            m.free.push(idx);
+        })
+    }
+}
@@ -5,15 +5,17 @@
 //! This module also includes the exit to userspace.
 
 use core::convert::TryInto;
-use mu_shared::{KernResult, SyscallNum};
+use mu_shared::{
+    KernErr, KernResult, ProcessHandle, SyscallNum, EXIT_EXCEPTION, EXIT_PAGE_FAULT, MAX_SPAWN_ARGS,
+};
 use riscv::arch::{
-    clear_stip, get_scause, get_sie, get_sip, get_sstatus, machinecall, set_sie, set_sstatus,
-    set_stvec, ExceptionType, SIE_STIE,
+    get_scause, get_sie, get_sip, get_sstatus, get_stval, machinecall, set_sie, set_sstatus,
+    set_stvec, ExceptionType, Satp, SIE_STIE,
 };
-use riscv::paging::Addr;
+use riscv::paging::{AccessType, Addr, VirtAddr};
 
 #[allow(dead_code)]
-mod Reg {
+pub(crate) mod Reg {
     pub const A0: usize = 9; // x10
     pub const A1: usize = 10; // x11
     pub const A2: usize = 11; // x12
@@ -24,7 +26,10 @@ mod Reg {
     pub const A7: usize = 16; // x17
 }
 
+use crate::process;
 use crate::tframe::{TrapFrame, TrapHandler, TRAP_FRAMES};
+use crate::thread;
+use crate::timer;
 
 const _ASSERT_K_ENTRY_IS_RIGHT_TYPE: TrapHandler = k_entry;
 
@@ -53,6 +58,49 @@ unsafe fn sc_LogMessage(len: usize, message: *const u8) -> KernResult<()> {
     Ok(())
 }
 
+/// Copies `len` bytes from userspace at `v_user` into the start of `into`,
+/// after checking the user could read them, and returns the part of `into`
+/// that was written
+unsafe fn read_user(into: &mut [u8], v_user: *const u8, len: usize) -> KernResult<&[u8]> {
+    let into = into.get_mut(..len).ok_or(KernErr::TooLong)?;
+    let pt = Satp::current().as_pagetable().ok_or(KernErr::BadAddress)?;
+    pt.read_virt(VirtAddr(v_user as usize), into)
+        .map_err(|_| KernErr::BadAddress)?;
+    Ok(into)
+}
+
+/// `SleepUntil(deadline: u64)`
+unsafe fn sc_SleepUntil(tf: &TrapFrame, deadline: u64) -> KernResult<()> {
+    let deadline = timer::nanos_to_ticks(deadline);
+    if deadline <= timer::now() {
+        return Ok(());
+    }
+    thread::sleep_until(tf, deadline)
+}
+
+/// `DebugDumpPageTable()`
+unsafe fn sc_DebugDumpPageTable() -> KernResult<()> {
+    if let Some(pt) = Satp::current().as_pagetable() {
+        riscv::println!("{}", pt.dump());
+    }
+    Ok(())
+}
+
+/// `ProcessSpawn(name_len: usize, name: *const u8, args_len: usize,
+/// args: *const u8) -> ProcessHandle`
+unsafe fn sc_ProcessSpawn(
+    name_len: usize,
+    name: *const u8,
+    args_len: usize,
+    args: *const u8,
+) -> KernResult<ProcessHandle> {
+    let mut name_buf = [0; 255];
+    let name = core::str::from_utf8(read_user(&mut name_buf, name, name_len)?)?;
+    let mut args_buf = [0; MAX_SPAWN_ARGS];
+    let args = read_user(&mut args_buf, args, args_len)?;
+    process::spawn(name, args)
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn k_entry(tf: *mut TrapFrame) -> ! {
     let tf = &mut *tf;
@@ -68,12 +116,49 @@ pub unsafe extern "C" fn k_entry(tf: *mut TrapFrame) -> ! {
 
     match scause {
         ExceptionType::EnvCallU => {}
-        // FIXME: This should go to sched instead
         ExceptionType::STimer => {
-            clear_stip();
-            enter_userspace(tf);
+            // this also clears the interrupt
+            timer::tick();
+            thread::reschedule(tf);
+        }
+        ExceptionType::InsnPageFault
+        | ExceptionType::LoadPageFault
+        | ExceptionType::StoreAmoPageFault => {
+            let access = match scause {
+                ExceptionType::InsnPageFault => AccessType::Execute,
+                ExceptionType::LoadPageFault => AccessType::Read,
+                _ => AccessType::Write,
+            };
+            let va = VirtAddr(get_stval());
+            // the hardware may not set A/D itself, in which case we have to,
+            // then retry the faulting instruction
+            if let Some(pt) = Satp::current().as_pagetable() {
+                if pt.fixup_accessed_dirty(va, access) {
+                    enter_userspace(tf);
+                }
+            }
+            log::warn!(
+                "page fault in userspace: {:?} of {:?} at pc {:?}, killing the process",
+                access,
+                va,
+                tf.user_pc
+            );
+            process::exit(tf, EXIT_PAGE_FAULT)
+        }
+        // these are not anything the process did
+        ExceptionType::SSoftware
+        | ExceptionType::MSoftware
+        | ExceptionType::MTimer
+        | ExceptionType::SExternal
+        | ExceptionType::MExternal => panic!("unexpected interrupt {:?}", get_scause()),
+        e => {
+            log::warn!(
+                "exception in userspace {:?} at pc {:?}, killing the process",
+                e,
+                tf.user_pc
+            );
+            process::exit(tf, EXIT_EXCEPTION)
         }
-        e => panic!("exceptiowo in userspace {:?}", e),
     }
 
     log::info!("user pc: {:?}", tf.user_pc);
@@ -81,18 +166,23 @@ pub unsafe extern "C" fn k_entry(tf: *mut TrapFrame) -> ! {
 
     let arg0 = tf.regs[Reg::A1];
     let arg1 = tf.regs[Reg::A2];
+    let arg2 = tf.regs[Reg::A3];
+    let arg3 = tf.regs[Reg::A4];
 
     let res = match tf.regs[Reg::A0].try_into() {
-        Ok(SyscallNum::LogMessage) => sc_LogMessage(arg0, arg1 as *const _),
+        Ok(SyscallNum::LogMessage) => sc_LogMessage(arg0, arg1 as *const _).map(|()| 0),
+        Ok(SyscallNum::DebugDumpPageTable) => sc_DebugDumpPageTable().map(|()| 0),
+        Ok(SyscallNum::ProcessSpawn) => {
+            sc_ProcessSpawn(arg0, arg1 as *const _, arg2, arg3 as *const _)
+        }
+        Ok(SyscallNum::ProcessExit) => process::exit(tf, arg0 as isize),
+        Ok(SyscallNum::ProcessWait) => process::wait(tf, arg0).map(|code| code as usize),
+        Ok(SyscallNum::ClockGetMonotonic) => Ok(timer::ticks_to_nanos(timer::now()) as usize),
+        Ok(SyscallNum::SleepUntil) => sc_SleepUntil(tf, arg0 as u64).map(|()| 0),
         Err(v) => panic!("unknown syscall {}", v),
     };
 
-    tf.regs[Reg::A0] = matches!(res, Ok(_)) as usize;
-    tf.regs[Reg::A1] = match res {
-        Ok(_) => 0,
-        Err(v) => v as usize,
-    };
-
+    tf.set_syscall_result(res);
     enter_userspace(tf);
 }
 
@@ -0,0 +1,353 @@
+//! Loading programs out of the initrd into new address spaces
+
+use core::ops::Range;
+use core::slice;
+
+use elf_loader::stack::{ProcessStack, StackError};
+use elf_loader::{AddressSpace, Elf, LoadError, LoadedImage, TlsTemplate};
+use microflop::{Decompressor, EntryKind, Microflop};
+use mu_shared::{KernErr, KernResult};
+use riscv::addr::{self, MemoryMap, UserLayout};
+use riscv::arch::{Mutex, PhysAddr, PhysMem, Satp};
+use riscv::paging::{
+    AccessError, Addr, MapError, PageSize, PageTable, PhysAccess, PteAttrs, VirtAddr, PAGE_MASK,
+    PAGE_SIZE, PT_ENTRIES,
+};
+use riscv::rand::Rng;
+
+/// Size of the main thread's stack
+const STACK_LEN: usize = 0x8000;
+
+/// Space for a process's arguments and auxiliary vector
+const STACK_DATA: usize = 4096;
+
+/// The initrd. This is locked for the whole of loading a program, since that
+/// uses the scratch window.
+static INITRD: Mutex<&'static [u8]> = Mutex::new(&[]);
+
+/// Remembers where the initrd is and sets up the scratch window. Must be called
+/// before any address spaces are made, so they all share the scratch window's
+/// page tables.
+pub unsafe fn init(initrd: &'static [u8]) {
+    *INITRD.lock() = initrd;
+
+    let pt = current_pt();
+    let va = VirtAddr(addr::KERNEL_SCRATCH.start);
+    pt.virt_alloc_one(va, PteAttrs::R | PteAttrs::W)
+        .expect("failed to make scratch window page tables");
+    unmap_free(pt, va, PAGE_SIZE as usize);
+}
+
+fn current_pt() -> PageTable<PhysMem> {
+    // safety: the kernel always runs with paging on, in a valid page table
+    unsafe { Satp::current().as_pagetable() }.expect("kernel is running without paging")
+}
+
+fn map_err(e: MapError) -> KernErr {
+    match e {
+        MapError::OOM => KernErr::NoMemory,
+        _ => KernErr::BadExecutable,
+    }
+}
+
+fn access_err(_: AccessError) -> KernErr {
+    KernErr::BadExecutable
+}
+
+fn stack_err(e: StackError) -> KernErr {
+    match e {
+        StackError::Overflow | StackError::TooManyArgs => KernErr::TooLong,
+        StackError::Access(e) => access_err(e),
+    }
+}
+
+/// Maps `len` bytes of zeroed memory at `va` in `pt`
+unsafe fn alloc_zeroed(
+    pt: PageTable<PhysMem>,
+    va: VirtAddr,
+    len: usize,
+    attrs: PteAttrs,
+) -> KernResult<()> {
+    for offs in (0..len).step_by(PAGE_SIZE as usize) {
+        let page = PhysMem::alloc().ok_or(KernErr::NoMemory)?;
+        page.as_u8_ptr().write_bytes(0, PAGE_SIZE as usize);
+        if let Err(e) = pt.virt_map_one(page, VirtAddr(va.get() + offs), PageSize::Page4k, attrs) {
+            PhysMem::free(page);
+            return Err(map_err(e));
+        }
+    }
+    Ok(())
+}
+
+/// Unmaps the `len` bytes at `va` in `pt`, freeing the pages under them
+unsafe fn unmap_free(pt: PageTable<PhysMem>, va: VirtAddr, len: usize) {
+    for offs in (0..len).step_by(PAGE_SIZE as usize) {
+        let page = VirtAddr(va.get() + offs);
+        if let Some((phys, _)) = pt.translate(page) {
+            pt.virt_unmap_one(page)
+                .expect("page we just found is not mapped");
+            PhysMem::free(phys);
+        }
+    }
+}
+
+/// Finds the file `name` in the initrd and calls `f` with its contents.
+/// Compressed files are inflated into the scratch window, which is freed
+/// again afterwards.
+pub fn with_initrd_file<T>(name: &str, f: impl FnOnce(&[u8]) -> KernResult<T>) -> KernResult<T> {
+    let region = INITRD.lock();
+    let initrd = Microflop::new(*region).map_err(|_| KernErr::NotFound)?;
+    let entry = match initrd.entry(name) {
+        Ok(Some(entry)) if entry.kind == EntryKind::File => entry,
+        _ => return Err(KernErr::NotFound),
+    };
+    if let Some(contents) = entry.uncompressed() {
+        return f(contents);
+    }
+
+    let scratch = addr::KERNEL_SCRATCH;
+    if entry.size > scratch.end - scratch.start {
+        return Err(KernErr::NoMemory);
+    }
+    let pt = current_pt();
+    let va = VirtAddr(scratch.start);
+    let len = (entry.size + PAGE_MASK) & !PAGE_MASK;
+    // safety: nothing else uses the scratch window while we hold the lock
+    unsafe {
+        let res = alloc_zeroed(pt, va, len, PteAttrs::R | PteAttrs::W).and_then(|()| {
+            let out = slice::from_raw_parts_mut(va.as_mut_ptr::<u8>(), len);
+            let mut decompressor = Decompressor::new();
+            let contents = entry
+                .decompress_into(&mut decompressor, out)
+                .map_err(|_| KernErr::BadExecutable)?;
+            f(contents)
+        });
+        unmap_free(pt, va, len);
+        res
+    }
+}
+
+/// A new user address space being set up
+#[derive(Clone, Copy)]
+pub struct UserSpace {
+    pub pt: PageTable<PhysMem>,
+    /// Where to put position independent images
+    image_base: VirtAddr,
+    /// One past the last user address
+    user_end: VirtAddr,
+}
+
+impl UserSpace {
+    /// Makes an address space containing just the kernel. The kernel half
+    /// of the root table is copied from the current one, so the page tables
+    /// under it are shared.
+    pub unsafe fn new(layout: &UserLayout) -> KernResult<UserSpace> {
+        let current = current_pt();
+        let mode = current.mode();
+        let pt = PageTable::<PhysMem>::alloc(mode).ok_or(KernErr::NoMemory)?;
+        for idx in PT_ENTRIES / 2..PT_ENTRIES {
+            pt.entry_ptr(idx as u16)
+                .write_volatile(current.entry(idx as u16));
+        }
+        let space = UserSpace {
+            pt,
+            image_base: layout.image_base,
+            user_end: MemoryMap::for_mode(mode).userspace_stack_top,
+        };
+
+        // the kernel uses these at their physical addresses
+        let devices = [
+            (addr::UART0, addr::UART0LEN),
+            (addr::CLINT, addr::CLINT_LEN),
+        ];
+        for &(base, len) in devices.iter() {
+            let res = pt.virt_map(
+                PhysAddr::new(base),
+                VirtAddr(base),
+                len,
+                PteAttrs::R | PteAttrs::W,
+            );
+            if let Err(e) = res {
+                space.destroy();
+                return Err(map_err(e));
+            }
+        }
+        Ok(space)
+    }
+
+    /// Frees the address space and everything in its user half
+    pub unsafe fn destroy(self) {
+        self.pt.free_user_half();
+        PhysMem::free(self.pt.get_base());
+    }
+
+    fn check_user(&self, virt: &Range<VirtAddr>) -> KernResult<()> {
+        if virt.start > virt.end || virt.end > self.user_end {
+            return Err(KernErr::BadExecutable);
+        }
+        Ok(())
+    }
+}
+
+impl AddressSpace for UserSpace {
+    type Error = KernErr;
+
+    fn choose_base(&mut self, _link_span: Range<VirtAddr>) -> KernResult<VirtAddr> {
+        Ok(self.image_base)
+    }
+
+    fn map_zeroed(&mut self, virt: Range<VirtAddr>, attrs: PteAttrs) -> KernResult<()> {
+        // the image must not get into the kernel half, which is shared
+        self.check_user(&virt)?;
+        let len = virt.end.get() - virt.start.get();
+        unsafe { alloc_zeroed(self.pt, virt.start, len, attrs) }
+    }
+
+    fn write(&mut self, va: VirtAddr, data: &[u8]) -> KernResult<()> {
+        let end = va
+            .get()
+            .checked_add(data.len())
+            .ok_or(KernErr::BadExecutable)?;
+        self.check_user(&(va..VirtAddr(end)))?;
+        let mut done = 0;
+        while done < data.len() {
+            let here = VirtAddr(va.get() + done);
+            // write_virt would refuse read only pages, so go through the
+            // mapping of physical memory
+            let (phys, attrs) = unsafe { self.pt.translate(here) }.ok_or(KernErr::BadExecutable)?;
+            if !attrs.contains(PteAttrs::User) {
+                return Err(KernErr::BadExecutable);
+            }
+            let chunk = (PAGE_SIZE as usize - (here.get() & PAGE_MASK)).min(data.len() - done);
+            unsafe {
+                phys.as_u8_ptr()
+                    .copy_from_nonoverlapping(data[done..].as_ptr(), chunk)
+            };
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, va: VirtAddr, buf: &mut [u8]) -> KernResult<()> {
+        let end = va
+            .get()
+            .checked_add(buf.len())
+            .ok_or(KernErr::BadExecutable)?;
+        self.check_user(&(va..VirtAddr(end)))?;
+        unsafe { self.pt.read_virt(va, buf) }.map_err(|_| KernErr::BadExecutable)
+    }
+}
+
+/// A program loaded into a new address space, ready to start
+pub struct LoadedProgram {
+    pub space: UserSpace,
+    pub entry: VirtAddr,
+    pub sp: VirtAddr,
+    /// Thread pointer for the main thread, or zero if it has no TLS
+    pub tp: VirtAddr,
+}
+
+/// Loads the program `name` from the initrd into a new address space, with
+/// the arguments after its name in `args`, each terminated by a nul byte
+pub unsafe fn load(name: &str, args: &[u8], rng: &mut Rng) -> KernResult<LoadedProgram> {
+    let mode = current_pt().mode();
+    let layout = UserLayout::randomized(&MemoryMap::for_mode(mode), rng);
+    with_initrd_file(name, |image| {
+        let elf = Elf::parse(image).map_err(|_| KernErr::BadExecutable)?;
+        let mut space = UserSpace::new(&layout)?;
+        match populate(&mut space, &elf, &layout, name, args, rng) {
+            Ok((entry, sp, tp)) => Ok(LoadedProgram {
+                space,
+                entry,
+                sp,
+                tp,
+            }),
+            Err(e) => {
+                space.destroy();
+                Err(e)
+            }
+        }
+    })
+}
+
+/// Loads `elf` into `space` and sets up its stack and thread local storage,
+/// returning the entry point, stack pointer and thread pointer
+unsafe fn populate(
+    space: &mut UserSpace,
+    elf: &Elf<'_>,
+    layout: &UserLayout,
+    name: &str,
+    args: &[u8],
+    rng: &mut Rng,
+) -> KernResult<(VirtAddr, VirtAddr, VirtAddr)> {
+    let loaded = elf.load(space, PteAttrs::User).map_err(|e| match e {
+        LoadError::Elf(_) => KernErr::BadExecutable,
+        LoadError::AddressSpace(e) => e,
+    })?;
+
+    let stack_top = layout.stack_top;
+    let stack_bottom = VirtAddr(stack_top.get() - STACK_LEN);
+    alloc_zeroed(
+        space.pt,
+        stack_bottom,
+        STACK_LEN,
+        PteAttrs::R | PteAttrs::W | PteAttrs::User,
+    )?;
+    let sp = build_stack(space.pt, &loaded, stack_top, name, args, rng)?;
+
+    // leave a guard page between the stack and the TLS block
+    let tp = match &loaded.tls {
+        Some(tls) => alloc_tls(
+            space.pt,
+            tls,
+            VirtAddr(stack_bottom.get() - PAGE_SIZE as usize),
+        )?,
+        None => VirtAddr(0),
+    };
+    Ok((loaded.entry, sp, tp))
+}
+
+/// Allocates and initializes the thread local storage block for a main
+/// thread, ending at or below `below`, and returns the thread pointer
+unsafe fn alloc_tls(
+    pt: PageTable<PhysMem>,
+    tls: &TlsTemplate,
+    below: VirtAddr,
+) -> KernResult<VirtAddr> {
+    let (begin, len) = tls.block_below(below).ok_or(KernErr::BadExecutable)?;
+    alloc_zeroed(pt, begin, len, PteAttrs::R | PteAttrs::W | PteAttrs::User)?;
+    tls.init_block(pt, begin).map_err(access_err)?;
+    Ok(begin)
+}
+
+/// Builds the initial stack below `stack_top` in `pt` and returns the stack
+/// pointer to start with. `argv[0]` is `name` and the rest come from `args`.
+/// Unlike init, spawned programs get no environment.
+unsafe fn build_stack(
+    pt: PageTable<PhysMem>,
+    loaded: &LoadedImage,
+    stack_top: VirtAddr,
+    name: &str,
+    args: &[u8],
+    rng: &mut Rng,
+) -> KernResult<VirtAddr> {
+    let mut buf = [0u8; STACK_DATA];
+    let mut random = [0u8; 16];
+    random[..8].copy_from_slice(&rng.next_u64().to_le_bytes());
+    random[8..].copy_from_slice(&rng.next_u64().to_le_bytes());
+    let mut stack =
+        ProcessStack::new(&mut buf, stack_top, loaded, random, None).map_err(stack_err)?;
+
+    stack.push_arg(name.as_bytes()).map_err(stack_err)?;
+    let args = match args.split_last() {
+        // drop the last terminator so splitting does not give an empty
+        // argument at the end
+        Some((0, args)) => Some(args),
+        Some(_) => return Err(KernErr::InvalidArgument),
+        None => None,
+    };
+    for arg in args.into_iter().flat_map(|args| args.split(|&b| b == 0)) {
+        stack.push_arg(arg).map_err(stack_err)?;
+    }
+    stack.write_to(pt).map_err(stack_err)
+}
@@ -6,15 +6,17 @@
 use core::sync::atomic::Ordering;
 
 mod exc;
+mod loader;
+mod process;
 mod tframe;
 mod thread;
+mod timer;
 
-use arch::Satp;
-use exc::{enter_userspace, k_entry};
+use arch::{PhysMem, Satp};
 use log::info;
 use riscv::arch;
-use riscv::paging::Addr;
-use riscv::{arch::freeze_hart, KernelEntryParams};
+use riscv::paging::{Addr, VirtAddr};
+use riscv::KernelEntryParams;
 use tframe::TrapFrame;
 
 /// Checks at compile time that the kern_main conforms to the ABI expected by
@@ -29,47 +31,21 @@ pub extern "C" fn kern_main(params: &KernelEntryParams) -> ! {
     riscv::print::init();
     riscv::NUM_CPUS.store(params.num_cpus, Ordering::Relaxed);
     info!("Hello world from the kernel on cpu {}!", params.core_id);
+    info!(
+        "init entry {:?}, stack {:?}",
+        params.init_entrypoint, params.init_sp
+    );
 
-    let tf = TrapFrame {
-        hart_id: arch::core_id(),
-        kernel_sp: params.stack_pointer.get() as *mut _,
-        new_satp: Satp::current(),
-        regs: [
-            /* ra */ 0,
-            /* sp */ params.init_sp.get(),
-            /* gp */ 0,
-            /* tp */ 0,
-            /* t0 */ 0,
-            /* t1 */ 0,
-            /* t2 */ 0,
-            /* s0 */ 0,
-            /* s1 */ 0,
-            /* a0 */ 0,
-            /* a1 */ 0,
-            /* a2 */ 0,
-            /* a3 */ 0,
-            /* a4 */ 0,
-            /* a5 */ 0,
-            /* a6 */ 0,
-            /* a7 */ 0,
-            /* s2 */ 0,
-            /* s3 */ 0,
-            /* s4 */ 0,
-            /* s5 */ 0,
-            /* s6 */ 0,
-            /* s7 */ 0,
-            /* s8 */ 0,
-            /* s9 */ 0,
-            /* s10 */ 0,
-            /* s11 */ 0,
-            /* t3 */ 0,
-            /* t4 */ 0,
-            /* t5 */ 0,
-            /* t6 */ 0,
-        ],
-        user_pc: params.init_entrypoint,
-        target_fn: k_entry,
-    };
-    unsafe { enter_userspace(&tf) };
-    freeze_hart()
+    // shoo has its own copy of the allocator, so take over its free pages
+    unsafe {
+        PhysMem::adopt_free_list(params.free_list);
+        process::init(params);
+    }
+    timer::init(params.timebase_freq);
+    process::adopt_init(params.init_entrypoint, params.init_sp, params.init_tp);
+
+    // there is nothing running yet, so this only supplies the kernel stack
+    let mut tf = TrapFrame::new_user(VirtAddr(0), VirtAddr(0), VirtAddr(0), Satp::current());
+    tf.kernel_sp = params.stack_pointer.get() as *mut _;
+    unsafe { thread::reschedule(&tf) }
 }
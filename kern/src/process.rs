@@ -0,0 +1,191 @@
+//! Processes, which are address spaces with threads running in them
+
+use core::slice;
+
+use mu_shared::{KernErr, KernResult, ProcessHandle};
+use riscv::addr::{MemoryMap, UserLayout, MAX_PROCESSES};
+use riscv::arch::{Mutex, PhysMem, Satp};
+use riscv::paging::{PageTable, PhysAccess, VirtAddr};
+use riscv::rand::Rng;
+use riscv::KernelEntryParams;
+
+use crate::loader::{self, LoadedProgram, UserSpace};
+use crate::tframe::TrapFrame;
+use crate::thread;
+
+#[derive(Clone, Copy)]
+enum State {
+    /// Running in the address space with this root page table
+    Running(PageTable<PhysMem>),
+    /// Exited with this code, and kept until something waits for it
+    Exited(isize),
+}
+
+struct Process {
+    handle: ProcessHandle,
+    state: State,
+}
+
+struct Processes {
+    slots: [Option<Process>; MAX_PROCESSES],
+    /// Handle for the next process. Handles are never reused, so a stale one
+    /// cannot refer to a different process.
+    next_handle: ProcessHandle,
+}
+
+impl Processes {
+    fn insert(&mut self, pt: PageTable<PhysMem>) -> KernResult<ProcessHandle> {
+        let handle = self.next_handle;
+        let slot = self
+            .slots
+            .iter_mut()
+            .find(|s| s.is_none())
+            .ok_or(KernErr::NoMemory)?;
+        self.next_handle += 1;
+        *slot = Some(Process {
+            handle,
+            state: State::Running(pt),
+        });
+        Ok(handle)
+    }
+
+    fn get_mut(&mut self, handle: ProcessHandle) -> Option<&mut Process> {
+        self.slots.iter_mut().flatten().find(|p| p.handle == handle)
+    }
+
+    fn remove(&mut self, handle: ProcessHandle) {
+        for slot in self.slots.iter_mut() {
+            if matches!(slot, Some(p) if p.handle == handle) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+static PROCESSES: Mutex<Processes> = Mutex::new(Processes {
+    slots: [const { Option::<Process>::None }; MAX_PROCESSES],
+    next_handle: 1,
+});
+
+/// Randomness for the layout of new processes
+static RNG: Mutex<Rng> = Mutex::new(Rng::new(0));
+
+/// Address space with nothing in the user half, for the kernel to run in
+/// while it frees the address space of an exiting process
+static KERNEL_SATP: Mutex<Satp> = Mutex::new(Satp::DISABLED);
+
+/// Sets up for starting processes, with what shoo gave us
+pub unsafe fn init(params: &KernelEntryParams) {
+    *RNG.lock() = Rng::new(params.rng_seed);
+    loader::init(slice::from_raw_parts(
+        params.initrd.as_mut_ptr::<u8>(),
+        params.initrd_len,
+    ));
+
+    let mode = Satp::current()
+        .mode()
+        .paging_mode()
+        .expect("kernel is running without paging");
+    let space = UserSpace::new(&UserLayout::fixed(&MemoryMap::for_mode(mode)))
+        .expect("failed to make the kernel's address space");
+    *KERNEL_SATP.lock() = Satp::new(&space.pt, 0);
+}
+
+/// Makes a trap frame to start a thread at `pc` in the address space `pt`
+fn thread_frame(pt: &PageTable<PhysMem>, pc: VirtAddr, sp: VirtAddr, tp: VirtAddr) -> TrapFrame {
+    TrapFrame::new_user(pc, sp, tp, Satp::new(pt, 0))
+}
+
+/// Makes the process for init, which shoo loaded into the current address
+/// space, with its main thread starting at `pc`
+pub fn adopt_init(pc: VirtAddr, sp: VirtAddr, tp: VirtAddr) -> ProcessHandle {
+    // safety: the kernel always runs with paging on, in a valid page table
+    let pt = unsafe { Satp::current().as_pagetable() }.expect("kernel is running without paging");
+    let mut processes = PROCESSES.lock();
+    let handle = processes.insert(pt).expect("no room for init");
+    thread::spawn(handle, thread_frame(&pt, pc, sp, tp)).expect("no room for init's thread");
+    handle
+}
+
+/// Starts the program `name` from the initrd, with the arguments after its
+/// name in `args`, each terminated by a nul byte
+pub unsafe fn spawn(name: &str, args: &[u8]) -> KernResult<ProcessHandle> {
+    // don't hold the lock while loading
+    let mut rng = Rng::new(RNG.lock().next_u64());
+    let LoadedProgram {
+        space,
+        entry,
+        sp,
+        tp,
+    } = loader::load(name, args, &mut rng)?;
+
+    let mut processes = PROCESSES.lock();
+    let handle = match processes.insert(space.pt) {
+        Ok(handle) => handle,
+        Err(e) => {
+            space.destroy();
+            return Err(e);
+        }
+    };
+    if let Err(e) = thread::spawn(handle, thread_frame(&space.pt, entry, sp, tp)) {
+        processes.remove(handle);
+        space.destroy();
+        return Err(e);
+    }
+    log::info!("spawned {} as process {}", name, handle);
+    Ok(handle)
+}
+
+/// Exits the current process with `code`, freeing its threads and address
+/// space, and runs something else. `tf` is the trap frame the kernel was
+/// entered with.
+pub unsafe fn exit(tf: &TrapFrame, code: isize) -> ! {
+    let handle = thread::current_process().expect("exit with no process running");
+    {
+        let mut processes = PROCESSES.lock();
+        let process = processes.get_mut(handle).expect("running process is gone");
+        let pt = match process.state {
+            State::Running(pt) => pt,
+            State::Exited(_) => unreachable!("exited process {} is running", handle),
+        };
+        process.state = State::Exited(code);
+        // if something was waiting it has the code now, otherwise keep it
+        // for whatever waits later
+        if thread::exit_process(handle, code) {
+            processes.remove(handle);
+        }
+
+        // the address space can't be freed while we are running in it
+        KERNEL_SATP.lock().activate();
+        pt.free_user_half();
+        PhysMem::free(pt.get_base());
+    }
+    log::info!("process {} exited with code {}", handle, code);
+    thread::switch(tf)
+}
+
+/// Waits for the process `handle` to exit and gets its exit code. If it has
+/// not exited yet, this blocks the current thread, which gets the code when
+/// it is rescheduled, and runs something else. `tf` is the trap frame the
+/// kernel was entered with.
+pub unsafe fn wait(tf: &TrapFrame, handle: ProcessHandle) -> KernResult<isize> {
+    let mut processes = PROCESSES.lock();
+    if thread::current_process() == Some(handle) {
+        // that would never return
+        return Err(KernErr::InvalidArgument);
+    }
+    let process = processes.get_mut(handle).ok_or(KernErr::NotFound)?;
+    match process.state {
+        State::Exited(code) => {
+            processes.remove(handle);
+            Ok(code)
+        }
+        State::Running(_) => {
+            // the process can't exit until we drop the lock, so the wakeup
+            // can't be missed
+            thread::wait_for(tf, handle);
+            drop(processes);
+            thread::switch(tf)
+        }
+    }
+}
@@ -4,12 +4,15 @@ use core::fmt::Display;
 use core::iter;
 use core::{cell::UnsafeCell, ffi::c_void, ptr};
 
+use mu_shared::KernResult;
 use riscv::{
-    arch::Satp,
+    arch::{self, Satp},
     globals::{HasEmpty, PerHartMut},
-    paging::VirtAddr,
+    paging::{Addr, VirtAddr},
 };
 
+use crate::exc::{k_entry, Reg};
+
 pub type TrapHandler = unsafe extern "C" fn(*mut TrapFrame) -> !;
 
 pub static TRAP_FRAMES: PerHartMut<TrapFrame> = PerHartMut::new();
@@ -100,6 +103,35 @@ impl Display for FormatRegs<'_> {
 }
 
 impl TrapFrame {
+    /// Makes a trap frame to start a user thread at `pc` with the stack
+    /// pointer `sp` and thread pointer `tp`, in the address space `satp`. The
+    /// other registers start at zero. The kernel stack is filled in when the
+    /// thread is scheduled.
+    pub fn new_user(pc: VirtAddr, sp: VirtAddr, tp: VirtAddr, satp: Satp) -> TrapFrame {
+        let mut regs = [0; 31];
+        // regs[n] is x(n + 1)
+        regs[1] = sp.get();
+        regs[3] = tp.get();
+        TrapFrame {
+            regs,
+            target_fn: k_entry,
+            hart_id: arch::core_id(),
+            kernel_sp: ptr::null_mut(),
+            new_satp: satp,
+            user_pc: pc,
+        }
+    }
+
+    /// Sets the registers a system call returns in: a0 says whether it
+    /// worked, and a1 has the result or the error
+    pub fn set_syscall_result(&mut self, res: KernResult<usize>) {
+        self.regs[Reg::A0] = res.is_ok() as usize;
+        self.regs[Reg::A1] = match res {
+            Ok(v) => v,
+            Err(e) => e as usize,
+        };
+    }
+
     pub fn display_regs(&self) -> FormatRegs {
         FormatRegs(&self)
     }
@@ -1,13 +1,217 @@
-use riscv::{addr::MAX_THREADS, arch::Mutex};
+//! Threads, and scheduling them onto harts
 
+use mu_shared::{KernErr, KernResult, ProcessHandle};
+use riscv::addr::{MAX_CPUS, MAX_THREADS};
+use riscv::arch::{self, get_sie, get_sip, set_sie, Mutex, SIE_STIE, SIP_STIP};
+
+use crate::exc::enter_userspace;
 use crate::tframe::TrapFrame;
+use crate::timer;
 
-static THREADS: Mutex<[Option<Thread>; MAX_THREADS]> =
-    Mutex::new([const { Option::<Thread>::None }; MAX_THREADS]);
+static THREADS: Mutex<Threads> = Mutex::new(Threads {
+    slots: [const { Option::<Thread>::None }; MAX_THREADS],
+    running: [None; MAX_CPUS],
+});
 
 unsafe impl Send for Thread {}
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Runnable,
+    /// Blocked in `ProcessWait` until the process exits
+    Waiting(ProcessHandle),
+    /// In `SleepUntil`, in the timer wheel of `hart`
+    Sleeping {
+        hart: usize,
+    },
+}
+
 struct Thread {
     /// Trap frame to reenter this thread
     tframe: TrapFrame,
+    /// Process the thread belongs to
+    process: ProcessHandle,
+    state: State,
+}
+
+struct Threads {
+    slots: [Option<Thread>; MAX_THREADS],
+    /// Index in `slots` of the thread running on each hart
+    running: [Option<usize>; MAX_CPUS],
+}
+
+impl Threads {
+    fn current(&mut self) -> Option<&mut Thread> {
+        let idx = self.running[arch::core_id()]?;
+        self.slots[idx].as_mut()
+    }
+}
+
+/// Adds a thread to `process`, which will start running from `tframe`
+pub fn spawn(process: ProcessHandle, tframe: TrapFrame) -> KernResult<()> {
+    let mut threads = THREADS.lock();
+    let slot = threads
+        .slots
+        .iter_mut()
+        .find(|s| s.is_none())
+        .ok_or(KernErr::NoMemory)?;
+    *slot = Some(Thread {
+        tframe,
+        process,
+        state: State::Runnable,
+    });
+    // the thread running here may have had the hart to itself, with no end
+    // to its slice, and now has to share it.
+    //
+    // TODO(smp): an idle hart should pick the new thread up instead
+    if threads.running[arch::core_id()].is_some() {
+        timer::contend();
+    }
+    Ok(())
+}
+
+/// Gets the process of the thread running on this hart
+pub fn current_process() -> Option<ProcessHandle> {
+    THREADS.lock().current().map(|t| t.process)
+}
+
+/// Blocks the current thread until `process` exits. `tf` is the trap frame
+/// the thread entered the kernel with, which it will return with the exit
+/// code from. Call [`switch`] afterwards.
+pub fn wait_for(tf: &TrapFrame, process: ProcessHandle) {
+    let mut threads = THREADS.lock();
+    let thread = threads.current().expect("no thread to block");
+    thread.tframe = tf.clone();
+    thread.state = State::Waiting(process);
+}
+
+/// Puts the current thread to sleep on this hart until `deadline`, in ticks
+/// of `mtime`, and runs something else. `tf` is the trap frame the thread
+/// entered the kernel with, which it will return from `SleepUntil` with.
+pub unsafe fn sleep_until(tf: &TrapFrame, deadline: u64) -> ! {
+    let hart = arch::core_id();
+    {
+        let mut threads = THREADS.lock();
+        let idx = threads.running[hart].expect("no thread to put to sleep");
+        let thread = threads.slots[idx]
+            .as_mut()
+            .expect("no thread to put to sleep");
+        thread.tframe = tf.clone();
+        thread.tframe.set_syscall_result(Ok(0));
+        thread.state = State::Sleeping { hart };
+        timer::add_sleeper(idx, deadline);
+    }
+    switch(tf)
+}
+
+/// Wakes the thread at index `idx` from sleeping, if it still is
+pub fn wake(idx: usize) {
+    let mut threads = THREADS.lock();
+    if let Some(thread) = &mut threads.slots[idx] {
+        if matches!(thread.state, State::Sleeping { .. }) {
+            thread.state = State::Runnable;
+        }
+    }
+}
+
+/// Removes the threads of `process`, which exited with `code`, and wakes the
+/// threads waiting for it. Returns whether any were.
+pub fn exit_process(process: ProcessHandle, code: isize) -> bool {
+    let mut threads = THREADS.lock();
+    let Threads { slots, running } = &mut *threads;
+    let mut waited = false;
+    for (idx, slot) in slots.iter_mut().enumerate() {
+        match slot {
+            Some(thread) if thread.process == process => {
+                if let State::Sleeping { hart } = thread.state {
+                    timer::cancel(hart, idx);
+                }
+                // TODO(smp): threads of the process running on other harts
+                // need to be interrupted. for now, processes only have one
+                // thread, which is the one exiting.
+                *slot = None;
+                for r in running.iter_mut().filter(|r| **r == Some(idx)) {
+                    *r = None;
+                }
+            }
+            Some(thread) if thread.state == State::Waiting(process) => {
+                thread.tframe.set_syscall_result(Ok(code as usize));
+                thread.state = State::Runnable;
+                waited = true;
+            }
+            _ => {}
+        }
+    }
+    waited
+}
+
+/// Switches to the next thread after the current one that is not running on
+/// another hart, round robin, which may be the current one again.
+///
+/// `tf` is the trap frame the kernel was entered with. The current thread, if
+/// there is one, is saved from it, and the next thread runs on the same
+/// kernel stack.
+pub unsafe fn reschedule(tf: &TrapFrame) -> ! {
+    if let Some(thread) = THREADS.lock().current() {
+        thread.tframe = tf.clone();
+    }
+    switch(tf)
+}
+
+/// Like [`reschedule`], but without saving the current thread, which has
+/// been saved already or is gone.
+///
+/// If there is nothing to run, the hart waits for its timer to go off.
+pub unsafe fn switch(tf: &TrapFrame) -> ! {
+    let hart = arch::core_id();
+    let mut idle = false;
+    let next = loop {
+        let mut threads = THREADS.lock();
+        let current = threads.running[hart];
+        let start = current.map_or(0, |idx| idx + 1);
+        let mut candidates = (0..MAX_THREADS)
+            .map(|i| (start + i) % MAX_THREADS)
+            .filter(|&idx| {
+                matches!(&threads.slots[idx], Some(t) if t.state == State::Runnable)
+                    && (Some(idx) == current || !threads.running.contains(&Some(idx)))
+            });
+        let found = candidates.next();
+        // if another thread could run here too, this one only gets a slice
+        let contended = candidates.next().is_some();
+        threads.running[hart] = found;
+
+        let idx = match found {
+            Some(idx) => idx,
+            None => {
+                if !idle {
+                    log::debug!("hart {} is idle", hart);
+                    idle = true;
+                }
+                drop(threads);
+                // TODO(smp): a thread made runnable by another hart does not
+                // wake this one up. that needs an interprocessor interrupt.
+                //
+                // interrupts are off in the kernel, but wfi still wakes up
+                // for an interrupt enabled in sie (§ 3.3.3 Privileged), and
+                // then we look for the timer by hand
+                set_sie(get_sie() | 1 << SIE_STIE);
+                if get_sip() & (1 << SIP_STIP) == 0 {
+                    arch::wait_for_interrupt();
+                }
+                if get_sip() & (1 << SIP_STIP) != 0 {
+                    timer::tick();
+                }
+                continue;
+            }
+        };
+        timer::start_slice(contended);
+        let thread = threads.slots[idx].as_ref().unwrap();
+        log::debug!("run thread {} of process {}", idx, thread.process);
+        break TrapFrame {
+            hart_id: tf.hart_id,
+            kernel_sp: tf.kernel_sp,
+            ..thread.tframe.clone()
+        };
+    };
+    enter_userspace(&next)
 }
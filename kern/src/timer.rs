@@ -0,0 +1,237 @@
+//! Time, and threads sleeping until a deadline
+//!
+//! Each hart has a timer wheel of the threads that went to sleep on it. There
+//! is no periodic tick: each hart's timer is set for the earliest of their
+//! deadlines, or the end of the running thread's time slice if other threads
+//! are waiting for a hart, and is off otherwise. Times here are in ticks of
+//! the CLINT `mtime` counter unless they say otherwise.
+
+use core::iter;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use riscv::addr::{MAX_CPUS, MAX_THREADS};
+use riscv::arch::{self, set_timer, Mutex};
+use riscv::clint::CLINT;
+
+use crate::thread;
+
+/// Number of slots in each timer wheel
+const WHEEL_SLOTS: usize = 64;
+
+/// Each slot covers `1 << SLOT_SHIFT` ticks, about 6.5ms at 10MHz
+const SLOT_SHIFT: u32 = 16;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// How long a thread runs before the next one gets a turn, if they have to
+/// share a hart
+const SLICE_NANOS: u64 = 100_000_000;
+
+/// Frequency of `mtime` in Hz
+static TIMEBASE_FREQ: AtomicU64 = AtomicU64::new(0);
+
+static TIMERS: [Mutex<HartTimer>; MAX_CPUS] = [const {
+    Mutex::new(HartTimer {
+        wheel: Wheel {
+            heads: [None; WHEEL_SLOTS],
+            deadlines: [0; MAX_THREADS],
+            next: [None; MAX_THREADS],
+            last: 0,
+        },
+        slice_end: None,
+    })
+}; MAX_CPUS];
+
+/// What a hart's timer is for
+struct HartTimer {
+    wheel: Wheel,
+    /// When the running thread's time slice ends, if it has one
+    slice_end: Option<u64>,
+}
+
+impl HartTimer {
+    /// Sets this hart's timer for the next thing that needs it, or turns it
+    /// off. This also clears STIP.
+    fn program(&self) {
+        let next = match (self.wheel.earliest(), self.slice_end) {
+            (Some(a), Some(b)) => a.min(b),
+            (a, b) => a.or(b).unwrap_or(u64::MAX),
+        };
+        set_timer(next);
+    }
+}
+
+/// A hashed timer wheel. Sleeping threads are kept in a list in the slot
+/// their deadline falls in, so expiring them only has to look at the slots
+/// that passed since last time. The lists are linked through arrays indexed
+/// by thread, since a thread can only sleep once at a time.
+struct Wheel {
+    /// First thread in the list for each slot
+    heads: [Option<usize>; WHEEL_SLOTS],
+    /// Deadline of each thread in the wheel
+    deadlines: [u64; MAX_THREADS],
+    /// Next thread in the same slot as each thread
+    next: [Option<usize>; MAX_THREADS],
+    /// Slot number, unwrapped, that was last expired up to
+    last: u64,
+}
+
+impl Wheel {
+    /// Iterates over the threads in the list at slot number `slot`
+    fn slot(&self, slot: u64) -> impl Iterator<Item = usize> + '_ {
+        iter::successors(self.heads[slot as usize % WHEEL_SLOTS], move |&t| {
+            self.next[t]
+        })
+    }
+
+    fn insert(&mut self, thread: usize, deadline: u64) {
+        let slot = (deadline >> SLOT_SHIFT) as usize % WHEEL_SLOTS;
+        self.deadlines[thread] = deadline;
+        self.next[thread] = self.heads[slot];
+        self.heads[slot] = Some(thread);
+    }
+
+    /// Removes the threads in the list at `slot` for which `pred` is true
+    fn remove_where(&mut self, slot: usize, mut pred: impl FnMut(usize, u64) -> bool) {
+        let mut prev = None;
+        let mut cur = self.heads[slot];
+        while let Some(t) = cur {
+            cur = self.next[t];
+            if pred(t, self.deadlines[t]) {
+                match prev {
+                    Some(p) => self.next[p] = cur,
+                    None => self.heads[slot] = cur,
+                }
+                self.next[t] = None;
+            } else {
+                prev = Some(t);
+            }
+        }
+    }
+
+    /// Removes the threads whose deadlines are at or before `now`, calling
+    /// `f` with each
+    fn expire(&mut self, now: u64, mut f: impl FnMut(usize)) {
+        let now_slot = now >> SLOT_SHIFT;
+        // more than a turn of the wheel ago is all of the slots anyway
+        let from = self
+            .last
+            .max(now_slot.saturating_sub(WHEEL_SLOTS as u64 - 1));
+        for slot in from..=now_slot {
+            self.remove_where(slot as usize % WHEEL_SLOTS, |t, deadline| {
+                let expired = deadline <= now;
+                if expired {
+                    f(t);
+                }
+                expired
+            });
+        }
+        self.last = now_slot;
+    }
+
+    /// Gets the earliest deadline in the wheel
+    fn earliest(&self) -> Option<u64> {
+        // deadlines in the coming turn of the wheel are found in order of
+        // their slots, and anything later is in some slot or other
+        (self.last..self.last + WHEEL_SLOTS as u64)
+            .find_map(|slot| {
+                self.slot(slot)
+                    .map(|t| self.deadlines[t])
+                    .filter(|&d| d >> SLOT_SHIFT == slot)
+                    .min()
+            })
+            .or_else(|| {
+                (0..WHEEL_SLOTS as u64)
+                    .flat_map(|slot| self.slot(slot).map(move |t| self.deadlines[t]))
+                    .min()
+            })
+    }
+}
+
+/// Sets up timekeeping, with the frequency `mtime` counts at from shoo
+pub fn init(timebase_freq: u64) {
+    TIMEBASE_FREQ.store(timebase_freq, Ordering::Relaxed);
+}
+
+/// Gets the current time
+pub fn now() -> u64 {
+    // safety: the CLINT is mapped in every address space
+    unsafe { CLINT.mtime() }
+}
+
+/// Converts a time in ticks to nanoseconds
+pub fn ticks_to_nanos(ticks: u64) -> u64 {
+    let freq = TIMEBASE_FREQ.load(Ordering::Relaxed) as u128;
+    (ticks as u128 * NANOS_PER_SEC / freq) as u64
+}
+
+/// Converts a time in nanoseconds to ticks, rounding up so nothing wakes
+/// early
+pub fn nanos_to_ticks(nanos: u64) -> u64 {
+    let freq = TIMEBASE_FREQ.load(Ordering::Relaxed) as u128;
+    let ticks = (nanos as u128 * freq + NANOS_PER_SEC - 1) / NANOS_PER_SEC;
+    ticks.min(u64::MAX as u128) as u64
+}
+
+/// Adds the thread at index `thread`, which is going to sleep on this hart
+/// until `deadline`
+pub fn add_sleeper(thread: usize, deadline: u64) {
+    let mut timer = TIMERS[arch::core_id()].lock();
+    timer.wheel.insert(thread, deadline);
+    timer.program();
+}
+
+/// Takes the thread at index `thread` out of the wheel of `hart`, where it
+/// was sleeping. The timer of `hart` may still go off at the old deadline,
+/// which does no harm.
+pub fn cancel(hart: usize, thread: usize) {
+    let mut timer = TIMERS[hart].lock();
+    let slot = (timer.wheel.deadlines[thread] >> SLOT_SHIFT) as usize % WHEEL_SLOTS;
+    timer.wheel.remove_where(slot, |t, _| t == thread);
+}
+
+/// Starts the time slice of the thread that is about to run on this hart. If
+/// nothing else is waiting for a hart, it has no end.
+pub fn start_slice(contended: bool) {
+    let mut timer = TIMERS[arch::core_id()].lock();
+    timer.slice_end = if contended {
+        Some(now().saturating_add(nanos_to_ticks(SLICE_NANOS)))
+    } else {
+        None
+    };
+    timer.program();
+}
+
+/// Gives the thread running on this hart a time slice, if it does not have
+/// one already, since another thread now wants the hart
+pub fn contend() {
+    let mut timer = TIMERS[arch::core_id()].lock();
+    if timer.slice_end.is_none() {
+        timer.slice_end = Some(now().saturating_add(nanos_to_ticks(SLICE_NANOS)));
+        timer.program();
+    }
+}
+
+/// Wakes the threads sleeping on this hart whose deadlines have passed, ends
+/// the time slice if it is over, and sets the timer for what is next. Called
+/// when the timer goes off.
+pub fn tick() {
+    let mut expired = [None; MAX_THREADS];
+    let mut n = 0;
+    {
+        let mut timer = TIMERS[arch::core_id()].lock();
+        let now = now();
+        timer.wheel.expire(now, |t| {
+            expired[n] = Some(t);
+            n += 1;
+        });
+        if matches!(timer.slice_end, Some(end) if end <= now) {
+            timer.slice_end = None;
+        }
+        timer.program();
+    }
+    // the timer is unlocked, since threads are locked before timers
+    for &t in expired[..n].iter().flatten() {
+        thread::wake(t);
+    }
+}
@@ -1,3 +1,5 @@
+use std::{env, fs, path::PathBuf};
+
 use build_bits::external_dep;
 
 fn main() {
@@ -24,4 +26,34 @@ fn main() {
     external_dep("src/init.s");
     external_dep("src/vectors.s");
     external_dep("shoo.ld");
+
+    write_initrd_key();
+}
+
+/// Writes the public key that the initrd must be signed with, from the PEM
+/// file at `$SHOO_INITRD_PUBKEY`, if it is set
+fn write_initrd_key() {
+    println!("cargo:rerun-if-env-changed=SHOO_INITRD_PUBKEY");
+    let key = match env::var("SHOO_INITRD_PUBKEY") {
+        Ok(path) => {
+            external_dep(&path);
+            let pem = fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("failed to read initrd key {:?}: {:?}", path, e));
+            let key = ed25519_compact::PublicKey::from_pem(&pem)
+                .unwrap_or_else(|e| panic!("bad initrd key {:?}: {:?}", path, e));
+            format!("Some({:?})", *key)
+        }
+        Err(_) => "None".to_owned(),
+    };
+
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("initrd_key.rs");
+    fs::write(
+        out,
+        format!(
+            "/// Public key the initrd must be signed with\n\
+             pub const INITRD_KEY: Option<[u8; 32]> = {};\n",
+            key
+        ),
+    )
+    .unwrap();
 }
@@ -0,0 +1,20 @@
+//! Gathering entropy at boot for address space layout randomization
+
+use riscv::clint::CLINT;
+use riscv::rand::EntropyPool;
+
+/// Number of timer ticks to measure jitter over
+const JITTER_SAMPLES: usize = 64;
+
+/// Mixes in the jitter between the timer and how fast we can spin. This is
+/// weak on its own, especially under emulation, but it is always available.
+pub unsafe fn add_timer_jitter(pool: &mut EntropyPool) {
+    for _ in 0..JITTER_SAMPLES {
+        let start = CLINT.mtime();
+        let mut spins = 0u64;
+        while CLINT.mtime() == start {
+            spins += 1;
+        }
+        pool.add_u64(start ^ spins.rotate_left(32));
+    }
+}
@@ -12,10 +12,10 @@ _entry:
 
     // set up a stack
     la sp, STACKS
-    li t0, 16384     // use 16k stacks
+    li t0, 32768     // use 32k stacks
 
     // we want to get the pointer to the top of the (descending) stack
-    // thus we want 16k * (hartid + 1)
+    // thus we want 32k * (hartid + 1)
     addi t1, a0, 1
     mul t0, t0, t1
     add sp, sp, t0
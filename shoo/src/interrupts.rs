@@ -7,17 +7,14 @@ use bitvec::prelude::*;
 
 use riscv::addr;
 use riscv::arch;
+use riscv::clint::CLINT;
 
 const TIMER_ISR_EMPTY: TimerIsrData = TimerIsrData {
     regs: [0; 2],
     my_mtimecmp: ptr::null_mut(),
-    my_interval: 0,
 };
 
 pub static mut TIMER_ISR_DATA: [TimerIsrData; addr::MAX_CPUS] = [TIMER_ISR_EMPTY; addr::MAX_CPUS];
-pub static CLINT: Clint = Clint {
-    base: addr::CLINT as *mut _,
-};
 
 /// Data to be used by our timer ISRs in `vectors.s`. Do not change this
 /// structure without checking those first!
@@ -26,36 +23,6 @@ pub static CLINT: Clint = Clint {
 pub struct TimerIsrData {
     pub regs: [u64; 2],
     pub my_mtimecmp: *mut u64,
-    pub my_interval: u64,
-}
-
-/// Some dude that gives you interrupts
-/// jk it's the SiFive CLINT interrupt controller
-pub struct Clint {
-    base: *mut (),
-}
-
-/// safety: the accesses don't step on each other
-unsafe impl Sync for Clint {}
-
-impl Clint {
-    unsafe fn my_mtimecmp(&self, hart_id: u8) -> *mut u64 {
-        let mtimecmp_base = self.base as usize + 0x4000;
-        let addr = (mtimecmp_base as *mut u64).offset(hart_id as isize);
-        addr
-    }
-
-    /// Sets the next mtimecmp interrupt time in ~cycles from now
-    pub unsafe fn schedule_interrupt(&self, hart_id: u8, int_time: u64) {
-        // documented in SiFive U74MC manual, section 9.5
-        // available at https://sifive.cdn.prismic.io/sifive/aee0dd4c-d156-496e-a6c4-db0cf54bbe68_sifive_U74MC_rtl_full_20G1.03.00_manual.pdf
-        let mtime_base = self.base as usize + 0xbff8;
-        let addr = self.my_mtimecmp(hart_id);
-
-        let now = (mtime_base as *const u64).read_volatile();
-        let next_time = now.wrapping_add(int_time);
-        addr.write_volatile(next_time);
-    }
 }
 
 extern "C" {
@@ -66,9 +33,10 @@ extern "C" {
 pub unsafe fn init_timers() {
     let hart = arch::m_core_id();
 
-    // stolen from xv6, apparently 1/10s in qemu
-    let interval = 1000000;
-    CLINT.schedule_interrupt(hart as u8, interval);
+    // there is no periodic tick: the timer stays off until the kernel sets a
+    // deadline with MachineCall::SetTimer
+    let mtimecmp = CLINT.mtimecmp(hart as u8);
+    mtimecmp.write_volatile(u64::MAX);
 
     // TODO:
     // this is probably a bad idea since this stuff is really probably volatile
@@ -76,8 +44,7 @@ pub unsafe fn init_timers() {
     let my_isr_data = &mut TIMER_ISR_DATA[hart];
 
     *my_isr_data = TimerIsrData {
-        my_mtimecmp: CLINT.my_mtimecmp(hart as u8),
-        my_interval: interval,
+        my_mtimecmp: mtimecmp,
         ..TIMER_ISR_DATA[hart]
     };
 
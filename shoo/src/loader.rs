@@ -1,187 +1,279 @@
-//! ELF loader
+//! Loading ELF images out of the initrd before we have paging
 
-use core::convert::TryInto;
-use core::mem;
-use core::slice;
+use core::ops::Range;
 
-use goblin::elf64::*;
-use header::{Header, ELFMAG};
-use mem::MaybeUninit;
-use program_header::*;
-use riscv::addr;
+use elf_loader::stack::{ProcessStack, StackError};
+use elf_loader::{
+    default_base, AddressSpace, Elf, ElfLoadErr, FileStream, LoadError, LoadedImage, TlsTemplate,
+};
+use microflop::Inflater;
+use mu_shared::BootInfo;
 use riscv::arch::{PhysAddr, PhysMem};
-use riscv_paging::{Addr, MapError, PageSize, PageTable, PhysAccess, PteAttrs, VirtAddr, VirtSize};
-use section_header::SectionHeader;
+use riscv::rand::Rng;
+use riscv_paging::{AccessError, Addr, MapError, PageTable, PteAttrs, VirtAddr};
 use spanner::Span;
 
-/// Converts the ELF Phdr.p_flags to PteAttrs
-pub fn flags_to_riscv(p_flags: u32) -> PteAttrs {
-    let mut out = PteAttrs::empty();
-    if p_flags & PF_R != 0 {
-        out |= PteAttrs::R;
+/// Most segments we will load from one image
+const MAX_SEGMENTS: usize = 16;
+
+/// Space for init's arguments, environment and auxiliary vector
+const INIT_STACK_DATA: usize = 4096;
+
+#[derive(Clone, Copy, Debug)]
+struct PackedSegment {
+    virt: Span,
+    phys: usize,
+    attrs: PteAttrs,
+}
+
+/// An [`AddressSpace`] that packs the segments of an image one after another
+/// into contiguous physical memory, remembering where they went so they can be
+/// mapped once there is a page table. Gaps between the segments in virtual
+/// memory do not use any physical memory.
+pub struct PackedImage {
+    phys: Span,
+    segments: [Option<PackedSegment>; MAX_SEGMENTS],
+    /// Where to put the image if it is position independent
+    base: Option<VirtAddr>,
+    loaded: Option<LoadedImage>,
+}
+
+#[derive(Debug)]
+pub enum PackErr {
+    /// The image has more than [`MAX_SEGMENTS`] segments
+    TooManySegments,
+    /// Tried to write somewhere that is not in any segment
+    NotMapped(VirtAddr),
+}
+
+impl PackedImage {
+    /// Makes an empty image that will be placed at the page aligned physical
+    /// address `start_at`. Nothing may be using the physical memory after it.
+    pub fn new(start_at: PhysAddr) -> PackedImage {
+        PackedImage {
+            phys: Span::new(start_at.get(), start_at.get()),
+            segments: [None; MAX_SEGMENTS],
+            base: None,
+            loaded: None,
+        }
+    }
+
+    /// Loads `elf` at the physical address `start_at`, giving it the extra
+    /// attributes `extra_attrs` when it is mapped. If it is position
+    /// independent, it is put at the virtual address `base`, or where it was
+    /// linked if that is `None`.
+    pub unsafe fn load(
+        elf: &Elf<'_>,
+        start_at: PhysAddr,
+        extra_attrs: PteAttrs,
+        base: Option<VirtAddr>,
+    ) -> Result<PackedImage, LoadError<PackErr>> {
+        PackedImage::load_with(start_at, base, |image| elf.load(image, extra_attrs))
+    }
+
+    /// Like [`PackedImage::load`], but for an ELF of which only the start was
+    /// parsed, with the rest coming from `stream`
+    pub unsafe fn load_stream(
+        elf: &Elf<'_>,
+        stream: &mut impl FileStream,
+        start_at: PhysAddr,
+        extra_attrs: PteAttrs,
+        base: Option<VirtAddr>,
+    ) -> Result<PackedImage, LoadError<PackErr>> {
+        PackedImage::load_with(start_at, base, |image| {
+            elf.load_stream(stream, image, extra_attrs)
+        })
+    }
+
+    unsafe fn load_with(
+        start_at: PhysAddr,
+        base: Option<VirtAddr>,
+        load: impl FnOnce(&mut PackedImage) -> Result<LoadedImage, LoadError<PackErr>>,
+    ) -> Result<PackedImage, LoadError<PackErr>> {
+        let mut image = PackedImage::new(start_at);
+        image.base = base;
+        let loaded = load(&mut image)?;
+        log::debug!(
+            "image range is {:?}, phys: {:?}",
+            loaded.virt_span,
+            image.phys
+        );
+        image.loaded = Some(loaded);
+        Ok(image)
+    }
+
+    /// Where the image was put in virtual memory
+    pub fn loaded(&self) -> &LoadedImage {
+        self.loaded.as_ref().expect("image was not loaded")
     }
-    if p_flags & PF_W != 0 {
-        out |= PteAttrs::W;
+
+    /// Physical memory used by the image
+    pub fn phys_span(&self) -> Span {
+        self.phys
     }
-    if p_flags & PF_X != 0 {
-        out |= PteAttrs::X;
+
+    /// Maps the image into the page table `pt`
+    pub unsafe fn map(&self, pt: PageTable<PhysMem>) -> Result<(), MapError> {
+        for seg in self.segments.iter().flatten() {
+            log::debug!("map {:?} -> {:x} flags {:?}", seg.virt, seg.phys, seg.attrs);
+            pt.virt_map(
+                PhysAddr::new(seg.phys),
+                VirtAddr(seg.virt.begin()),
+                seg.virt.len(),
+                seg.attrs,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Finds where the `len` bytes at `va` are in physical memory
+    fn phys_of(&self, va: VirtAddr, len: usize) -> Result<usize, PackErr> {
+        let target = Span::new(va.get(), va.get() + len);
+        let seg = self
+            .segments
+            .iter()
+            .flatten()
+            .find(|s| s.virt.intersect(target) == Some(target))
+            .ok_or(PackErr::NotMapped(va))?;
+        Ok(seg.phys + (va.get() - seg.virt.begin()))
     }
-    out
 }
 
-#[derive(Clone, Copy, Debug)]
-pub enum ElfLoadErr {
-    Oop,
+impl AddressSpace for PackedImage {
+    type Error = PackErr;
+
+    fn choose_base(&mut self, link_span: Range<VirtAddr>) -> Result<VirtAddr, PackErr> {
+        Ok(self.base.unwrap_or_else(|| default_base(&link_span)))
+    }
+
+    fn map_zeroed(&mut self, virt: Range<VirtAddr>, attrs: PteAttrs) -> Result<(), PackErr> {
+        let slot = self
+            .segments
+            .iter_mut()
+            .find(|s| s.is_none())
+            .ok_or(PackErr::TooManySegments)?;
+        let virt = Span::new(virt.start.get(), virt.end.get());
+        let phys = self.phys.end();
+        // safety: we are running on physical addresses and are promised the
+        // memory after the image is free
+        unsafe { PhysAddr::new(phys).as_u8_ptr().write_bytes(0, virt.len()) };
+        *slot = Some(PackedSegment { virt, phys, attrs });
+        self.phys = Span::new(self.phys.begin(), phys + virt.len());
+        Ok(())
+    }
+
+    fn write(&mut self, va: VirtAddr, data: &[u8]) -> Result<(), PackErr> {
+        let phys = self.phys_of(va, data.len())?;
+        // safety: this is within memory we allocated for the segment
+        unsafe {
+            PhysAddr::new(phys)
+                .as_u8_ptr()
+                .copy_from_nonoverlapping(data.as_ptr(), data.len())
+        };
+        Ok(())
+    }
+
+    fn read(&mut self, va: VirtAddr, buf: &mut [u8]) -> Result<(), PackErr> {
+        let phys = self.phys_of(va, buf.len())?;
+        // safety: as for write
+        unsafe {
+            buf.as_mut_ptr()
+                .copy_from_nonoverlapping(PhysAddr::new(phys).as_u8_ptr(), buf.len())
+        };
+        Ok(())
+    }
 }
 
-pub fn get_total_size(headers: &[ProgramHeader]) -> usize {
-    headers
-        .iter()
-        .map(|h| {
-            VirtSize(h.p_memsz as usize)
-                .round_up(PageSize::Page4k)
-                .unwrap()
-                .get()
+/// Feeds an ELF that is being inflated out of the initrd to
+/// [`Elf::load_stream`]
+pub struct InflateStream<'a>(pub Inflater<'a>);
+
+impl FileStream for InflateStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ElfLoadErr> {
+        self.0.read(buf).map_err(|e| {
+            log::warn!("failed to inflate: {}", e);
+            ElfLoadErr::Unreadable
         })
-        .sum()
+    }
 }
 
-pub struct ImageLoadInfo<'a> {
-    pub virt_span: Span,
-    pub phys_span: Span,
-    pub headers: &'a [ProgramHeader],
-    pub elf_header: Header,
+#[derive(Debug)]
+pub enum InitErr {
+    /// Could not build init's stack
+    Stack(StackError),
+    /// Could not allocate memory for init
+    Map(MapError),
+    /// Could not write into init's memory
+    Access(AccessError),
 }
 
-pub unsafe fn map_executable(
-    pt: PageTable<PhysMem>,
-    phys_range: Span,
-    virt_range: Span,
-    headers: &[ProgramHeader],
-    extra_flags: PteAttrs,
-) -> Result<(), MapError> {
-    for header in headers.iter().filter(|h| h.p_type == PT_LOAD) {
-        // load the page into VM space
-        let pa = header.p_vaddr as usize - virt_range.begin() + phys_range.begin();
-        let pa = PhysAddr::new(pa);
-        let va = VirtAddr(header.p_vaddr as usize);
-        let len = VirtSize(header.p_memsz as usize)
-            .round_up(PageSize::Page4k)
-            .unwrap()
-            .get();
-        let flags = flags_to_riscv(header.p_flags);
-        log::debug!(
-            "map {:?} -> {:?} len {:x} flags {:?}",
-            va,
-            pa,
-            len,
-            flags | extra_flags
-        );
-        pt.virt_map(pa, va, len, flags | extra_flags)?;
+impl From<MapError> for InitErr {
+    fn from(e: MapError) -> Self {
+        InitErr::Map(e)
     }
-    Ok(())
 }
 
-pub unsafe fn load_image<'a>(image_slice: &'a [u8], start_at: *mut u8) -> ImageLoadInfo<'a> {
-    let (hdr, headers) = get_headers(image_slice).expect("kern elf load err");
+impl From<StackError> for InitErr {
+    fn from(e: StackError) -> Self {
+        InitErr::Stack(e)
+    }
+}
 
-    let header_to_span = |h: &ProgramHeader| {
-        if h.p_type != PT_LOAD {
-            None
-        } else {
-            Some(Span::new(
-                h.p_vaddr as usize,
-                VirtSize(h.p_vaddr as usize + h.p_memsz as usize)
-                    .round_up(PageSize::Page4k)
-                    .unwrap()
-                    .get(),
-            ))
-        }
-    };
-    let first_header = headers
-        .iter()
-        .filter_map(header_to_span)
-        .position(|s| s.len() != 0)
-        .expect("no nonempty regions in the kern elf");
-    let kernel_range = headers[first_header + 1..]
-        .iter()
-        .filter_map(header_to_span)
-        .filter(|s| s.len() != 0)
-        .try_fold(header_to_span(&headers[first_header]).unwrap(), |s1, s2| {
-            s1.merge(s2)
-        })
-        .expect("kernel regions not contiguous");
-
-    // next, load the kernel into contiguous physical memory
-
-    let kern_range_phys = kernel_range
-        .offset(-(kernel_range.begin() as isize))
-        .offset(start_at as isize);
-    let kern_slice_w = kern_range_phys.as_slice_mut::<MaybeUninit<u8>>();
-    let base = kernel_range.begin();
-
-    // load the image into memory
-    for header in headers.iter().filter(|h| h.p_type == PT_LOAD) {
-        let start_idx = header.p_vaddr as usize - base;
-        let end_idx = start_idx + header.p_filesz as usize;
-        let extra = (header.p_memsz - header.p_filesz) as usize;
-        let end_extra_align = VirtSize(end_idx + extra)
-            .round_up(PageSize::Page4k)
-            .unwrap()
-            .get();
-        // transmute is ok because it is transmuting slice of init to slice of
-        // MaybeUninit, identical layout.
-        kern_slice_w[start_idx..end_idx].copy_from_slice(mem::transmute::<_, &[MaybeUninit<u8>]>(
-            &image_slice[header.p_offset as usize..(header.p_offset + header.p_filesz) as usize],
-        ));
-        // fill till the end of the section
-        kern_slice_w[end_idx..end_extra_align].fill(MaybeUninit::new(0));
-    }
-
-    log::debug!(
-        "kernel range is {:?}, phys: {:?}",
-        &kernel_range,
-        &kern_range_phys
-    );
-    ImageLoadInfo {
-        virt_span: kernel_range,
-        phys_span: kern_range_phys,
-        headers,
-        elf_header: hdr,
+impl From<AccessError> for InitErr {
+    fn from(e: AccessError) -> Self {
+        InitErr::Access(e)
     }
 }
 
-pub fn get_headers(elf: &[u8]) -> Result<(Header, &[ProgramHeader]), ElfLoadErr> {
-    let bits = &elf[..64].try_into().unwrap();
-    let hdr = Header::from_bytes(bits);
-
-    assert_eq!(&hdr.e_ident[..4], ELFMAG);
-    let phentsize = mem::size_of::<ProgramHeader>();
-    assert_eq!(hdr.e_phentsize as usize, phentsize);
-
-    let prog_headers =
-        &elf[hdr.e_phoff as usize..hdr.e_phoff as usize + phentsize * hdr.e_phnum as usize];
-    let (empty1, prog_headers, empty2) = unsafe { prog_headers.align_to::<ProgramHeader>() };
-    assert!(
-        empty1.is_empty() && empty2.is_empty(),
-        "program headers misaligned?!"
-    );
-
-    for phdr in prog_headers {
-        let ProgramHeader {
-            p_vaddr,
-            p_filesz,
-            p_memsz,
-            p_flags,
-            ..
-        } = phdr;
-        log::debug!(
-            "prog header: @{:x} f:{:x} m:{:x} flags:{:x}",
-            p_vaddr,
-            p_filesz,
-            p_memsz,
-            p_flags
-        );
+/// Allocates and initializes the thread local storage block for init's main
+/// thread, ending at or below `below`, and returns the thread pointer
+pub unsafe fn alloc_init_tls(
+    pt: PageTable<PhysMem>,
+    tls: &TlsTemplate,
+    below: VirtAddr,
+) -> Result<VirtAddr, InitErr> {
+    let (begin, len) = tls.block_below(below).ok_or(MapError::ArithOvf)?;
+    if len != 0 {
+        pt.virt_alloc(begin, len, PteAttrs::R | PteAttrs::W | PteAttrs::User)?;
+    }
+    tls.init_block(pt, begin)?;
+    Ok(begin)
+}
+
+/// Builds init's initial stack below `stack_top` in `pt`, with its arguments
+/// and environment taken from the kernel command line `bootargs`, and returns
+/// the stack pointer to start it with.
+///
+/// As on Linux, words of the command line that look like `key=value` become
+/// environment variables and the rest become arguments.
+pub unsafe fn build_init_stack(
+    pt: PageTable<PhysMem>,
+    image: &PackedImage,
+    stack_top: VirtAddr,
+    bootargs: &str,
+    rng: &mut Rng,
+    boot_info: &BootInfo,
+) -> Result<VirtAddr, InitErr> {
+    let mut buf = [0u8; INIT_STACK_DATA];
+    let mut random = [0u8; 16];
+    random[..8].copy_from_slice(&rng.next_u64().to_le_bytes());
+    random[8..].copy_from_slice(&rng.next_u64().to_le_bytes());
+    let mut stack =
+        ProcessStack::new(&mut buf, stack_top, image.loaded(), random, Some(boot_info))?;
+
+    stack.push_arg(b"init")?;
+    for word in bootargs.split_whitespace() {
+        let res = if word.contains('=') {
+            stack.push_env(word.as_bytes())
+        } else {
+            stack.push_arg(word.as_bytes())
+        };
+        match res {
+            Err(StackError::TooManyArgs) => {
+                log::warn!("too many init arguments, dropping {:?}", word)
+            }
+            res => res?,
+        }
     }
-    Ok((hdr.clone(), prog_headers))
+    Ok(stack.write_to(pt)?)
 }
@@ -2,6 +2,7 @@
 #![no_main]
 #![feature(asm, panic_info_message)]
 
+mod entropy;
 mod interrupts;
 mod isr;
 mod loader;
@@ -14,16 +15,20 @@ use core::{ffi::c_void, sync::atomic::Ordering};
 #[macro_use]
 extern crate riscv;
 
-use addr::{PHYSMEM, PHYSMEM_MAP};
-use goblin::elf64::program_header::{ProgramHeader, PT_LOAD};
-use loader::{flags_to_riscv, load_image, map_executable, ImageLoadInfo};
-use microflop::FileName;
-use riscv::addr::{PHYSMEM_LEN, USERSPACE_STACK_TOP};
+use addr::PHYSMEM;
+use elf_loader::{Elf, FileStream};
+use loader::{InflateStream, PackedImage};
+use microflop::{ed25519, Decompressor, EntryKind, Microflop, WINDOW_SIZE};
+use mu_shared::BootInfo;
+use riscv::addr::{MemoryMap, UserLayout, PHYSMEM_LEN};
 use riscv::arch::*;
 use riscv::globals::*;
 use riscv::print;
+use riscv::rand::{EntropyPool, Rng};
 use riscv::{addr, KernelEntryParams};
-use riscv_paging::{Addr, PageSize, PageTable, PhysAccess, PteAttrs, VirtAddr, VirtSize};
+use riscv_paging::{
+    Addr, PageSize, PageTable, PagingMode, PhysAccess, PteAttrs, VirtAddr, VirtSize,
+};
 use spanner::Span;
 
 use bitvec::prelude::*;
@@ -68,8 +73,9 @@ unsafe extern "C" fn startup(core_id: usize, dtb: *const u8) {
 
     set_mstatus(new_mstatus);
 
-    // turn off paging
-    set_satp(Satp(0));
+    // find out how big of an address space we can have. this also turns off
+    // paging
+    let paging_levels = probe_paging_mode().map_or(0, |m| m.levels());
 
     // set the exception return address
     set_mepc(shoo_main as *const _);
@@ -99,7 +105,7 @@ unsafe extern "C" fn startup(core_id: usize, dtb: *const u8) {
 
     setup_pmps();
 
-    asm!("mret", in("a0") core_id, in("a1") dtb);
+    asm!("mret", in("a0") core_id, in("a1") dtb, in("a2") paging_levels);
     unreachable!("mret did Not");
 }
 
@@ -126,7 +132,16 @@ unsafe fn setup_pmps() {
 
 /// Data we get from reading the device tree
 struct DtbRead {
+    /// Where the device tree itself is. `rng_seed` and `bootargs` point into
+    /// it, so it has to be kept around.
+    dtb: Span,
     initrd: &'static [u8],
+    /// Random bytes from the bootloader, if any
+    rng_seed: Option<&'static [u8]>,
+    /// Kernel command line
+    bootargs: &'static str,
+    /// Frequency `mtime` counts at, in Hz
+    timebase_freq: u32,
 }
 
 fn dump_dt(lvl: u8, dt: &DevTree) -> Result<(), DevTreeError> {
@@ -191,13 +206,28 @@ unsafe fn read_dtb(dtb: *const u8) -> Result<DtbRead, DevTreeError> {
     let mut props = chosen.props();
     let mut initrd_start = None;
     let mut initrd_end = None;
+    let mut rng_seed = None;
+    let mut bootargs = "";
     while let Some(p) = props.next()? {
         match p.name() {
             Ok("linux,initrd-start") => initrd_start = Some(p.u32(0)?),
             Ok("linux,initrd-end") => initrd_end = Some(p.u32(0)?),
+            Ok("rng-seed") => rng_seed = Some(p.raw()),
+            Ok("bootargs") => bootargs = p.str()?,
             _ => (),
         }
     }
+
+    let cpus = dtb
+        .nodes()
+        .find(|n| Ok(n.name()? == "cpus"))?
+        .expect("failed to get cpus");
+    let timebase_freq = cpus
+        .props()
+        .find(|p| Ok(p.name()? == "timebase-frequency"))?
+        .expect("missing timebase frequency")
+        .u32(0)?;
+
     let initrd_start = initrd_start.expect("missing initrd start");
     let initrd_end = initrd_end.expect("missing initrd end");
 
@@ -207,71 +237,225 @@ unsafe fn read_dtb(dtb: *const u8) -> Result<DtbRead, DevTreeError> {
     );
 
     // dump_dt(0, &dtb)?;
-    Ok(DtbRead { initrd })
+    Ok(DtbRead {
+        dtb: buf.into(),
+        initrd,
+        rng_seed,
+        bootargs,
+        timebase_freq,
+    })
+}
+
+/// Seeds a random number generator from whatever entropy we can find
+unsafe fn boot_rng(rng_seed: Option<&[u8]>) -> Rng {
+    let mut pool = EntropyPool::new();
+    if let Some(seed) = rng_seed {
+        pool.add_bytes(seed);
+    }
+    entropy::add_timer_jitter(&mut pool);
+    info!(
+        "seeding layout randomization with {} bytes from the device tree and timer jitter",
+        pool.bytes_fed()
+    );
+    pool.into_rng()
+}
+
+/// Picks where to load the kernel, if it is position independent
+#[cfg(feature = "kaslr")]
+fn kernel_base(elf: &Elf<'_>, rng: &mut Rng) -> Option<VirtAddr> {
+    /// Most virtual memory we expect the kernel image to take
+    const MAX_KERNEL_SIZE: usize = 1024 * 1024 * 1024;
+
+    if !elf.is_pie() {
+        log::warn!("kernel is not position independent, not randomizing its address");
+        return None;
+    }
+    let window = addr::KERNEL_WINDOW;
+    let slack = window.end - window.start - MAX_KERNEL_SIZE;
+    Some(VirtAddr(
+        window.start + rng.aligned_below(slack, PageSize::Page2m),
+    ))
+}
+
+#[cfg(not(feature = "kaslr"))]
+fn kernel_base(_elf: &Elf<'_>, _rng: &mut Rng) -> Option<VirtAddr> {
+    None
+}
+
+include!(concat!(env!("OUT_DIR"), "/initrd_key.rs"));
+
+/// Checks the initrd is signed with [`INITRD_KEY`] if shoo was built with
+/// one, and halts if it is not
+fn check_initrd_signature(initrd: &Microflop<'_>) {
+    let key = match INITRD_KEY {
+        Some(key) => ed25519::PublicKey::new(key),
+        None => {
+            info!("no initrd key built in, not checking the initrd signature");
+            return;
+        }
+    };
+    match initrd.verify_signature(&key) {
+        Ok(()) => info!("initrd signature is good"),
+        Err(microflop::Error::Unsigned) => {
+            println!("refusing to boot: initrd is not signed");
+            freeze_hart()
+        }
+        Err(e) => {
+            println!("refusing to boot: initrd signature check failed: {}", e);
+            freeze_hart()
+        }
+    }
 }
 
-unsafe extern "C" fn shoo_main(core_id: usize, dtb: *const u8) -> ! {
+/// How much of the start of a compressed ELF is inflated up front, which has
+/// to cover its headers
+const ELF_HEAD_LEN: usize = 4096;
+
+/// Where the output of inflating a compressed ELF is kept while it is read.
+/// This is too big for our stack.
+static mut INFLATE_WINDOW: [u8; WINDOW_SIZE] = [0; WINDOW_SIZE];
+
+/// Loads the ELF `name` from the initrd at `start_at`, or prints why it could
+/// not and halts. Compressed files are inflated as they are loaded, so they
+/// never have to be in memory whole. If the image is position independent, it
+/// goes at the virtual address `choose_base` picks, or where it was linked if
+/// that is `None`.
+unsafe fn load_or_halt(
+    initrd: &Microflop<'static>,
+    name: &str,
+    start_at: PhysAddr,
+    extra_attrs: PteAttrs,
+    choose_base: impl FnOnce(&Elf<'_>) -> Option<VirtAddr>,
+) -> PackedImage {
+    let entry = match initrd.entry(name) {
+        Ok(Some(entry)) if entry.kind == EntryKind::File => entry,
+        Ok(_) => {
+            println!("could not find {} in initrd", name);
+            freeze_hart()
+        }
+        Err(e) => {
+            println!("initrd parse err: {}", e);
+            freeze_hart()
+        }
+    };
+
+    let mut decompressor = Decompressor::new();
+    let res = match entry.inflater(&mut decompressor, &mut INFLATE_WINDOW) {
+        None => Elf::parse(entry.contents)
+            .map_err(From::from)
+            .and_then(|elf| {
+                let base = choose_base(&elf);
+                PackedImage::load(&elf, start_at, extra_attrs, base)
+            }),
+        Some(inflater) => {
+            info!(
+                "inflating {} from {} to {} bytes while loading it",
+                name,
+                entry.contents.len(),
+                entry.size
+            );
+            let mut stream = InflateStream(inflater);
+            let mut head = [0u8; ELF_HEAD_LEN];
+            let mut head_len = 0;
+            while head_len < head.len() {
+                match stream.read(&mut head[head_len..]) {
+                    Ok(0) => break,
+                    Ok(len) => head_len += len,
+                    Err(e) => {
+                        println!("failed to load {}: {}", name, e);
+                        freeze_hart()
+                    }
+                }
+            }
+            Elf::parse_head(&head[..head_len], entry.size)
+                .map_err(From::from)
+                .and_then(|elf| {
+                    let base = choose_base(&elf);
+                    PackedImage::load_stream(&elf, &mut stream, start_at, extra_attrs, base)
+                })
+        }
+    };
+    let initrd_span: Span = initrd.region().into();
+    match res {
+        // the initrd was read while the image was being written, so this is
+        // too late to avoid damage, but it is better than starting something
+        // broken
+        Ok(image) if image.phys_span().intersect(initrd_span).is_some() => {
+            println!("{} was loaded on top of the initrd", name);
+            freeze_hart()
+        }
+        Ok(image) => image,
+        Err(e) => {
+            println!("failed to load {}: {}", name, e);
+            freeze_hart()
+        }
+    }
+}
+
+unsafe extern "C" fn shoo_main(core_id: usize, dtb: *const u8, paging_levels: usize) -> ! {
     let endaddr = &SEC_END as *const _ as usize;
     if core_id != 0 {
         loop {}
     }
 
     crate::print::init();
+    let paging_mode =
+        PagingMode::from_levels(paging_levels).expect("hart does not support Sv39 or larger");
+    let memory_map = MemoryMap::for_mode(paging_mode);
+    info!("using {:?} paging", paging_mode);
     let DtbRead {
+        dtb: dtb_span,
         initrd: initrd_slice,
+        rng_seed,
+        bootargs,
+        timebase_freq,
     } = read_dtb(dtb).expect("dtb");
+    let mut rng = boot_rng(rng_seed);
+    let user_layout = UserLayout::randomized(&memory_map, &mut rng);
+    info!("init layout: {:x?}", user_layout);
 
     // CORE0
-    let kern = FileName(*b"kern\0\0\0\0\0\0\0\0\0\0\0");
-    let init = FileName(*b"init\0\0\0\0\0\0\0\0\0\0\0");
-
-    let initrd = microflop::Microflop::new(initrd_slice).expect("failed to open initrd");
-    let mut files = initrd.files();
-    let mut kern_slice = None;
-    let mut init_slice = None;
-    while let Some((name, content)) = files.next().expect("initrd parse err") {
-        match name {
-            n if n == kern => kern_slice = Some(content),
-            n if n == init => init_slice = Some(content),
-            _ => (),
-        }
-    }
-    let kern_slice = kern_slice.expect("could not find kern in initrd");
-    let init_slice = init_slice.expect("could not find init in initrd");
+    let initrd = Microflop::new(initrd_slice).expect("failed to open initrd");
+    check_initrd_signature(&initrd);
+    let initrd_span: Span = initrd_slice.into();
 
     // at this stage we don't have anything in the physical memory after our end,
     // of significance, at least
-    let kern_ptr = PhysAddr::new(endaddr)
-        .round_up(PageSize::Page4k)
-        .unwrap()
-        .as_u8_ptr();
-    let ImageLoadInfo {
-        virt_span: kernel_range_virt,
-        phys_span: kern_range_phys,
-        headers: kernel_headers,
-        elf_header: hdr,
-    } = load_image(kern_slice, kern_ptr);
-
-    let ImageLoadInfo {
-        virt_span: init_range_virt,
-        phys_span: init_range_phys,
-        headers: init_headers,
-        elf_header: init_hdr,
-    } = load_image(init_slice, PhysAddr::new(kern_range_phys.end()).as_u8_ptr());
+    let kern_ptr = PhysAddr::new(endaddr).round_up(PageSize::Page4k).unwrap();
+    let kern_image = load_or_halt(&initrd, "kern", kern_ptr, PteAttrs::empty(), |elf| {
+        kernel_base(elf, &mut rng)
+    });
+    let kern_range_phys = kern_image.phys_span();
+
+    let init_image = load_or_halt(
+        &initrd,
+        "init",
+        PhysAddr::new(kern_range_phys.end()),
+        PteAttrs::User,
+        |_| Some(user_layout.image_base),
+    );
+    let init_range_phys = init_image.phys_span();
 
+    info!(
+        "kern_range_phys: {:x?}, kern_range_virt: {:x?}",
+        kern_range_phys,
+        kern_image.loaded().virt_span
+    );
     info!(
         "init_range_phys: {:x?}, init_range_virt: {:x?}",
-        init_range_phys, init_range_virt
+        init_range_phys,
+        init_image.loaded().virt_span
     );
     info!("init physical memory allocator");
     for page in (endaddr..addr::PHYSMEM + addr::PHYSMEM_LEN).step_by(4096) {
         //println!("wtf {:x}", page);
 
-        // If the page intersects initrd, we don't want to clobber it
-        // We don't really care so much about clobbering dtb.
+        // If the page intersects initrd, we don't want to clobber it. The
+        // same goes for the dtb, which the boot arguments are still read out
+        // of when building init's stack.
         let page_span = Span::new(page, page + 4096);
-        let initrd_span = initrd_slice.into();
         if page_span.intersect(initrd_span).is_some()
+            || page_span.intersect(dtb_span).is_some()
             || page_span.intersect(kern_range_phys).is_some()
             || page_span.intersect(init_range_phys).is_some()
         {
@@ -286,8 +470,8 @@ unsafe extern "C" fn shoo_main(core_id: usize, dtb: *const u8) -> ! {
     get_sstatus();
     get_sip();
 
-    let root_pt = PageTable::<PhysMem>::alloc().expect("root pagetable alloc failed");
-    let satp = Satp::new(&root_pt, 0, TranslationMode::Sv39);
+    let root_pt = PageTable::<PhysMem>::alloc(paging_mode).expect("root pagetable alloc failed");
+    let satp = Satp::new(&root_pt, 0);
 
     // sets the running task so we can hit exceptions properly
     let task = task::FAULT_TASKS.get();
@@ -299,23 +483,9 @@ unsafe extern "C" fn shoo_main(core_id: usize, dtb: *const u8) -> ! {
     set_running_task(task as *mut _ as usize);
 
     // ALL CORES
-    map_executable(
-        root_pt,
-        kern_range_phys,
-        kernel_range_virt,
-        kernel_headers,
-        PteAttrs::empty(),
-    )
-    .expect("failed to map kernel");
+    kern_image.map(root_pt).expect("failed to map kernel");
 
-    map_executable(
-        root_pt,
-        init_range_phys,
-        init_range_virt,
-        init_headers,
-        PteAttrs::User,
-    )
-    .expect("failed to map kernel");
+    init_image.map(root_pt).expect("failed to map init");
 
     info!("map shoo");
     let textaddr = &SEC_TEXT as *const _ as usize;
@@ -355,7 +525,7 @@ unsafe extern "C" fn shoo_main(core_id: usize, dtb: *const u8) -> ! {
         root_pt
             .virt_map_one(
                 PhysAddr::new(offs),
-                VirtAddr(addr::PHYSMEM_MAP + offs),
+                VirtAddr(memory_map.physmem_map + offs),
                 PageSize::Page1g,
                 PteAttrs::R | PteAttrs::W,
             )
@@ -385,7 +555,7 @@ unsafe extern "C" fn shoo_main(core_id: usize, dtb: *const u8) -> ! {
     info!("allocate kernel stack");
     // make a new kernel stack
     let kstack_len = 0x8000;
-    let kstack_begin = PHYSMEM_MAP - kstack_len;
+    let kstack_begin = memory_map.physmem_map - kstack_len;
     root_pt
         .virt_alloc(
             VirtAddr::new(kstack_begin),
@@ -394,35 +564,67 @@ unsafe extern "C" fn shoo_main(core_id: usize, dtb: *const u8) -> ! {
         )
         .expect("failed to alloc kernel stack");
 
-    let init_sp = USERSPACE_STACK_TOP;
+    let init_stack_top = user_layout.stack_top;
     let init_stack_len = 0x8000;
     root_pt
         .virt_alloc(
-            USERSPACE_STACK_TOP.offset(-init_stack_len),
+            init_stack_top.offset(-init_stack_len),
             init_stack_len as usize,
             PteAttrs::R | PteAttrs::W | PteAttrs::User,
         )
         .expect("alloc init stack");
 
+    let boot_info = BootInfo {
+        heap_base: user_layout.heap_base.get(),
+        num_cpus: riscv::NUM_CPUS.load(Ordering::Relaxed),
+    };
+    let init_sp = loader::build_init_stack(
+        root_pt,
+        &init_image,
+        init_stack_top,
+        bootargs,
+        &mut rng,
+        &boot_info,
+    )
+    .expect("failed to build init stack");
+
+    // leave a guard page between the stack and the TLS block
+    let init_tp = match &init_image.loaded().tls {
+        Some(tls) => loader::alloc_init_tls(
+            root_pt,
+            tls,
+            init_stack_top.offset(-init_stack_len - PageSize::Page4k.size() as isize),
+        )
+        .expect("failed to allocate init TLS"),
+        None => VirtAddr(0),
+    };
+
     set_satp(satp);
     info!("paging enabled, jumping to the kernel");
 
     let entry_params_size = mem::size_of::<KernelEntryParams>();
     // i think sp needs to be aligned to 16
-    let sp = (PHYSMEM_MAP - entry_params_size) & !(16 - 1);
+    let sp = (memory_map.physmem_map - entry_params_size) & !(16 - 1);
 
     let entry_params = KernelEntryParams {
         core_id,
         init_sp,
-        init_entrypoint: VirtAddr(init_hdr.e_entry as usize),
+        init_entrypoint: init_image.loaded().entry,
+        init_tp,
         stack_pointer: VirtAddr(sp),
         num_cpus: riscv::NUM_CPUS.load(Ordering::Relaxed),
+        initrd: VirtAddr(memory_map.physmem_map + initrd_slice.as_ptr() as usize),
+        initrd_len: initrd_slice.len(),
+        // nothing may be allocated after this
+        free_list: PhysMem::take_free_list(),
+        rng_seed: rng.next_u64(),
+        timebase_freq: timebase_freq as u64,
     };
 
-    let params_ptr = (PHYSMEM_MAP - entry_params_size) as *mut KernelEntryParams;
+    let params_ptr = (memory_map.physmem_map - entry_params_size) as *mut KernelEntryParams;
     params_ptr.copy_from_nonoverlapping(&entry_params, 1);
 
-    let k_entry_va = hdr.e_entry;
+    let k_entry_va = kern_image.loaded().entry.get();
 
     // jmp kernel!!!! hell yeah
     asm!(
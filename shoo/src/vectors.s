@@ -215,6 +215,7 @@ m_exc_tab:
 m_sw_tab:
     j m_InterruptHart
     j m_ClearTimerInt
+    j m_SetTimer
 .option pop
 
 // for convenience in gdb these get stuffed into regs. this is not actually
@@ -263,11 +264,22 @@ m_ClearTimerInt:
     csrc mip, a3
     j m_machinecall_leave
 
+m_SetTimer:
+    // a2 points to this hart's TimerIsrData; get the pointer to mtimecmp
+    ld a3, 16(a2)
+    // the timer goes off when mtime reaches the deadline in a1
+    sd a1, 0(a3)
+    // whatever timer interrupt was pending has been dealt with. if the
+    // deadline already passed, the machine timer interrupt sets it again
+    li a3, 1 << 5
+    csrc mip, a3
+    j m_machinecall_leave
+
 // software exception i.e. machinecall
 // we use the ABI of call num in a0, arg in a1
 m_machinecall:
     // max machinecall number
-    li a3, 1
+    li a3, 2
     bgtu a0, a3, bad_machinecall
 
     // load the jump table address into a0
@@ -305,19 +317,11 @@ m_isr_timer:
     sd a1, 0(a0)
     sd a2, 8(a0)
 
-    // get the current time
-    li a1, MTIME_ADDR
-    ld a1, 0(a1)
-
-    // get the interval
-    ld a2, 24(a0)
-
-    // get the next interrupt time
-    add a2, a1, a2
-
-    // pointer to mtimecmp
+    // the timer is one shot: push mtimecmp as far out as it goes so the
+    // interrupt stops firing, until the kernel sets the next deadline with
+    // SetTimer
     ld a1, 16(a0)
-    // store the next interrupt time
+    li a2, -1
     sd a2, 0(a1)
 
     li a1, SERIAL_PORT
@@ -0,0 +1,124 @@
+//! Looking inside the ELF files in archives
+
+use color_eyre::eyre::{Context, Result};
+use goblin::elf::{program_header, sym, Elf};
+use serde::Serialize;
+
+/// Layout of an ELF file
+#[derive(Debug, Serialize)]
+pub struct ElfSummary {
+    pub entry: u64,
+    pub segments: Vec<SegmentInfo>,
+    pub sections: Vec<SectionInfo>,
+}
+
+/// A program header
+#[derive(Debug, Serialize)]
+pub struct SegmentInfo {
+    /// Type of the segment, like `PT_LOAD`
+    pub kind: String,
+    /// Permissions, like `r-x`
+    pub flags: String,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+/// A section header
+#[derive(Debug, Serialize)]
+pub struct SectionInfo {
+    pub name: String,
+    pub size: u64,
+}
+
+/// A symbol from the symbol table
+#[derive(Debug, Serialize)]
+pub struct SymbolInfo {
+    /// Demangled name
+    pub name: String,
+    /// Type of the symbol, like `FUNC`
+    pub kind: &'static str,
+    pub size: u64,
+}
+
+/// Whether `bytes` start like an ELF file
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"\x7fELF")
+}
+
+fn flags_str(p_flags: u32) -> String {
+    let flag = |bit, c| {
+        if p_flags & bit != 0 {
+            c
+        } else {
+            '-'
+        }
+    };
+    [
+        flag(program_header::PF_R, 'r'),
+        flag(program_header::PF_W, 'w'),
+        flag(program_header::PF_X, 'x'),
+    ]
+    .iter()
+    .collect()
+}
+
+/// Summarizes the layout of the ELF file in `bytes`
+pub fn summarize(bytes: &[u8]) -> Result<ElfSummary> {
+    let elf = Elf::parse(bytes).wrap_err("bad ELF file")?;
+    let segments = elf
+        .program_headers
+        .iter()
+        .map(|ph| SegmentInfo {
+            kind: program_header::pt_to_str(ph.p_type).to_owned(),
+            flags: flags_str(ph.p_flags),
+            offset: ph.p_offset,
+            vaddr: ph.p_vaddr,
+            filesz: ph.p_filesz,
+            memsz: ph.p_memsz,
+        })
+        .collect();
+    let sections = elf
+        .section_headers
+        .iter()
+        // skip the null section
+        .filter(|sh| sh.sh_name != 0)
+        .map(|sh| SectionInfo {
+            name: elf
+                .shdr_strtab
+                .get(sh.sh_name)
+                .and_then(|name| name.ok())
+                .unwrap_or("<bad name>")
+                .to_owned(),
+            size: sh.sh_size,
+        })
+        .collect();
+    Ok(ElfSummary {
+        entry: elf.entry,
+        segments,
+        sections,
+    })
+}
+
+/// Gets the `n` largest symbols of the ELF file in `bytes`, largest first
+pub fn largest_symbols(bytes: &[u8], n: usize) -> Result<Vec<SymbolInfo>> {
+    let elf = Elf::parse(bytes).wrap_err("bad ELF file")?;
+    let mut syms: Vec<SymbolInfo> = elf
+        .syms
+        .iter()
+        .filter(|s| s.st_size != 0)
+        .map(|s| SymbolInfo {
+            name: match elf.strtab.get(s.st_name).and_then(|name| name.ok()) {
+                Some(name) => format!("{:#}", rustc_demangle::demangle(name)),
+                None => "<bad name>".to_owned(),
+            },
+            kind: sym::type_to_str(s.st_type()),
+            size: s.st_size,
+        })
+        .collect();
+    // ties are broken by name so the output is stable
+    syms.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+    syms.truncate(n);
+    Ok(syms)
+}
@@ -1,12 +1,22 @@
 //! Generate microflop files at the command line
 
-use clap::Clap;
+mod elf;
+
+use clap::{ArgEnum, Clap};
 use color_eyre::eyre::{eyre, Context, Result};
 use fallible_iterator::FallibleIterator;
-use microflop::{FileName, Header, HeaderEntry, HeaderEntryType, Microflop, Offset};
+use microflop::{
+    ed25519, Compression, Decompressor, Entry, EntryKind, Microflop, MicroflopBuilder, Mode,
+    Version,
+};
 
-use std::{convert::TryInto, fs, io::BufWriter, mem};
-use std::{io::Write, path::PathBuf};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::{fs, io::BufWriter};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
 
 #[derive(Debug, Clap)]
 enum SubCommand {
@@ -14,89 +24,593 @@ enum SubCommand {
     List {
         /// File name to open
         filename: PathBuf,
+        #[clap(long, arg_enum, default_value = "text")]
+        format: Format,
     },
     /// Hexdump all the files in the file
     Dump {
         /// File name to open
         filename: PathBuf,
+        /// With `json`, the contents are hex strings
+        #[clap(long, arg_enum, default_value = "text")]
+        format: Format,
+    },
+    /// Show the program headers, section sizes, entry point and largest
+    /// symbols of the ELF files in an archive
+    Inspect {
+        /// File name to open
+        filename: PathBuf,
+        /// Number of symbols to show per file
+        #[clap(long, default_value = "10")]
+        top: usize,
+        /// Instead, show how file and section sizes changed since this
+        /// older archive
+        #[clap(long)]
+        diff: Option<PathBuf>,
+    },
+    /// Check the checksums of an archive and report damaged entries
+    Verify {
+        /// File name to open
+        filename: PathBuf,
+        /// Also check that the archive is signed by this public key
+        #[clap(long)]
+        key: Option<PathBuf>,
+    },
+    /// Generate a key pair for signing archives, written to `NAME.pem` and
+    /// `NAME.pub.pem`
+    Keygen {
+        /// Name of the key files, without extension
+        name: PathBuf,
+    },
+    /// Sign an existing archive, replacing any previous signature
+    Sign {
+        /// File name to open
+        filename: PathBuf,
+        /// Secret key to sign with
+        #[clap(long)]
+        key: PathBuf,
+        /// Output path, the input file by default
+        #[clap(short = 'o')]
+        output: Option<PathBuf>,
+    },
+    /// Write a file in an archive out, decompressing it if needed
+    Extract {
+        /// File name to open
+        filename: PathBuf,
+        /// Path of the file in the archive
+        name: String,
+        /// Output path, the file name of `name` in the current directory by
+        /// default
+        #[clap(short = 'o')]
+        output: Option<PathBuf>,
+    },
+    /// Add files to the end of an existing archive
+    Add {
+        /// File name to open
+        filename: PathBuf,
+        /// Input files, as for `new`
+        files: Vec<PathBuf>,
+        #[clap(flatten)]
+        edit: EditOpts,
+    },
+    /// Replace the contents of files in an existing archive, keeping their
+    /// place in it
+    Replace {
+        /// File name to open
+        filename: PathBuf,
+        /// Input files, as for `new`
+        files: Vec<PathBuf>,
+        #[clap(flatten)]
+        edit: EditOpts,
+    },
+    /// Remove entries from an existing archive. Removing a directory removes
+    /// everything in it.
+    Remove {
+        /// File name to open
+        filename: PathBuf,
+        /// Paths in the archive to remove
+        names: Vec<String>,
+        #[clap(flatten)]
+        edit: EditOpts,
+    },
+    /// Make a new archive as described by a TOML manifest. The output only
+    /// depends on the manifest and the contents of the files it lists.
+    ///
+    /// The manifest has an `[[entry]]` table for each entry, in archive
+    /// order, with a `name` in the archive and a `kind` of `file` or `dir`.
+    /// Files also have a `source` path relative to the manifest, and
+    /// optionally `exec`, `readonly` and `compress` flags and an `align` in
+    /// bytes. Directories holding an entry are added before it if they are
+    /// not listed earlier.
+    Build {
+        /// Manifest to build from
+        manifest: PathBuf,
+        #[clap(short = 'o')]
+        /// Output path
+        output: PathBuf,
+        /// Sign the archive with this secret key
+        #[clap(long)]
+        key: Option<PathBuf>,
     },
     /// Make a new archive
     New {
-        /// Input files for archiving
+        /// Input files for archiving. They are put in the root of the archive
+        /// unless given as `path/in/archive=file`.
         files: Vec<PathBuf>,
         #[clap(short = 'o')]
         /// Output path
         output: PathBuf,
+        /// Compress the files that get smaller for it
+        #[clap(long)]
+        compress: bool,
+        /// Sign the archive with this secret key
+        #[clap(long)]
+        key: Option<PathBuf>,
     },
 }
 
+/// Output format of commands that describe an archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+enum Format {
+    Text,
+    Json,
+}
+
+/// Options for changing an existing archive
+#[derive(Debug, Clap)]
+struct EditOpts {
+    /// Output path, the input file by default
+    #[clap(short = 'o')]
+    output: Option<PathBuf>,
+    /// Compress the added files that get smaller for it
+    #[clap(long)]
+    compress: bool,
+    /// Sign the archive with this secret key. Changing an archive drops its
+    /// signature otherwise.
+    #[clap(long)]
+    key: Option<PathBuf>,
+}
+
 #[derive(Debug, Clap)]
 struct Opts {
     #[clap(subcommand)]
     subcmd: SubCommand,
 }
 
-fn list(filename: PathBuf) -> Result<()> {
+/// Description of an archive for `--format json`
+#[derive(Debug, Serialize)]
+struct ArchiveInfo {
+    version: u16,
+    checksums: bool,
+    signed: bool,
+    entries: Vec<EntryInfo>,
+}
+
+/// Description of an entry for `--format json`
+#[derive(Debug, Serialize)]
+struct EntryInfo {
+    path: String,
+    kind: &'static str,
+    /// Offset of the contents in the archive, none for directories
+    begin: Option<usize>,
+    end: Option<usize>,
+    /// Length of the contents as stored
+    length: usize,
+    /// Length of the contents once decompressed
+    size: usize,
+    /// Bytes of alignment padding between the previous entry, or the
+    /// tables, and this one
+    padding: usize,
+    checksum: Option<u32>,
+    compression: &'static str,
+    exec: bool,
+    readonly: bool,
+    /// Layout of the file if it is an ELF
+    elf: Option<elf::ElfSummary>,
+    /// Hex of the contents as stored, for `dump`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    contents: Option<String>,
+}
+
+/// Gets the contents of a file, decompressing them if needed
+fn file_contents(entry: &Entry<'_>) -> Result<Vec<u8>> {
+    let mut contents = vec![0u8; entry.size];
+    entry.decompress_into(&mut Decompressor::new(), &mut contents)?;
+    Ok(contents)
+}
+
+fn archive_info(mf: &Microflop<'_>, with_contents: bool) -> Result<ArchiveInfo> {
+    let region = mf.region().as_ptr() as usize;
+    let mut prev_end = mf.data_start();
+    let mut infos = Vec::new();
+    let mut entries = mf.entries();
+    while let Some(entry) = entries.next()? {
+        // directories have no contents, so they are nowhere in particular
+        let (begin, end, padding) = match entry.kind {
+            EntryKind::Dir => (None, None, 0),
+            _ => {
+                let begin = entry.contents.as_ptr() as usize - region;
+                let end = begin + entry.contents.len();
+                let padding = begin.saturating_sub(prev_end);
+                prev_end = prev_end.max(end);
+                (Some(begin), Some(end), padding)
+            }
+        };
+
+        let elf = match entry.kind {
+            EntryKind::File => {
+                let contents = file_contents(&entry)?;
+                if elf::is_elf(&contents) {
+                    match elf::summarize(&contents) {
+                        Ok(summary) => Some(summary),
+                        Err(e) => {
+                            eprintln!("warning: {}: {}", entry.path, e);
+                            None
+                        }
+                    }
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        infos.push(EntryInfo {
+            path: entry.path.to_owned(),
+            kind: match entry.kind {
+                EntryKind::File => "file",
+                EntryKind::Dir => "dir",
+                EntryKind::Signature => "signature",
+            },
+            begin,
+            end,
+            length: entry.contents.len(),
+            size: entry.size,
+            padding,
+            checksum: entry.checksum,
+            compression: match entry.compression {
+                Compression::None => "none",
+                Compression::Deflate => "deflate",
+            },
+            exec: entry.mode.contains(Mode::Exec),
+            readonly: entry.mode.contains(Mode::ReadOnly),
+            elf,
+            contents: if with_contents {
+                Some(
+                    entry
+                        .contents
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect(),
+                )
+            } else {
+                None
+            },
+        });
+    }
+    Ok(ArchiveInfo {
+        version: match mf.version() {
+            Version::V1 => 1,
+            Version::V2 => 2,
+        },
+        checksums: mf.has_checksums(),
+        signed: mf.signature()?.is_some(),
+        entries: infos,
+    })
+}
+
+fn print_json(mf: &Microflop<'_>, with_contents: bool) -> Result<()> {
+    let info = archive_info(mf, with_contents)?;
+    println!("{}", serde_json::to_string_pretty(&info)?);
+    Ok(())
+}
+
+fn list(filename: PathBuf, format: Format) -> Result<()> {
     let bytes = fs::read(filename)?;
     let mf = Microflop::new(&bytes)?;
-    let mut files = mf.files();
-    while let Some((fname, data)) = files.next()? {
-        println!("File {} - {} bytes", fname.as_str()?, data.len());
+    if format == Format::Json {
+        return print_json(&mf, false);
+    }
+    let mut entries = mf.entries();
+    while let Some(entry) = entries.next()? {
+        match entry.kind {
+            EntryKind::Dir => println!("Dir  {}/", entry.path),
+            EntryKind::File => {
+                print!("File {} - {} bytes", entry.path, entry.size);
+                if entry.compression != Compression::None {
+                    print!(
+                        " ({} compressed with {:?})",
+                        entry.contents.len(),
+                        entry.compression
+                    );
+                }
+                if entry.mode.contains(Mode::Exec) {
+                    print!(" (exec)");
+                }
+                println!();
+            }
+            EntryKind::Signature => println!("Signature"),
+        }
     }
     Ok(())
 }
 
-fn dump(filename: PathBuf) -> Result<()> {
+fn dump(filename: PathBuf, format: Format) -> Result<()> {
     let bytes = fs::read(filename)?;
     let mf = Microflop::new(&bytes)?;
-    let mut files = mf.entries();
-    while let Some((entry, data)) = files.next()? {
-        let fname = entry.fname;
-        println!("Entry: {:?}", &entry);
-        println!("File {} - {} bytes", fname.as_str()?, data.len());
-        println!("{}", hexdump::HexDumper::new(data));
+    if format == Format::Json {
+        return print_json(&mf, true);
+    }
+    println!("Version: {:?}", mf.version());
+    let mut entries = mf.entries();
+    while let Some(entry) = entries.next()? {
+        println!("Entry: {:?}", entry);
+        println!("{}", hexdump::HexDumper::new(entry.contents));
+    }
+    Ok(())
+}
+
+/// Gets the files in an archive with their contents decompressed, in
+/// archive order
+fn read_files(filename: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    let bytes = fs::read(filename).wrap_err_with(|| eyre!("failed to read {:?}", filename))?;
+    let mf = Microflop::new(&bytes)?;
+    let mut files = Vec::new();
+    let mut iter = mf.files();
+    while let Some(entry) = iter.next()? {
+        files.push((entry.path.to_owned(), file_contents(&entry)?));
+    }
+    Ok(files)
+}
+
+fn inspect(filename: PathBuf, top: usize) -> Result<()> {
+    for (path, contents) in read_files(&filename)? {
+        if !elf::is_elf(&contents) {
+            continue;
+        }
+        let summary = elf::summarize(&contents).wrap_err_with(|| eyre!("in {}", path))?;
+        println!(
+            "{}: {} bytes, entry {:#x}",
+            path,
+            contents.len(),
+            summary.entry
+        );
+        println!("  Program headers:");
+        for seg in &summary.segments {
+            println!(
+                "    {:<16} {} offset {:#8x} vaddr {:#18x} filesz {:#8x} memsz {:#8x}",
+                seg.kind, seg.flags, seg.offset, seg.vaddr, seg.filesz, seg.memsz
+            );
+        }
+        println!("  Sections:");
+        for section in &summary.sections {
+            println!("    {:<24} {:>10}", section.name, section.size);
+        }
+        println!("  Largest symbols:");
+        for sym in elf::largest_symbols(&contents, top)? {
+            println!("    {:>10} {:<7} {}", sym.size, sym.kind, sym.name);
+        }
+        println!();
+    }
+    Ok(())
+}
+
+/// Prints `old -> new (+delta)` with `what` in front, if they differ
+fn print_delta(what: &str, old: Option<u64>, new: Option<u64>) {
+    if old == new {
+        return;
+    }
+    let show = |n: Option<u64>| match n {
+        Some(n) => n.to_string(),
+        None => "-".to_owned(),
+    };
+    let delta = new.unwrap_or(0) as i64 - old.unwrap_or(0) as i64;
+    println!("{}: {} -> {} ({:+})", what, show(old), show(new), delta);
+}
+
+/// Section sizes of a file by name, if it is an ELF
+fn section_sizes(contents: &[u8]) -> Result<BTreeMap<String, u64>> {
+    if !elf::is_elf(contents) {
+        return Ok(BTreeMap::new());
+    }
+    let summary = elf::summarize(contents)?;
+    let mut sizes = BTreeMap::new();
+    for section in summary.sections {
+        *sizes.entry(section.name).or_insert(0) += section.size;
+    }
+    Ok(sizes)
+}
+
+fn inspect_diff(filename: PathBuf, old: PathBuf) -> Result<()> {
+    let old_files: BTreeMap<_, _> = read_files(&old)?.into_iter().collect();
+    let new_files: BTreeMap<_, _> = read_files(&filename)?.into_iter().collect();
+    let paths: BTreeSet<&String> = old_files.keys().chain(new_files.keys()).collect();
+
+    let mut total = (0, 0);
+    for path in paths {
+        let old = old_files.get(path);
+        let new = new_files.get(path);
+        let old_len = old.map(|c| c.len() as u64);
+        let new_len = new.map(|c| c.len() as u64);
+        total.0 += old_len.unwrap_or(0);
+        total.1 += new_len.unwrap_or(0);
+        print_delta(path, old_len, new_len);
+
+        let old_sections = match old {
+            Some(c) => section_sizes(c)?,
+            None => BTreeMap::new(),
+        };
+        let new_sections = match new {
+            Some(c) => section_sizes(c)?,
+            None => BTreeMap::new(),
+        };
+        let names: BTreeSet<&String> = old_sections.keys().chain(new_sections.keys()).collect();
+        for name in names {
+            print_delta(
+                &format!("  {}", name),
+                old_sections.get(name).copied(),
+                new_sections.get(name).copied(),
+            );
+        }
+    }
+    print_delta("total", Some(total.0), Some(total.1));
+    Ok(())
+}
+
+fn read_secret_key(path: &Path) -> Result<ed25519::KeyPair> {
+    let pem = fs::read_to_string(path).wrap_err_with(|| eyre!("failed to read key {:?}", path))?;
+    ed25519::KeyPair::from_pem(&pem).map_err(|e| eyre!("bad secret key {:?}: {}", path, e))
+}
+
+fn read_public_key(path: &Path) -> Result<ed25519::PublicKey> {
+    let pem = fs::read_to_string(path).wrap_err_with(|| eyre!("failed to read key {:?}", path))?;
+    ed25519::PublicKey::from_pem(&pem).map_err(|e| eyre!("bad public key {:?}: {}", path, e))
+}
+
+/// Writes `contents` to a new file at `path`, readable only by the owner on
+/// unix
+fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
     }
+    let mut file = options
+        .open(path)
+        .wrap_err_with(|| eyre!("Unable to create {:?}", path))?;
+    file.write_all(contents)?;
     Ok(())
 }
 
-fn new(files: &[PathBuf], output: PathBuf) -> Result<()> {
-    // construct the header bits then commit them later
-    let mut file_contents = vec![];
+fn keygen(name: PathBuf) -> Result<()> {
+    let key = ed25519::KeyPair::generate();
+    let secret = name.with_extension("pem");
+    let public = name.with_extension("pub.pem");
+    write_private(&secret, key.to_pem().as_bytes())?;
+    fs::write(&public, key.pk.to_pem()).wrap_err_with(|| eyre!("Unable to write {:?}", public))?;
+    println!(
+        "Wrote secret key to {:?}, public key to {:?}",
+        secret, public
+    );
+    Ok(())
+}
+
+fn sign(filename: PathBuf, key: PathBuf, output: Option<PathBuf>) -> Result<()> {
+    let opts = EditOpts {
+        output,
+        compress: false,
+        key: Some(key),
+    };
+    edit(filename, &opts, |_| Ok(()))
+}
+
+fn verify(filename: PathBuf, key: Option<PathBuf>) -> Result<()> {
+    let bytes = fs::read(filename)?;
+    let mf = Microflop::new_unverified(&bytes).wrap_err("archive structure is damaged")?;
+    if !mf.has_checksums() {
+        println!("{:?} archive has no checksums", mf.version());
+        return Ok(());
+    }
+
+    let mut damaged = 0;
+    if mf.verify_table().is_err() {
+        println!("Header table is damaged");
+        damaged += 1;
+    }
+    let mut entries = mf.entries().enumerate();
+    while let Some((idx, entry)) = entries.next()? {
+        if !entry.checksum_ok() {
+            println!("Entry {} ({}) is damaged", idx, entry.path);
+            damaged += 1;
+        }
+    }
+
+    if damaged > 0 {
+        return Err(eyre!("{} damaged parts in archive", damaged));
+    }
+    println!("Archive is intact");
+
+    if let Some(key) = key {
+        mf.verify_signature(&read_public_key(&key)?)
+            .wrap_err_with(|| eyre!("signature does not match {:?}", key))?;
+        println!("Signature is good");
+    }
+    Ok(())
+}
+
+/// Gets the archive mode of a file from its permissions
+fn file_mode(file: &Path) -> Result<Mode> {
+    let perms = fs::metadata(file)?.permissions();
+    let mut mode = Mode::empty();
+    if perms.readonly() {
+        mode |= Mode::ReadOnly;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if perms.mode() & 0o111 != 0 {
+            mode |= Mode::Exec;
+        }
+    }
+    Ok(mode)
+}
+
+fn compression(compress: bool) -> Compression {
+    if compress {
+        Compression::Deflate
+    } else {
+        Compression::None
+    }
+}
+
+/// A file to put in an archive
+struct Input {
+    path: String,
+    contents: Vec<u8>,
+    mode: Mode,
+}
+
+/// Reads an input file given as `file`, which goes in the root of the
+/// archive, or as `path/in/archive=file`
+fn read_input(file: &Path) -> Result<Input> {
+    let arg = file
+        .to_str()
+        .ok_or_else(|| eyre!("file name contained non unicode: {:?}", file))?;
+    let (path, source) = match arg.find('=') {
+        Some(idx) => (&arg[..idx], PathBuf::from(&arg[idx + 1..])),
+        None => {
+            let name = file
+                .file_name()
+                .ok_or_else(|| eyre!("no file name on {:?}", file))?;
+            // file_name of a valid UTF-8 path is valid UTF-8
+            (name.to_str().unwrap(), file.to_owned())
+        }
+    };
+    let contents =
+        fs::read(&source).wrap_err_with(|| eyre!("failed to read input file {:?}", source))?;
+    Ok(Input {
+        path: path.to_owned(),
+        contents,
+        mode: file_mode(&source)?,
+    })
+}
+
+fn new(files: &[PathBuf], output: PathBuf, compress: bool, key: Option<PathBuf>) -> Result<()> {
+    let mut builder = MicroflopBuilder::new();
     for file in files.iter() {
-        file_contents.push(fs::read(file).wrap_err("failed to read input file")?);
-    }
-
-    let headers_end = mem::size_of::<HeaderEntry>() * (files.len() + 1) + mem::size_of::<Header>();
-
-    let mut headers = vec![];
-    let mut out_pos = headers_end;
-    for (file, fc) in files.iter().zip(file_contents.iter()) {
-        let remain_align = (8 - fc.len() % 8) % 8;
-        let file_end = out_pos + fc.len();
-        let new_end = file_end + remain_align;
-
-        let file_name = file
-            .file_name()
-            .ok_or_else(|| eyre!("no file name on {:?}", file))
-            .and_then(|s| {
-                s.to_str()
-                    .ok_or_else(|| eyre!("file name contained non unicode: {:?}", s))
-            })?;
-        headers.push(HeaderEntry {
-            fname: FileName::new(file_name)
-                .wrap_err_with(|| eyre!("bad file name {:?}", file_name))?,
-            begin: Offset(out_pos.try_into().wrap_err("ran outta u32")?),
-            end: Offset(file_end.try_into().wrap_err("ran outta u32")?),
-            tag: HeaderEntryType::Entry,
-        });
-        out_pos = new_end;
+        let Input {
+            path,
+            contents,
+            mode,
+        } = read_input(file)?;
+        builder
+            .add_with_options(&path, contents, mode, compression(compress))
+            .wrap_err_with(|| eyre!("can't add {:?} to the archive", path))?;
+    }
+    if let Some(key) = key {
+        builder.sign(read_secret_key(&key)?);
     }
-    headers.push(HeaderEntry {
-        fname: FileName::EMPTY,
-        begin: Offset(0),
-        end: Offset(0),
-        tag: HeaderEntryType::End,
-    });
 
     // write it out
     let out = fs::OpenOptions::new()
@@ -107,34 +621,307 @@ fn new(files: &[PathBuf], output: PathBuf) -> Result<()> {
         .wrap_err("Unable to open output file")?;
 
     let mut bw = BufWriter::new(out);
-    // header
-    bw.write(&microflop::MAGIC.to_le_bytes())?;
-    for header in headers {
-        header.serialize(&mut bw)?;
+    builder.write(&mut bw)?;
+    bw.flush()?;
+
+    Ok(())
+}
+
+/// Archive contents for `build`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    /// Entries in archive order
+    #[serde(default, rename = "entry")]
+    entries: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+enum ManifestEntry {
+    File {
+        /// Path in the archive
+        name: String,
+        /// Path of the file to include, relative to the manifest
+        source: PathBuf,
+        #[serde(default)]
+        exec: bool,
+        #[serde(default)]
+        readonly: bool,
+        #[serde(default)]
+        compress: bool,
+        align: Option<usize>,
+    },
+    Dir {
+        /// Path in the archive
+        name: String,
+    },
+}
+
+/// Builds the archive described by the manifest at `manifest`, signed with
+/// the secret key at `key` if there is one
+fn build_archive(manifest: &Path, key: Option<&Path>) -> Result<Vec<u8>> {
+    let text = fs::read_to_string(manifest)
+        .wrap_err_with(|| eyre!("failed to read manifest {:?}", manifest))?;
+    let parsed: Manifest =
+        toml::from_str(&text).wrap_err_with(|| eyre!("bad manifest {:?}", manifest))?;
+    let base = manifest.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut builder = MicroflopBuilder::new();
+    for entry in &parsed.entries {
+        match entry {
+            ManifestEntry::File {
+                name,
+                source,
+                exec,
+                readonly,
+                compress,
+                align,
+            } => {
+                let source = base.join(source);
+                let contents = fs::read(&source)
+                    .wrap_err_with(|| eyre!("failed to read input file {:?}", source))?;
+                // the mode comes from the manifest rather than the file system
+                // so that the output does not depend on where it is built
+                let mut mode = Mode::empty();
+                mode.set(Mode::Exec, *exec);
+                mode.set(Mode::ReadOnly, *readonly);
+                builder
+                    .add_with_options(name, contents, mode, compression(*compress))
+                    .wrap_err_with(|| eyre!("can't add {:?} to the archive", name))?;
+                if let Some(align) = *align {
+                    builder
+                        .set_align(name, align)
+                        .wrap_err_with(|| eyre!("bad alignment {} for {:?}", align, name))?;
+                }
+            }
+            ManifestEntry::Dir { name } => {
+                builder
+                    .add_dir(name)
+                    .wrap_err_with(|| eyre!("can't add {:?} to the archive", name))?;
+            }
+        }
+    }
+    if let Some(key) = key {
+        builder.sign(read_secret_key(key)?);
     }
-    let zeros = [0u8; 7];
-    for fc in file_contents {
-        bw.write_all(&fc)?;
-        let remain_align = (8 - fc.len() % 8) % 8;
-        bw.write_all(&zeros[..remain_align])?;
+    Ok(builder.to_vec()?)
+}
+
+fn build(manifest: PathBuf, output: PathBuf, key: Option<PathBuf>) -> Result<()> {
+    let out = build_archive(&manifest, key.as_deref())?;
+    fs::write(&output, out).wrap_err_with(|| eyre!("Unable to write {:?}", output))?;
+    Ok(())
+}
+
+fn extract(filename: PathBuf, name: String, output: Option<PathBuf>) -> Result<()> {
+    let bytes = fs::read(filename)?;
+    let mf = Microflop::new(&bytes)?;
+    let entry = match mf.entry(&name)? {
+        Some(entry) if entry.kind == EntryKind::File => entry,
+        Some(_) => return Err(eyre!("{:?} is not a file", name)),
+        None => return Err(eyre!("{:?} is not in the archive", name)),
+    };
+    let contents = file_contents(&entry)?;
+
+    // the archive checks paths are relative, so this is inside the current
+    // directory
+    let output = output.unwrap_or_else(|| PathBuf::from(archive_basename(&name)));
+    fs::write(&output, contents).wrap_err_with(|| eyre!("Unable to write {:?}", output))?;
+    Ok(())
+}
+
+/// Last component of a path in an archive
+fn archive_basename(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(idx) => &path[idx + 1..],
+        None => path,
     }
+}
 
+/// Rebuilds the archive at `filename` after changing it with `f`, keeping
+/// the order of the entries that are left
+fn edit(
+    filename: PathBuf,
+    opts: &EditOpts,
+    f: impl FnOnce(&mut MicroflopBuilder) -> Result<()>,
+) -> Result<()> {
+    let bytes = fs::read(&filename)?;
+    let mf = Microflop::new(&bytes)?;
+    let mut builder = MicroflopBuilder::from_archive(&mf)?;
+    f(&mut builder)?;
+    match &opts.key {
+        Some(key) => {
+            builder.sign(read_secret_key(key)?);
+        }
+        None if mf.signature()?.is_some() => {
+            eprintln!("warning: dropping the signature of {:?}", filename);
+        }
+        None => (),
+    }
+    let out = builder.to_vec()?;
+    let output = opts.output.as_ref().unwrap_or(&filename);
+    fs::write(output, out).wrap_err("Unable to write output file")?;
     Ok(())
 }
 
+fn add(filename: PathBuf, files: &[PathBuf], opts: &EditOpts) -> Result<()> {
+    edit(filename, opts, |builder| {
+        for file in files {
+            let Input {
+                path,
+                contents,
+                mode,
+            } = read_input(file)?;
+            builder
+                .add_with_options(&path, contents, mode, compression(opts.compress))
+                .wrap_err_with(|| eyre!("can't add {:?} to the archive", path))?;
+        }
+        Ok(())
+    })
+}
+
+fn replace(filename: PathBuf, files: &[PathBuf], opts: &EditOpts) -> Result<()> {
+    edit(filename, opts, |builder| {
+        for file in files {
+            let Input {
+                path,
+                contents,
+                mode,
+            } = read_input(file)?;
+            builder
+                .replace(&path, contents, mode, compression(opts.compress))
+                .wrap_err_with(|| eyre!("can't replace {:?} in the archive", path))?;
+        }
+        Ok(())
+    })
+}
+
+fn remove(filename: PathBuf, names: &[String], opts: &EditOpts) -> Result<()> {
+    edit(filename, opts, |builder| {
+        for name in names {
+            builder
+                .remove(name)
+                .wrap_err_with(|| eyre!("can't remove {:?} from the archive", name))?;
+        }
+        Ok(())
+    })
+}
+
 pub(crate) fn main() -> Result<()> {
     color_eyre::install()?;
     let args = Opts::parse();
     match args.subcmd {
-        SubCommand::List { filename } => {
-            list(filename)?;
+        SubCommand::List { filename, format } => {
+            list(filename, format)?;
+        }
+        SubCommand::Dump { filename, format } => {
+            dump(filename, format)?;
+        }
+        SubCommand::Inspect {
+            filename,
+            top,
+            diff,
+        } => match diff {
+            Some(old) => inspect_diff(filename, old)?,
+            None => inspect(filename, top)?,
+        },
+        SubCommand::Verify { filename, key } => {
+            verify(filename, key)?;
+        }
+        SubCommand::Keygen { name } => {
+            keygen(name)?;
+        }
+        SubCommand::Sign {
+            filename,
+            key,
+            output,
+        } => {
+            sign(filename, key, output)?;
+        }
+        SubCommand::Extract {
+            filename,
+            name,
+            output,
+        } => {
+            extract(filename, name, output)?;
         }
-        SubCommand::Dump { filename } => {
-            dump(filename)?;
+        SubCommand::Add {
+            filename,
+            files,
+            edit,
+        } => {
+            add(filename, &files, &edit)?;
         }
-        SubCommand::New { files, output } => {
-            new(&files, output)?;
+        SubCommand::Replace {
+            filename,
+            files,
+            edit,
+        } => {
+            replace(filename, &files, &edit)?;
+        }
+        SubCommand::Remove {
+            filename,
+            names,
+            edit,
+        } => {
+            remove(filename, &names, &edit)?;
+        }
+        SubCommand::Build {
+            manifest,
+            output,
+            key,
+        } => {
+            build(manifest, output, key)?;
+        }
+        SubCommand::New {
+            files,
+            output,
+            compress,
+            key,
+        } => {
+            new(&files, output, compress, key)?;
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_build_manifest() {
+        let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("testfiles/manifest.toml");
+        let bytes = build_archive(&manifest, None).unwrap();
+        assert!(build_archive(&manifest, None).unwrap() == bytes);
+
+        let mf = Microflop::new(&bytes).unwrap();
+        let mut paths = Vec::new();
+        let mut entries = mf.entries();
+        while let Some(entry) = entries.next().unwrap() {
+            paths.push(entry.path);
+        }
+        // in manifest order, with directories just before what needs them
+        assert_eq!(
+            paths,
+            [
+                "etc",
+                "bin",
+                "bin/second",
+                "bin/first",
+                "bin/again",
+                "src",
+                "src/elf.rs"
+            ]
+        );
+
+        let first = mf.entry("bin/first").unwrap().unwrap();
+        assert_eq!(first.mode, Mode::Exec);
+        assert_eq!(first.align, 4096);
+        assert_eq!(mf.get("bin/again").unwrap(), Some(&b"aaaa\n"[..]));
+        let elf_rs = mf.entry("src/elf.rs").unwrap().unwrap();
+        assert_eq!(elf_rs.compression, Compression::Deflate);
+        assert_eq!(file_contents(&elf_rs).unwrap(), include_bytes!("elf.rs"));
+    }
+}
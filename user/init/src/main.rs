@@ -1,13 +1,67 @@
 // init process
 #![no_std]
 #![feature(bench_black_box)]
+#![feature(thread_local)]
+
+use core::cell::Cell;
+use core::time::Duration;
 
 use mu::syscall;
 
 extern crate mu;
 
+#[thread_local]
+static GREETINGS: Cell<usize> = Cell::new(1);
+
 fn main() {
+    // init starts a copy of itself with this argument, to show off spawning
+    if mu::env::args().nth(1) == Some("child") {
+        mu::println!("hello from init's child");
+        let start = syscall::clock_get_monotonic();
+        syscall::sleep(Duration::from_millis(500));
+        mu::println!(
+            "child slept for {:?}",
+            syscall::clock_get_monotonic() - start
+        );
+        syscall::exit(42);
+    }
+
     syscall::log("hello from init");
     syscall::log("hello from init 2");
+    for (i, arg) in mu::env::args().enumerate() {
+        mu::println!("argv[{}] = {}", i, arg);
+    }
+    for (key, value) in mu::env::vars() {
+        mu::println!("env {} = {}", key, value);
+    }
+    GREETINGS.set(GREETINGS.get() + 1);
+    mu::println!(
+        "tp = {:#x}, thread local greetings: {}",
+        mu::tls::thread_pointer(),
+        GREETINGS.get()
+    );
+    match syscall::spawn("init", &["child"]) {
+        Ok(child) => {
+            mu::println!("spawned child process {}", child);
+            // keep the hart busy for a while, so the child only gets to run
+            // if we are preempted
+            let start = syscall::clock_get_monotonic();
+            for _ in 0..3 {
+                let until = syscall::clock_get_monotonic() + Duration::from_millis(150);
+                while syscall::clock_get_monotonic() < until {
+                    core::hint::spin_loop();
+                }
+                mu::println!(
+                    "init still running {:?} after spawning",
+                    syscall::clock_get_monotonic() - start
+                );
+            }
+            match syscall::wait(child) {
+                Ok(code) => mu::println!("child exited with code {}", code),
+                Err(e) => mu::println!("failed to wait for child: {:?}", e),
+            }
+        }
+        Err(e) => mu::println!("failed to spawn child: {:?}", e),
+    }
     loop {}
 }